@@ -0,0 +1,534 @@
+use std::collections::HashMap;
+use std::fs;
+
+use crate::bytecode::{ByteCode, Chunk, OpCode};
+use crate::cfg;
+use crate::json::Json;
+use crate::heap::Heap;
+use crate::native_functions::Native;
+use crate::object::{Function, Object};
+use crate::source_map::Loc;
+use crate::value::Value;
+use crate::verifier;
+use crate::virtual_machine::VirtualMachine;
+
+
+/// Text form of a compiled program, written by `disassemble` and read back by
+/// `assemble`:
+///
+/// ```text
+/// .object 0 "$list"     an object of `obj_list`, in order
+/// <fn> f/1:             a function and its arity
+///   .file "test.dpp"    the script the function was compiled from
+///   .const 0 42         an entry of the function's constant pool, which
+///                       also holds the names of globals and natives
+/// L12:                  a label, `disassemble` starts every basic block with one
+///   3:5-9 jz L12        source location (optional), mnemonic, operands
+/// ```
+///
+/// A source location is `line`, `line:column` or `line:column-end column`.
+///
+/// Values are `nil`, `true`, `false`, integers, floats (with a `.` or an
+/// exponent), `@3` for an object, `"text"` for a new string object,
+/// `fn:1`, `native:3`, `ptr:1`, `static_ptr:1` and `unk`.
+/// `;` starts a comment, it shows what constants refer to and where each
+/// block may continue.
+pub fn disassemble(functions: &[Function], obj_list: &Heap) -> String {
+    let mut asm = String::new();
+    for (i, obj) in obj_list.iter().enumerate() {
+        let text = match obj {
+            Object::String(s) => format!("{:?}", s),
+            Object::List(list) => {
+                let items: Vec<String> = list.iter().map(value_text).collect();
+                format!("list {}", items.join(" "))
+            },
+            Object::Obj => String::from("obj"),
+            Object::Function(_) => String::from("unsupported"),
+        };
+        asm += &format!(".object\t{}\t{}\n", i, text);
+    }
+    for func in functions {
+        asm += &format!("\n<fn> {}/{}:\n", func.name, func.arity);
+        let chunk = &func.chunk;
+        if !chunk.source_map.file.is_empty() {
+            asm += &format!("  .file\t{:?}\n", chunk.source_map.file);
+        }
+        for (i, value) in chunk.constants.iter().enumerate() {
+            asm += &format!("  .const\t{}\t{}\n", i, value_text(value));
+        }
+        let blocks = listing(func, functions, obj_list);
+        for block in &blocks {
+            let successors: Vec<String> = block.successors.iter().map(|offset| format!("L{}", offset)).collect();
+            asm += &format!("L{}:\t; ", block.offset);
+            asm += &match (block.reachable, successors.is_empty()) {
+                (false, _) => String::from("unreachable"),
+                (true, true) => String::from("exit"),
+                (true, false) => format!("-> {}", successors.join(" ")),
+            };
+            asm.push('\n');
+            for entry in &block.entries {
+                let mut operands: Vec<String> = entry.operands.iter().map(|operand| operand.to_string()).collect();
+                if let Some(target) = entry.ins.jump_target() {
+                    *operands.last_mut().unwrap() = format!("L{}", target);
+                }
+                asm += &format!("  {}\t{}", entry.loc, entry.mnemonic);
+                if !operands.is_empty() {
+                    asm += &format!("\t{}", operands.join(" "));
+                }
+                if let Some(resolved) = &entry.resolved {
+                    asm += &format!("\t; {}", resolved);
+                }
+                asm.push('\n');
+            }
+        }
+        if chunk.instructions().any(|(_, ins)| ins.jump_target() == Some(chunk.code.len())) {
+            asm += &format!("L{}:\n", chunk.code.len());
+        }
+    }
+    asm
+}
+
+/// The same listing as `disassemble`, as JSON for tools.
+pub fn to_json(functions: &[Function], obj_list: &Heap) -> String {
+    let value_json = |value: &Value| Json::object(vec![
+        ("value", Json::Str(value_text(value))),
+        ("resolved", Json::Str(resolve(value, functions, obj_list))),
+    ]);
+    let objects = obj_list.iter().map(|obj| match obj {
+        Object::String(s) => Json::str(s),
+        Object::List(list) => Json::Array(list.iter().map(|value| Json::Str(value_text(value))).collect()),
+        _ => Json::Null,
+    }).collect();
+    let functions_json = functions.iter().map(|func| {
+        let blocks = listing(func, functions, obj_list).into_iter().map(|block| {
+            let instructions = block.entries.into_iter().map(|entry| {
+                let mut members = vec![
+                    ("offset", Json::Int(entry.offset as i64)),
+                    ("line", Json::Int(entry.loc.line as i64)),
+                    ("column", Json::Int(entry.loc.column as i64)),
+                    ("end_column", Json::Int(entry.loc.end_column as i64)),
+                    ("op", Json::Str(entry.mnemonic)),
+                    ("operands", Json::Array(entry.operands.iter().map(|operand| Json::Int(*operand as i64)).collect())),
+                ];
+                if let Some(target) = entry.ins.jump_target() {
+                    members.push(("target", Json::Str(format!("L{}", target))));
+                }
+                if let Some(resolved) = entry.resolved {
+                    members.push(("resolved", Json::Str(resolved)));
+                }
+                Json::object(members)
+            }).collect();
+            Json::object(vec![
+                ("label", Json::Str(format!("L{}", block.offset))),
+                ("reachable", Json::Bool(block.reachable)),
+                ("successors", Json::Array(block.successors.iter().map(|offset| Json::Str(format!("L{}", offset))).collect())),
+                ("instructions", Json::Array(instructions)),
+            ])
+        }).collect();
+        Json::object(vec![
+            ("name", Json::str(&func.name)),
+            ("arity", Json::Int(func.arity)),
+            ("file", Json::str(&func.chunk.source_map.file)),
+            ("constants", Json::Array(func.chunk.constants.iter().map(value_json).collect())),
+            ("blocks", Json::Array(blocks)),
+        ])
+    }).collect();
+    Json::object(vec![
+        ("objects", Json::Array(objects)),
+        ("functions", Json::Array(functions_json)),
+    ]).to_string()
+}
+
+/// An instruction of a listing.
+struct Entry {
+    offset: usize,
+    loc: Loc,
+    ins: ByteCode,
+    mnemonic: String,
+    operands: Vec<usize>,
+    /// What the constant operand refers to.
+    resolved: Option<String>,
+}
+
+/// A basic block of a listing, blocks are named by their offset.
+struct ListedBlock {
+    offset: usize,
+    reachable: bool,
+    successors: Vec<usize>,
+    entries: Vec<Entry>,
+}
+
+fn listing(func: &Function, functions: &[Function], obj_list: &Heap) -> Vec<ListedBlock> {
+    let chunk = &func.chunk;
+    let decoded: Vec<(usize, ByteCode)> = chunk.instructions().collect();
+    let code = chunk.to_instructions();
+    let blocks = cfg::basic_blocks(&code);
+    let reachable = cfg::reachable(&blocks);
+    blocks.iter().zip(reachable).map(|(block, reachable)| {
+        let entries = decoded[block.start..block.end].iter().map(|&(offset, ins)| {
+            let mnemonic = ins.disassemble();
+            let resolved = match ins {
+                ByteCode::Value(c) | ByteCode::AddLocalConst(_, c) | ByteCode::AddConstSetLocal(c, _) |
+                ByteCode::DefGlobal(c) | ByteCode::Load(c) | ByteCode::LoadNative(c) | ByteCode::Set(c) =>
+                    chunk.constants.get(c).map(|value| resolve(value, functions, obj_list)),
+                _ => None,
+            };
+            Entry {
+                offset,
+                loc: chunk.loc(offset),
+                ins,
+                mnemonic: String::from(mnemonic.split('\t').next().unwrap()),
+                operands: ins.operands(),
+                resolved,
+            }
+        }).collect();
+        ListedBlock {
+            offset: decoded[block.start].0,
+            reachable,
+            successors: block.successors.iter().map(|(next, _)| decoded[blocks[*next].start].0).collect(),
+            entries,
+        }
+    }).collect()
+}
+
+/// Parses the output of `disassemble`, or a hand-written program.
+/// :returns: (functions, obj_list)
+pub fn assemble(text: &str) -> Result<(Vec<Function>, Heap), String> {
+    let mut assembler = Assembler::default();
+    for (i, line) in text.lines().enumerate() {
+        assembler.line(line).map_err(|msg| format!("[Assembler Error] {} at line {}", msg, i + 1))?;
+    }
+    assembler.finish_function().map_err(|msg| format!("[Assembler Error] {}", msg))?;
+    Ok((assembler.functions, assembler.obj_list))
+}
+
+/// Reads a `.asm` file into a VM ready to `interpret`.
+pub fn load(path: &str) -> Result<VirtualMachine, String> {
+    let text = fs::read_to_string(path).map_err(|err| format!("Cannot read '{}': {}", path, err))?;
+    let (functions, obj_list) = assemble(&text)?;
+    let natives = Native::new();
+    verifier::verify(&functions, &obj_list, &natives).map_err(|err| err.to_string())?;
+    Ok(VirtualMachine::from_parts(functions, obj_list, natives))
+}
+
+fn value_text(value: &Value) -> String {
+    match value {
+        Value::Nil => String::from("nil"),
+        Value::Unk => String::from("unk"),
+        Value::Bool(b) => b.to_string(),
+        Value::Int(i) => i.to_string(),
+        // `{:?}` keeps a `.` or an exponent, so floats stay apart from integers
+        Value::Float(f) => format!("{:?}", f),
+        Value::Ptr(p) => format!("ptr:{}", p),
+        Value::StaticPtr(p) => format!("static_ptr:{}", p),
+        Value::Obj(p) => format!("@{}", p),
+        Value::Function(p) => format!("fn:{}", p),
+        Value::NativeFunction(p) => format!("native:{}", p),
+    }
+}
+
+/// What a constant refers to, for comments.
+fn resolve(value: &Value, functions: &[Function], obj_list: &Heap) -> String {
+    match value {
+        Value::Obj(p) | Value::NativeFunction(p) => match obj_list.get(*p) {
+            Some(Object::String(s)) => format!("{:?}", s),
+            Some(obj) => obj.to_str(),
+            None => value.to_str(),
+        },
+        Value::Function(p) => match functions.get(*p) {
+            Some(func) => format!("<fn> {}", func.name),
+            None => value.to_str(),
+        },
+        _ => value.to_str(),
+    }
+}
+
+/// Splits a line into tokens at whitespace, keeping quoted strings whole and
+/// dropping comments.
+fn tokenize(line: &str) -> Result<Vec<String>, String> {
+    let mut tokens = vec![];
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == ';' {
+            break;
+        } else if c == '"' {
+            chars.next();
+            let mut token = String::from("\"");
+            loop {
+                match chars.next() {
+                    None => return Err(String::from("Unterminated string")),
+                    Some('"') => break,
+                    Some('\\') => {
+                        token.push('\\');
+                        token.push(chars.next().ok_or("Unterminated string")?);
+                    },
+                    Some(c) => token.push(c),
+                }
+            }
+            token.push('"');
+            tokens.push(token);
+        } else {
+            let mut token = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == ';' {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+            }
+            tokens.push(token);
+        }
+    }
+    Ok(tokens)
+}
+
+/// Reverses the escapes of `{:?}`.
+fn unescape(quoted: &str) -> Result<String, String> {
+    let mut s = String::new();
+    let mut chars = quoted[1..quoted.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            s.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => s.push('\n'),
+            Some('r') => s.push('\r'),
+            Some('t') => s.push('\t'),
+            Some('0') => s.push('\0'),
+            Some(c @ ('\\' | '"' | '\'')) => s.push(c),
+            Some('u') => {
+                let rest: String = chars.by_ref().take_while(|c| *c != '}').collect();
+                let code = rest.strip_prefix('{')
+                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                    .and_then(char::from_u32)
+                    .ok_or_else(|| format!("Invalid escape in {}", quoted))?;
+                s.push(code);
+            },
+            _ => return Err(format!("Invalid escape in {}", quoted)),
+        }
+    }
+    Ok(s)
+}
+
+
+#[derive(Default)]
+struct Assembler {
+    functions: Vec<Function>,
+    obj_list: Heap,
+    mnemonics: HashMap<String, OpCode>,
+    labels: HashMap<String, usize>,
+    /// Jumps to labels of the current function: (offset of the jump, label).
+    fixups: Vec<(usize, String)>,
+    in_function: bool,
+}
+
+impl Assembler {
+    fn line(&mut self, line: &str) -> Result<(), String> {
+        if let Some(header) = line.trim().strip_prefix("<fn>") {
+            let header = header.trim().strip_suffix(':').ok_or("Expect ':' after the function")?;
+            let (name, arity) = header.rsplit_once('/').ok_or("Expect '/arity' after the function name")?;
+            let arity = arity.parse().map_err(|_| format!("Invalid arity '{}'", arity))?;
+            self.finish_function()?;
+            self.functions.push(Function { arity, chunk: Chunk::new(), name: String::from(name) });
+            self.in_function = true;
+            return Ok(());
+        }
+        let tokens = tokenize(line)?;
+        let Some(first) = tokens.first() else {
+            return Ok(());
+        };
+        match first.as_str() {
+            ".object" => {
+                self.index(&tokens, self.obj_list.len())?;
+                let obj = self.object(&tokens[2..])?;
+                self.insert(obj)?;
+            },
+            ".file" => match &tokens[1..] {
+                [file] if file.starts_with('"') => self.chunk()?.source_map.file = unescape(file)?,
+                _ => return Err(String::from("Expect a quoted file name after '.file'")),
+            },
+            ".const" => {
+                let count = self.chunk()?.constants.len();
+                self.index(&tokens, count)?;
+                let value = self.single_value(&tokens[2..])?;
+                // kept as written, the code refers to the entries by index
+                self.chunk()?.constants.push(value);
+            },
+            label if tokens.len() == 1 && label.ends_with(':') => {
+                let offset = self.chunk()?.code.len();
+                let label = String::from(&label[..label.len() - 1]);
+                if self.labels.insert(label.clone(), offset).is_some() {
+                    return Err(format!("Label '{}' is defined twice", label));
+                }
+            },
+            _ => self.instruction(&tokens)?,
+        }
+        Ok(())
+    }
+
+    fn instruction(&mut self, tokens: &[String]) -> Result<(), String> {
+        let (loc, tokens) = if tokens[0].starts_with(|c: char| c.is_ascii_digit()) {
+            (tokens[0].parse::<Loc>()?, &tokens[1..])
+        } else {
+            (Loc::default(), tokens)
+        };
+        let mnemonic = tokens.first().ok_or("Expect an instruction after the source location")?;
+        if self.mnemonics.is_empty() {
+            self.mnemonics = OpCode::ALL.iter().map(|&op| {
+                let name = ByteCode::from_operands(op, &[0, 0, 0]).disassemble();
+                (String::from(name.split('\t').next().unwrap()), op)
+            }).collect();
+        }
+        let op = *self.mnemonics.get(mnemonic).ok_or_else(|| format!("Unknown instruction '{}'", mnemonic))?;
+        let operands = &tokens[1..];
+        if operands.len() != op.operand_count() {
+            return Err(format!("'{}' takes {} operands", mnemonic, op.operand_count()));
+        }
+        let mut values = vec![];
+        let mut label = None;
+        for (i, operand) in operands.iter().enumerate() {
+            match operand.parse::<usize>() {
+                Ok(value) => values.push(value),
+                Err(_) if op.is_jump() && i + 1 == operands.len() => {
+                    label = Some(operand.clone());
+                    values.push(0);
+                },
+                Err(_) => return Err(format!("Invalid operand '{}'", operand)),
+            }
+        }
+        let chunk = self.chunk()?;
+        let offset = chunk.code.len();
+        chunk.add(ByteCode::from_operands(op, &values), loc);
+        if let Some(label) = label {
+            self.fixups.push((offset, label));
+        }
+        Ok(())
+    }
+
+    /// Points the jumps of the current function at their labels.
+    fn finish_function(&mut self) -> Result<(), String> {
+        let fixups = std::mem::take(&mut self.fixups);
+        let labels = std::mem::take(&mut self.labels);
+        if !self.in_function {
+            return Ok(());
+        }
+        let func = self.functions.last_mut().unwrap();
+        for (offset, label) in fixups {
+            let target = *labels.get(&label)
+                .ok_or_else(|| format!("Undefined label '{}' in function '{}'", label, func.name))?;
+            let (mut ins, next) = func.chunk.decode(offset);
+            ins.set_jump_target(target);
+            func.chunk.patch(offset, ins, next - offset);
+        }
+        Ok(())
+    }
+
+    fn chunk(&mut self) -> Result<&mut Chunk, String> {
+        match self.functions.last_mut() {
+            Some(func) if self.in_function => Ok(&mut func.chunk),
+            _ => Err(String::from("Expect a function header first")),
+        }
+    }
+
+    /// Checks that the index of a table entry is the next one.
+    fn index(&self, tokens: &[String], expected: usize) -> Result<(), String> {
+        match tokens.get(1).map(|index| index.parse::<usize>()) {
+            Some(Ok(index)) if index == expected => Ok(()),
+            _ => Err(format!("Expect index {} after '{}'", expected, tokens[0])),
+        }
+    }
+
+    fn insert(&mut self, obj: Object) -> Result<Value, String> {
+        let size = match &obj {
+            Object::String(s) => Heap::string_size(s.len()),
+            Object::List(list) => Heap::list_size(list.len()),
+            _ => Heap::string_size(0),
+        };
+        self.obj_list.allocate(size)?;
+        Ok(self.obj_list.insert(obj))
+    }
+
+    fn object(&mut self, tokens: &[String]) -> Result<Object, String> {
+        match tokens.first().map(|token| token.as_str()) {
+            Some("obj") if tokens.len() == 1 => Ok(Object::Obj),
+            Some("list") => Ok(Object::List(
+                tokens[1..].iter().map(|token| self.value(token)).collect::<Result<_, _>>()?)),
+            Some(token) if tokens.len() == 1 && token.starts_with('"') => Ok(Object::String(unescape(token)?)),
+            _ => Err(String::from("Expect a string, 'list' or 'obj'")),
+        }
+    }
+
+    fn single_value(&mut self, tokens: &[String]) -> Result<Value, String> {
+        match tokens {
+            [token] => self.value(token),
+            _ => Err(String::from("Expect one value")),
+        }
+    }
+
+    fn value(&mut self, token: &str) -> Result<Value, String> {
+        let number = |s: &str| s.parse::<usize>().map_err(|_| format!("Invalid value '{}'", token));
+        Ok(match token {
+            "nil" => Value::Nil,
+            "unk" => Value::Unk,
+            "true" => Value::Bool(true),
+            "false" => Value::Bool(false),
+            _ if token.starts_with('"') => {
+                let s = unescape(token)?;
+                self.insert(Object::String(s))?
+            },
+            _ => if let Some(p) = token.strip_prefix('@') {
+                Value::Obj(number(p)?)
+            } else if let Some(p) = token.strip_prefix("fn:") {
+                Value::Function(number(p)?)
+            } else if let Some(p) = token.strip_prefix("native:") {
+                Value::NativeFunction(number(p)?)
+            } else if let Some(p) = token.strip_prefix("static_ptr:") {
+                Value::StaticPtr(number(p)?)
+            } else if let Some(p) = token.strip_prefix("ptr:") {
+                Value::Ptr(number(p)?)
+            } else if let Ok(i) = token.parse::<i64>() {
+                Value::Int(i)
+            } else if let Ok(f) = token.parse::<f64>() {
+                Value::Float(f)
+            } else {
+                return Err(format!("Invalid value '{}'", token));
+            },
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assemble_reads_back_the_disassembly() {
+        let mut scripts: Vec<String> = fs::read_dir("tests/scripts").unwrap()
+            .map(|entry| entry.unwrap().path().display().to_string())
+            .filter(|path| path.ends_with(".dpp"))
+            .collect();
+        scripts.sort();
+        for path in &scripts {
+            for opt_level in [0, 2] {
+                let codegen = crate::compile(path, opt_level);
+                let text = disassemble(&codegen.functions, &codegen.obj_list);
+                let (functions, obj_list) = assemble(&text).unwrap();
+                assert_eq!(functions.len(), codegen.functions.len(), "{}", path);
+                for (func, expected) in functions.iter().zip(&codegen.functions) {
+                    let at = format!("'{}' of {} at -O{}", expected.name, path, opt_level);
+                    assert_eq!(func.name, expected.name, "{}", at);
+                    assert_eq!(func.arity, expected.arity, "{}", at);
+                    assert_eq!(func.chunk.code, expected.chunk.code, "{}", at);
+                    assert_eq!(func.chunk.source_map, expected.chunk.source_map, "{}", at);
+                    // by their text, so that a NaN equals itself
+                    assert_eq!(format!("{:?}", func.chunk.constants), format!("{:?}", expected.chunk.constants), "{}", at);
+                }
+                assert!(obj_list.iter().eq(codegen.obj_list.iter()), "{}", path);
+            }
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use crate::source_map::Loc;
+
+/// From the first character of the first token of a node to the last
+/// character of its last token, columns are 1-based and inclusive.
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    pub end_line: usize,
+    pub end_column: usize,
+}
+
+impl Span {
+    pub fn new(line: usize, column: usize, end_line: usize, end_column: usize) -> Self {
+        Span { line, column, end_line, end_column }
+    }
+
+    /// From the start of `self` to the end of `other`.
+    pub fn to(self, other: Span) -> Self {
+        Span { end_line: other.end_line, end_column: other.end_column, ..self }
+    }
+
+    /// The first line of the span, the whole span when it fits on one line.
+    pub fn start(self) -> Loc {
+        let end_column = if self.end_line == self.line { self.end_column } else { self.column };
+        Loc::new(self.line, self.column, end_column)
+    }
+
+    /// The last line of the span, the whole span when it fits on one line.
+    pub fn end(self) -> Loc {
+        let column = if self.end_line == self.line { self.column } else { self.end_column };
+        Loc::new(self.end_line, column, self.end_column)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOp {
+    Neg, Not, LNot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Add, Sub, Mul, Div, Mod,
+    Eq, Ne, Lt, Le, Gt, Ge,
+    Shr, Shl, LAnd, LOr, LXor,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogicalOp {
+    And, Or,
+}
+
+#[derive(Debug, Clone)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub enum ExprKind {
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Bool(bool),
+    Nil,
+    Variable(String),
+    Assign { name: String, value: Box<Expr> },
+    Unary { op: UnaryOp, operand: Box<Expr> },
+    Binary { op: BinaryOp, lhs: Box<Expr>, rhs: Box<Expr> },
+    /// `and`/`or`, `op_span` is the span of the keyword.
+    Logical { op: LogicalOp, lhs: Box<Expr>, rhs: Box<Expr>, op_span: Span },
+    Group(Box<Expr>),
+    Call { callee: Box<Expr>, args: Vec<Expr> },
+    /// `[a, b, ...]`
+    List(Vec<Expr>),
+    /// `list(n)` or `list(n, value)`
+    NewList(Vec<Expr>),
+    Index { list: Box<Expr>, index: Box<Expr> },
+    SetIndex { list: Box<Expr>, index: Box<Expr>, value: Box<Expr> },
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        Expr { kind, span }
+    }
+}
+
+/// One `name [= init]` of a `let` statement. The span ends at the comma
+/// separating it from the next one, if any.
+#[derive(Debug, Clone)]
+pub struct Declarator {
+    pub name: String,
+    pub init: Option<Expr>,
+    pub span: Span,
+}
+
+/// Indented statements, the span ends at the closing dedent.
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub stmts: Vec<Stmt>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub struct FuncDecl {
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: Block,
+}
+
+#[derive(Debug, Clone)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub enum StmtKind {
+    Expression(Expr),
+    Print(Expr),
+    Let(Vec<Declarator>),
+    Func(FuncDecl),
+    Return(Option<Expr>),
+    If { cond: Expr, then_branch: Box<Stmt>, else_branch: Option<Box<Stmt>> },
+    While { cond: Expr, body: Box<Stmt> },
+    Block(Block),
+}
+
+impl Stmt {
+    pub fn new(kind: StmtKind, span: Span) -> Self {
+        Stmt { kind, span }
+    }
+}
@@ -1,34 +1,149 @@
-use std::{ops::{DerefMut, Deref}, fs::File, io::BufReader, cmp::Ordering};
+use std::{ops::{DerefMut, Deref}, fs::File, io::BufReader};
+use std::collections::HashMap;
+use std::mem::{self, Discriminant};
 use std::io::prelude::*;
 
-use crate::{value::*, object::Object};
+use crate::{value::*, object::Object, source_map::{Loc, SourceMap}};
 
-#[derive(Default, Debug, PartialEq, Clone)]
-pub enum ByteCode {    
+/// Width in bytes of a jump operand. Jump targets are fixed-width so that the
+/// parser can reserve room for a jump and fill it back later.
+pub const JUMP_OPERAND_LEN: usize = 4;
+
+#[repr(u8)]
+#[derive(Default, Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum OpCode {
+    #[default]
+    Hlt,
+    Ret,
+    Out,
+    Value,
+    Add, Sub, Mul, Div, Neg, Mod,
+    Shr, Shl, LAnd, LOr, LXor, LNot,
+    True, False,
+    Nil,
+    And, Or, Not,
+    Eq, Ne, Lt, Le, Gt, Ge,
+    Pop,
+    DefGlobal,
+    Load,
+    LoadNative,
+    Set,
+    LoadLocal,
+    SetLocal,
+    Jz,
+    Jnz,
+    J,
+    Nop,
+    Call,
+    CallNative,
+    TailCall,
+    JzKeep,
+    JnzKeep,
+    AddLocalConst,
+    AddConstSetLocal,
+    LtLocalsJz,
+    PopN,
+}
+
+impl OpCode {
+    pub const ALL: [OpCode; 48] = [
+        OpCode::Hlt, OpCode::Ret, OpCode::Out, OpCode::Value,
+        OpCode::Add, OpCode::Sub, OpCode::Mul, OpCode::Div, OpCode::Neg, OpCode::Mod,
+        OpCode::Shr, OpCode::Shl, OpCode::LAnd, OpCode::LOr, OpCode::LXor, OpCode::LNot,
+        OpCode::True, OpCode::False,
+        OpCode::Nil,
+        OpCode::And, OpCode::Or, OpCode::Not,
+        OpCode::Eq, OpCode::Ne, OpCode::Lt, OpCode::Le, OpCode::Gt, OpCode::Ge,
+        OpCode::Pop,
+        OpCode::DefGlobal,
+        OpCode::Load,
+        OpCode::LoadNative,
+        OpCode::Set,
+        OpCode::LoadLocal,
+        OpCode::SetLocal,
+        OpCode::Jz,
+        OpCode::Jnz,
+        OpCode::J,
+        OpCode::Nop,
+        OpCode::Call,
+        OpCode::CallNative,
+        OpCode::TailCall,
+        OpCode::JzKeep,
+        OpCode::JnzKeep,
+        OpCode::AddLocalConst,
+        OpCode::AddConstSetLocal,
+        OpCode::LtLocalsJz,
+        OpCode::PopN,
+    ];
+
+    pub fn from_byte(byte: u8) -> Option<OpCode> {
+        Self::ALL.get(byte as usize).copied()
+    }
+
+    /// Whether the last operand is a jump target.
+    pub fn is_jump(self) -> bool {
+        matches!(self, OpCode::Jz | OpCode::Jnz | OpCode::J | OpCode::JzKeep | OpCode::JnzKeep |
+                       OpCode::LtLocalsJz)
+    }
+
+    pub fn operand_count(self) -> usize {
+        match self {
+            OpCode::Value | OpCode::DefGlobal | OpCode::Load | OpCode::LoadNative |
+            OpCode::Set | OpCode::LoadLocal | OpCode::SetLocal |
+            OpCode::Jz | OpCode::Jnz | OpCode::J |
+            OpCode::Call | OpCode::CallNative | OpCode::TailCall |
+            OpCode::JzKeep | OpCode::JnzKeep | OpCode::PopN => 1,
+            OpCode::AddLocalConst | OpCode::AddConstSetLocal => 2,
+            OpCode::LtLocalsJz => 3,
+            _ => 0,
+        }
+    }
+}
+
+/// A decoded instruction. Inside a `Chunk` it is stored as a one-byte `OpCode`
+/// followed by its operands, if any: jump targets take `JUMP_OPERAND_LEN` bytes,
+/// every other operand is an unsigned LEB128 varint.
+/// `Value` refers to an entry of the chunk's constant pool.
+/// `AddLocalConst`, `AddConstSetLocal` and `LtLocalsJz` are superinstructions, see `ByteCode::expand`.
+#[derive(Default, Debug, PartialEq, Clone, Copy)]
+pub enum ByteCode {
     #[default]
     Hlt,
     Ret,
     Out,
-    Value(Value),
+    Value(usize),
     Add, Sub, Mul, Div, Neg, Mod,
     Shr, Shl, LAnd, LOr, LXor, LNot,
     True, False,
     Nil,
     And, Or, Not,
     Eq, Ne, Lt, Le, Gt, Ge,
-    Pop, Push(Value),
+    Pop,
     DefGlobal(usize),
     Load(usize),
     LoadNative(usize),
     Set(usize),
     LoadLocal(usize),
     SetLocal(usize),
-    JZ(usize),
-    JNZ(usize),
+    Jz(usize),
+    Jnz(usize),
     J(usize),
     Nop,
     Call(usize),
-    CallNative(usize)
+    CallNative(usize),
+    /// `Call` that replaces the current frame, emitted for `return f(...)`.
+    TailCall(usize),
+    /// Like `Jz` and `Jnz`, but the condition stays on the stack.
+    JzKeep(usize),
+    JnzKeep(usize),
+    /// `LoadLocal(local); Value(constant); Add`
+    AddLocalConst(usize, usize),
+    /// `Value(constant); Add; SetLocal(local); Pop`
+    AddConstSetLocal(usize, usize),
+    /// `LoadLocal(a); LoadLocal(b); Lt; Jz(target)`
+    LtLocalsJz(usize, usize, usize),
+    /// Drops the given number of values, emitted when several locals leave scope.
+    PopN(usize),
 }
 
 
@@ -43,6 +158,7 @@ impl ByteCode {
             ByteCode::Sub => String::from("sub"),
             ByteCode::Mul => String::from("mul"),
             ByteCode::Div => String::from("div"),
+            ByteCode::Neg => String::from("neg"),
             ByteCode::And => String::from("and"),
             ByteCode::Or  => String::from("or"),
             ByteCode::Not => String::from("not"),
@@ -50,8 +166,8 @@ impl ByteCode {
             ByteCode::Ne  => String::from("ne"),
             ByteCode::Le  => String::from("le"),
             ByteCode::Lt => String::from("lt"),
-            ByteCode::Ge  => String::from("gt"),
-            ByteCode::Gt  => String::from("ge"),
+            ByteCode::Gt  => String::from("gt"),
+            ByteCode::Ge  => String::from("ge"),
             ByteCode::Pop  => String::from("pop"),
             ByteCode::Nop  => String::from("nop"),
             ByteCode::Mod  => String::from("mod"),
@@ -61,87 +177,428 @@ impl ByteCode {
             ByteCode::LOr  => String::from("lor"),
             ByteCode::LXor  => String::from("lxor"),
             ByteCode::LNot  => String::from("lnot"),
-            ByteCode::Push(c)  => String::from("push\t") + &c.to_str(),
-            ByteCode::Value(c) => String::from("const\t") + &c.to_str(),
+            ByteCode::True  => String::from("true"),
+            ByteCode::False  => String::from("false"),
+            ByteCode::Nil  => String::from("nil"),
+            ByteCode::Value(c) => String::from("const\t") + &c.to_string(),
             ByteCode::DefGlobal(c) => String::from("def_global\t") + &c.to_string(),
             ByteCode::Load(c) => String::from("load\t") + &c.to_string(),
             ByteCode::LoadNative(c) => String::from("load_native\t") + &c.to_string(),
             ByteCode::Set(c) => String::from("set\t") + &c.to_string(),
             ByteCode::LoadLocal(c) => String::from("load_local\t") + &c.to_string(),
             ByteCode::SetLocal(c) => String::from("set_local\t") + &c.to_string(),
-            ByteCode::JZ(c) => String::from("jz\t") + &c.to_string(),
-            ByteCode::JNZ(c) => String::from("jnz\t") + &c.to_string(),
+            ByteCode::Jz(c) => String::from("jz\t") + &c.to_string(),
+            ByteCode::Jnz(c) => String::from("jnz\t") + &c.to_string(),
             ByteCode::J(c) => String::from("j\t") + &c.to_string(),
             ByteCode::Call(c) => String::from("call\t") + &c.to_string(),
             ByteCode::CallNative(c) => String::from("call_native\t") + &c.to_string(),
-            _ => String::from("[UNK]")
+            ByteCode::TailCall(c) => String::from("tail_call\t") + &c.to_string(),
+            ByteCode::JzKeep(c) => String::from("jz_keep\t") + &c.to_string(),
+            ByteCode::JnzKeep(c) => String::from("jnz_keep\t") + &c.to_string(),
+            ByteCode::AddLocalConst(l, c) => format!("add_local_const\t{} {}", l, c),
+            ByteCode::AddConstSetLocal(c, l) => format!("add_const_set_local\t{} {}", c, l),
+            ByteCode::LtLocalsJz(a, b, t) => format!("lt_locals_jz\t{} {} {}", a, b, t),
+            ByteCode::PopN(c) => String::from("pop_n\t") + &c.to_string(),
         }
     }
 
+    pub fn opcode(&self) -> OpCode {
+        match self {
+            ByteCode::Hlt => OpCode::Hlt,
+            ByteCode::Ret => OpCode::Ret,
+            ByteCode::Out => OpCode::Out,
+            ByteCode::Value(_) => OpCode::Value,
+            ByteCode::Add => OpCode::Add,
+            ByteCode::Sub => OpCode::Sub,
+            ByteCode::Mul => OpCode::Mul,
+            ByteCode::Div => OpCode::Div,
+            ByteCode::Neg => OpCode::Neg,
+            ByteCode::Mod => OpCode::Mod,
+            ByteCode::Shr => OpCode::Shr,
+            ByteCode::Shl => OpCode::Shl,
+            ByteCode::LAnd => OpCode::LAnd,
+            ByteCode::LOr => OpCode::LOr,
+            ByteCode::LXor => OpCode::LXor,
+            ByteCode::LNot => OpCode::LNot,
+            ByteCode::True => OpCode::True,
+            ByteCode::False => OpCode::False,
+            ByteCode::Nil => OpCode::Nil,
+            ByteCode::And => OpCode::And,
+            ByteCode::Or => OpCode::Or,
+            ByteCode::Not => OpCode::Not,
+            ByteCode::Eq => OpCode::Eq,
+            ByteCode::Ne => OpCode::Ne,
+            ByteCode::Lt => OpCode::Lt,
+            ByteCode::Le => OpCode::Le,
+            ByteCode::Gt => OpCode::Gt,
+            ByteCode::Ge => OpCode::Ge,
+            ByteCode::Pop => OpCode::Pop,
+            ByteCode::DefGlobal(_) => OpCode::DefGlobal,
+            ByteCode::Load(_) => OpCode::Load,
+            ByteCode::LoadNative(_) => OpCode::LoadNative,
+            ByteCode::Set(_) => OpCode::Set,
+            ByteCode::LoadLocal(_) => OpCode::LoadLocal,
+            ByteCode::SetLocal(_) => OpCode::SetLocal,
+            ByteCode::Jz(_) => OpCode::Jz,
+            ByteCode::Jnz(_) => OpCode::Jnz,
+            ByteCode::J(_) => OpCode::J,
+            ByteCode::Nop => OpCode::Nop,
+            ByteCode::Call(_) => OpCode::Call,
+            ByteCode::CallNative(_) => OpCode::CallNative,
+            ByteCode::TailCall(_) => OpCode::TailCall,
+            ByteCode::JzKeep(_) => OpCode::JzKeep,
+            ByteCode::JnzKeep(_) => OpCode::JnzKeep,
+            ByteCode::AddLocalConst(..) => OpCode::AddLocalConst,
+            ByteCode::AddConstSetLocal(..) => OpCode::AddConstSetLocal,
+            ByteCode::LtLocalsJz(..) => OpCode::LtLocalsJz,
+            ByteCode::PopN(_) => OpCode::PopN,
+        }
+    }
 
-    pub fn disassemble_detail(&self, obj_list: &Vec<Object>) -> String {
+    pub fn operand(&self) -> Option<usize> {
         match self {
-            ByteCode::Value(Value::Obj(c)) => String::from("const\t") + obj_list[*c].to_str().as_str(),
-            _ => self.disassemble(),
+            ByteCode::Value(c) | ByteCode::DefGlobal(c) | ByteCode::Load(c) |
+            ByteCode::LoadNative(c) | ByteCode::Set(c) | ByteCode::LoadLocal(c) |
+            ByteCode::SetLocal(c) | ByteCode::Jz(c) | ByteCode::Jnz(c) | ByteCode::J(c) |
+            ByteCode::Call(c) | ByteCode::CallNative(c) | ByteCode::TailCall(c) |
+            ByteCode::JzKeep(c) | ByteCode::JnzKeep(c) | ByteCode::PopN(c) => Some(*c),
+            _ => None,
         }
     }
-    
-}
 
-impl From<f64> for ByteCode {
-    fn from(value: f64) -> Self {
-        Self::Value(Value::Float(value))
+    /// All operands in encoding order.
+    pub fn operands(&self) -> Vec<usize> {
+        match *self {
+            ByteCode::AddLocalConst(a, b) | ByteCode::AddConstSetLocal(a, b) => vec![a, b],
+            ByteCode::LtLocalsJz(a, b, c) => vec![a, b, c],
+            _ => self.operand().into_iter().collect(),
+        }
     }
-}
 
-impl From<i64> for ByteCode {
-    fn from(value: i64) -> Self {
-        Self::Value(Value::Int(value))
+    /// The instructions a superinstruction stands for, or just `self`.
+    pub fn expand(&self) -> Vec<ByteCode> {
+        match *self {
+            ByteCode::AddLocalConst(l, c) => vec![ByteCode::LoadLocal(l), ByteCode::Value(c), ByteCode::Add],
+            ByteCode::AddConstSetLocal(c, l) =>
+                vec![ByteCode::Value(c), ByteCode::Add, ByteCode::SetLocal(l), ByteCode::Pop],
+            ByteCode::LtLocalsJz(a, b, t) =>
+                vec![ByteCode::LoadLocal(a), ByteCode::LoadLocal(b), ByteCode::Lt, ByteCode::Jz(t)],
+            _ => vec![*self],
+        }
     }
-}
 
-impl From<bool> for ByteCode {
-    fn from(value: bool) -> Self {
-        Self::Value(Value::Bool(value))
+    pub fn from_operands(op: OpCode, operands: &[usize]) -> Self {
+        let operand = operands.first().copied().unwrap_or(0);
+        match op {
+            OpCode::Hlt => ByteCode::Hlt,
+            OpCode::Ret => ByteCode::Ret,
+            OpCode::Out => ByteCode::Out,
+            OpCode::Value => ByteCode::Value(operand),
+            OpCode::Add => ByteCode::Add,
+            OpCode::Sub => ByteCode::Sub,
+            OpCode::Mul => ByteCode::Mul,
+            OpCode::Div => ByteCode::Div,
+            OpCode::Neg => ByteCode::Neg,
+            OpCode::Mod => ByteCode::Mod,
+            OpCode::Shr => ByteCode::Shr,
+            OpCode::Shl => ByteCode::Shl,
+            OpCode::LAnd => ByteCode::LAnd,
+            OpCode::LOr => ByteCode::LOr,
+            OpCode::LXor => ByteCode::LXor,
+            OpCode::LNot => ByteCode::LNot,
+            OpCode::True => ByteCode::True,
+            OpCode::False => ByteCode::False,
+            OpCode::Nil => ByteCode::Nil,
+            OpCode::And => ByteCode::And,
+            OpCode::Or => ByteCode::Or,
+            OpCode::Not => ByteCode::Not,
+            OpCode::Eq => ByteCode::Eq,
+            OpCode::Ne => ByteCode::Ne,
+            OpCode::Lt => ByteCode::Lt,
+            OpCode::Le => ByteCode::Le,
+            OpCode::Gt => ByteCode::Gt,
+            OpCode::Ge => ByteCode::Ge,
+            OpCode::Pop => ByteCode::Pop,
+            OpCode::DefGlobal => ByteCode::DefGlobal(operand),
+            OpCode::Load => ByteCode::Load(operand),
+            OpCode::LoadNative => ByteCode::LoadNative(operand),
+            OpCode::Set => ByteCode::Set(operand),
+            OpCode::LoadLocal => ByteCode::LoadLocal(operand),
+            OpCode::SetLocal => ByteCode::SetLocal(operand),
+            OpCode::Jz => ByteCode::Jz(operand),
+            OpCode::Jnz => ByteCode::Jnz(operand),
+            OpCode::J => ByteCode::J(operand),
+            OpCode::Nop => ByteCode::Nop,
+            OpCode::Call => ByteCode::Call(operand),
+            OpCode::CallNative => ByteCode::CallNative(operand),
+            OpCode::TailCall => ByteCode::TailCall(operand),
+            OpCode::JzKeep => ByteCode::JzKeep(operand),
+            OpCode::JnzKeep => ByteCode::JnzKeep(operand),
+            OpCode::AddLocalConst => ByteCode::AddLocalConst(operand, operands[1]),
+            OpCode::AddConstSetLocal => ByteCode::AddConstSetLocal(operand, operands[1]),
+            OpCode::LtLocalsJz => ByteCode::LtLocalsJz(operand, operands[1], operands[2]),
+            OpCode::PopN => ByteCode::PopN(operand),
+        }
+    }
+
+    /// Number of bytes this instruction takes once encoded.
+    pub fn encoded_len(&self) -> usize {
+        let mut code = Vec::new();
+        self.encode(&mut code);
+        code.len()
+    }
+
+    pub fn jump_target(&self) -> Option<usize> {
+        match self {
+            ByteCode::Jz(c) | ByteCode::Jnz(c) | ByteCode::J(c) |
+            ByteCode::JzKeep(c) | ByteCode::JnzKeep(c) | ByteCode::LtLocalsJz(_, _, c) => Some(*c),
+            _ => None,
+        }
+    }
+
+    pub fn set_jump_target(&mut self, target: usize) {
+        match self {
+            ByteCode::Jz(c) | ByteCode::Jnz(c) | ByteCode::J(c) |
+            ByteCode::JzKeep(c) | ByteCode::JnzKeep(c) | ByteCode::LtLocalsJz(_, _, c) => *c = target,
+            _ => (),
+        }
+    }
+
+    pub fn encode(&self, code: &mut Vec<u8>) {
+        let op = self.opcode();
+        code.push(op as u8);
+        let operands = self.operands();
+        for (i, &operand) in operands.iter().enumerate() {
+            if op.is_jump() && i + 1 == operands.len() {
+                assert!(operand <= u32::MAX as usize, "Jump target {} out of range", operand);
+                code.extend_from_slice(&(operand as u32).to_le_bytes());
+            } else {
+                let mut operand = operand;
+                while operand >= 0x80 {
+                    code.push((operand as u8 & 0x7f) | 0x80);
+                    operand >>= 7;
+                }
+                code.push(operand as u8);
+            }
+        }
     }
-}
 
-// impl From<String> for ByteCode {
-//     fn from(value: String) -> Self {
-//         Self::Value(Value::String(value))
-//     }
-// }
+    /// Decodes the instruction starting at `offset`.
+    /// :returns: (instruction, offset of the next instruction)
+    pub fn decode(code: &[u8], offset: usize) -> (ByteCode, usize) {
+        Self::try_decode(code, offset)
+            .unwrap_or_else(|err| panic!("{} at {}", err, offset))
+    }
 
+    /// Like `decode`, but fails on unknown opcodes and truncated operands.
+    pub fn try_decode(code: &[u8], offset: usize) -> Result<(ByteCode, usize), String> {
+        let byte = *code.get(offset).ok_or("Unexpected end of code")?;
+        let op = OpCode::from_byte(byte).ok_or_else(|| format!("Unknown opcode {}", byte))?;
+        let mut next = offset + 1;
+        let count = op.operand_count();
+        let mut operands = [0usize; 3];
+        for (i, operand) in operands.iter_mut().enumerate().take(count) {
+            if op.is_jump() && i + 1 == count {
+                let bytes = code.get(next..next + JUMP_OPERAND_LEN).ok_or("Truncated jump target")?;
+                *operand = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+                next += JUMP_OPERAND_LEN;
+                continue;
+            }
+            let mut shift = 0;
+            loop {
+                let byte = *code.get(next).ok_or("Truncated operand")?;
+                next += 1;
+                if shift >= usize::BITS {
+                    return Err(String::from("Operand too large"));
+                }
+                *operand |= ((byte & 0x7f) as usize) << shift;
+                if byte & 0x80 == 0 {
+                    break;
+                }
+                shift += 7;
+            }
+        }
+        Ok((ByteCode::from_operands(op, &operands[..count]), next))
+    }
+
+}
 
 
-#[derive(Default, Debug, Clone, PartialEq)]
+#[derive(Default, Debug, Clone)]
 pub struct Chunk {
-    pub code: Vec<ByteCode>,
-    pub lines: Vec<usize>,
-    // pub ip: u64,
+    pub code: Vec<u8>,
+    /// Source location of every byte in `code`.
+    pub source_map: SourceMap,
+    pub constants: Vec<Value>,
+    /// Where `add_constant` finds each entry of `constants`.
+    constant_index: HashMap<ConstantKey, usize>,
+}
+
+/// The variant of a constant and its bits.
+type ConstantKey = (Discriminant<Value>, u64);
+
+/// Chunks are equal when their code, source map and constants are.
+impl PartialEq for Chunk {
+    fn eq(&self, other: &Self) -> bool {
+        self.code == other.code && self.source_map == other.source_map && self.constants == other.constants
+    }
+}
+
+
+pub struct Instructions<'a> {
+    code: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Iterator for Instructions<'a> {
+    type Item = (usize, ByteCode);
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.code.len() {
+            return None;
+        }
+        let offset = self.offset;
+        let (ins, next) = ByteCode::decode(self.code, offset);
+        self.offset = next;
+        Some((offset, ins))
+    }
 }
 
 
 impl Chunk {
     pub fn new() -> Self {
-        Self { code: Vec::new(), lines: Vec::new() }
+        Self::default()
+    }
+
+    pub fn from_parts(code: Vec<u8>, source_map: SourceMap, constants: Vec<Value>) -> Self {
+        Self { code, source_map, constants, constant_index: HashMap::new() }
+    }
+
+    pub fn decode(&self, offset: usize) -> (ByteCode, usize) {
+        ByteCode::decode(&self.code, offset)
+    }
+
+    /// Iterates over `(offset, instruction)` pairs.
+    pub fn instructions(&self) -> Instructions<'_> {
+        Instructions { code: &self.code, offset: 0 }
+    }
+
+    /// Source location of the byte at `offset`.
+    pub fn loc(&self, offset: usize) -> Loc {
+        self.source_map.get(offset).unwrap_or_default()
+    }
+
+    pub fn line(&self, offset: usize) -> usize {
+        self.loc(offset).line
+    }
+
+    /// Decodes the whole chunk into `(instruction, location)` pairs, with jump
+    /// targets turned into instruction indices.
+    pub fn to_instructions(&self) -> Vec<(ByteCode, Loc)> {
+        let mut index = vec![usize::MAX; self.code.len() + 1];
+        let mut result = vec![];
+        for (offset, ins) in self.instructions() {
+            index[offset] = result.len();
+            result.push((ins, self.loc(offset)));
+        }
+        index[self.code.len()] = result.len();
+        for (ins, _) in result.iter_mut() {
+            if let Some(target) = ins.jump_target() {
+                assert!(index[target] != usize::MAX, "Jump into the middle of an instruction");
+                ins.set_jump_target(index[target]);
+            }
+        }
+        result
+    }
+
+    /// Re-encodes instructions produced by `to_instructions`.
+    pub fn set_instructions(&mut self, instructions: &[(ByteCode, Loc)]) {
+        let mut offsets = Vec::with_capacity(instructions.len() + 1);
+        let mut offset = 0;
+        for (ins, _) in instructions {
+            offsets.push(offset);
+            offset += ins.encoded_len();
+        }
+        offsets.push(offset);
+        self.code.clear();
+        self.source_map.clear();
+        for (ins, loc) in instructions {
+            let mut ins = *ins;
+            if let Some(target) = ins.jump_target() {
+                ins.set_jump_target(offsets[target]);
+            }
+            self.add(ins, *loc);
+        }
+    }
+
+    pub fn disassemble_ins(&self, ins: &ByteCode) -> String {
+        match ins {
+            ByteCode::Value(c) => String::from("const\t") + &self.constants[*c].to_str(),
+            ByteCode::AddLocalConst(l, c) =>
+                format!("add_local_const\t{} {}", l, self.constants[*c].to_str()),
+            ByteCode::AddConstSetLocal(c, l) =>
+                format!("add_const_set_local\t{} {}", self.constants[*c].to_str(), l),
+            _ => ins.disassemble(),
+        }
+    }
+
+    pub fn disassemble_detail(&self, ins: &ByteCode, obj_list: &[Object]) -> String {
+        match ins {
+            ByteCode::Value(c) => match self.constants[*c] {
+                Value::Obj(obj) => String::from("const\t") + obj_list[obj].to_str().as_str(),
+                _ => self.disassemble_ins(ins),
+            },
+            _ => self.disassemble_ins(ins),
+        }
     }
 
     pub fn disassemble(&self) -> String {
         let mut asm = String::new();
-        for (idx, (ins, lineno)) 
-            in self.code.iter()
-                        .zip(&self.lines)
-                        .enumerate()
-        {
-            asm += &format!("I{}\t{}\t{}\n", idx.to_string(), lineno.to_string(), ins.disassemble());
+        for (offset, ins) in self.instructions() {
+            asm += &format!("I{}\t{}\t{}\n", offset, self.loc(offset), self.disassemble_ins(&ins));
         }
         asm
     }
 
-    pub fn add(&mut self, ins: ByteCode, lineno: usize) {
-        self.push(ins);
-        self.lines.push(lineno);
+    pub fn add(&mut self, ins: ByteCode, loc: impl Into<Loc>) {
+        let start = self.code.len();
+        ins.encode(&mut self.code);
+        self.source_map.push(loc.into(), self.code.len() - start);
+    }
+
+    /// Overwrites the instruction at `offset`, padding with `Nop` when the new
+    /// one is shorter than the bytes it replaces.
+    pub fn patch(&mut self, offset: usize, ins: ByteCode, len: usize) {
+        let mut bytes = Vec::new();
+        ins.encode(&mut bytes);
+        assert!(bytes.len() <= len, "Patched instruction does not fit");
+        bytes.resize(len, OpCode::Nop as u8);
+        self.code[offset..offset + len].copy_from_slice(&bytes);
+    }
+
+    /// Index of `value` in the constant pool, added if it is not there yet.
+    /// Floats are told apart by their bits, so `0.` and `-0.` stay two entries.
+    pub fn add_constant(&mut self, value: Value) -> usize {
+        // entries pushed to `constants` directly, e.g. by the assembler
+        for (i, constant) in self.constants.iter().enumerate().skip(self.constant_index.len()) {
+            self.constant_index.entry(Self::constant_key(constant)).or_insert(i);
+        }
+        if let Some(&index) = self.constant_index.get(&Self::constant_key(&value)) {
+            return index;
+        }
+        self.constants.push(value);
+        self.constant_index.insert(Self::constant_key(&value), self.constants.len() - 1);
+        self.constants.len() - 1
+    }
+
+    fn constant_key(value: &Value) -> ConstantKey {
+        let bits = match *value {
+            Value::Nil | Value::Unk => 0,
+            Value::Bool(b) => b as u64,
+            Value::Int(i) => i as u64,
+            Value::Float(f) => f.to_bits(),
+            Value::Ptr(p) | Value::StaticPtr(p) | Value::Obj(p) | Value::Function(p) | Value::NativeFunction(p) => p as u64,
+        };
+        (mem::discriminant(value), bits)
     }
 
     pub fn from_file(filename: &str) -> Self {
@@ -150,22 +607,25 @@ impl Chunk {
         let reader = BufReader::new(f);
         for line in reader.lines() {
             let line = line.unwrap();
-            if line.contains("\t") {
+            if line.contains('\t') {
                 // println!("[{}]", line);
-                let mut sp = line.split("\t");
+                let mut sp = line.split('\t');
                 let ins = sp.next().unwrap();
                 let number = sp.next().unwrap();
                 if ins == "C" {
-                    if let Ok(x) = number.parse::<i64>() {
-                        chunk.add(ByteCode::Value(Value::Int(x)), 0);
+                    let value = if let Ok(x) = number.parse::<i64>() {
+                        Value::Int(x)
                     } else if let Ok(x) = number.parse::<f64>() {
-                        chunk.add(ByteCode::Value(Value::Float(x)), 0);
+                        Value::Float(x)
                     } else if let Ok(x) = number.parse::<bool>() {
-                        chunk.add(ByteCode::Value(Value::Bool(x)), 0);
-                    } else if number.starts_with("P_") {
-                        let x = number[2..].parse::<usize>().unwrap();
-                        chunk.add(ByteCode::Value(Value::Ptr(x)), 0);
-                    }
+                        Value::Bool(x)
+                    } else if let Some(x) = number.strip_prefix("P_") {
+                        Value::Ptr(x.parse::<usize>().unwrap())
+                    } else {
+                        continue
+                    };
+                    let idx = chunk.add_constant(value);
+                    chunk.add(ByteCode::Value(idx), 0);
                 }
             } else {
                 let ins = line.as_str();
@@ -186,30 +646,19 @@ impl Chunk {
 
     pub fn write_file(&self, filename: &str) {
         let mut f = File::create(filename).unwrap();
-        for ins in &self.code {
-            f.write(ins.disassemble().as_bytes()).unwrap();
-            f.write("\n".as_bytes()).unwrap();
+        for (_, ins) in self.instructions() {
+            f.write_all(self.disassemble_ins(&ins).as_bytes()).unwrap();
+            f.write_all("\n".as_bytes()).unwrap();
         }
     }
 
     pub fn to_string(&self) -> String {
         // let mut f = File::create(filename).unwrap();
         let mut s = String::new();
-        for ins in &self.code {
+        for (_, ins) in self.instructions() {
             s.push_str("  ");
-            s.push_str(ins.disassemble().as_str());
-            s.push_str("\n");
-        }
-        s
-    }
-
-    pub fn to_string_detail(&self, obj_list: &Vec<Object>) -> String {
-        // let mut f = File::create(filename).unwrap();
-        let mut s = String::new();
-        for ins in &self.code {
-            s.push_str("  ");
-            s.push_str(ins.disassemble_detail(obj_list).as_str());
-            s.push_str("\n");
+            s.push_str(self.disassemble_ins(&ins).as_str());
+            s.push('\n');
         }
         s
     }
@@ -217,7 +666,7 @@ impl Chunk {
 
 
 impl Deref for Chunk {
-    type Target = Vec<ByteCode>;
+    type Target = Vec<u8>;
     fn deref(&self) -> &Self::Target {
         &self.code
     }
@@ -227,4 +676,36 @@ impl DerefMut for Chunk {
     fn deref_mut(&mut self) -> &mut Self::Target {
         &mut self.code
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn operands_round_trip() {
+        let code = [ByteCode::J(100_000), ByteCode::Jz(u32::MAX as usize), ByteCode::Value(300), ByteCode::Call(0),
+                    ByteCode::LtLocalsJz(1, 200, 70_000), ByteCode::AddConstSetLocal(128, 3), ByteCode::PopN(300), ByteCode::Ret];
+        let mut chunk = Chunk::new();
+        for ins in code {
+            chunk.add(ins, 1);
+        }
+        let decoded: Vec<ByteCode> = chunk.instructions().map(|(_, ins)| ins).collect();
+        assert_eq!(decoded, code);
+    }
+
+    #[test]
+    fn add_constant_reuses_equal_entries() {
+        let mut chunk = Chunk::new();
+        assert_eq!(chunk.add_constant(Value::Int(1)), 0);
+        assert_eq!(chunk.add_constant(Value::Float(0.)), 1);
+        assert_eq!(chunk.add_constant(Value::Float(-0.)), 2);
+        assert_eq!(chunk.add_constant(Value::Obj(1)), 3);
+        assert_eq!(chunk.add_constant(Value::Function(1)), 4);
+        assert_eq!(chunk.add_constant(Value::Int(1)), 0);
+        assert_eq!(chunk.add_constant(Value::Float(-0.)), 2);
+        chunk.constants.push(Value::Bool(true));
+        assert_eq!(chunk.add_constant(Value::Bool(true)), 5);
+        assert_eq!(chunk.constants.len(), 6);
+    }
+}
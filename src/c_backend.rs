@@ -0,0 +1,289 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::bytecode::{ByteCode, Chunk};
+use crate::heap::Heap;
+use crate::native_functions::Native;
+use crate::object::{Function, Object};
+use crate::value::Value;
+use crate::verifier;
+
+
+/// The runtime translated programs are linked with.
+const RUNTIME_H: &str = include_str!("dpp_runtime.h");
+const RUNTIME_C: &str = include_str!("dpp_runtime.c");
+
+/// Translates a compiled program to C. Every function becomes a C function
+/// whose stack is a local array: the verifier gives the stack depth before
+/// each instruction, so every stack slot is a fixed index. Objects are
+/// created in the order of `obj_list`, so they print like in the VM.
+pub fn transpile(functions: &[Function], obj_list: &Heap) -> Result<String, String> {
+    let globals = Globals::new(functions, obj_list);
+    let mut c = String::from("/* Translated by c_backend, link with dpp_runtime.c. */\n");
+    c += "#include <math.h>\n#include <stdint.h>\n#include <string.h>\n\n#include \"dpp_runtime.h\"\n\n";
+    c += &format!("static Value globals[{}];\nstatic char defined[{}];\n\n",
+                  globals.names.len().max(1), globals.names.len().max(1));
+    for id in 0..functions.len() {
+        c += &format!("static Value fn_{}(Value *args);\n", id);
+    }
+    c += "\nconst DppFunction dpp_functions[] = {\n";
+    for (id, func) in functions.iter().enumerate() {
+        c += &format!("    {{ {}, {}, fn_{} }},\n", c_string(func.name.as_bytes()), func.arity, id);
+    }
+    c += "};\n\nvoid dpp_init_objects(void) {\n";
+    for (i, obj) in obj_list.iter().enumerate() {
+        c += &match obj {
+            Object::String(s) => format!("    dpp_new_string({}, {});\n", c_string(s.as_bytes()), s.len()),
+            Object::List(list) => {
+                let mut init = format!("    dpp_new_list({});\n", list.len());
+                for (j, item) in list.iter().enumerate() {
+                    init += &format!("    dpp_list_init({}, {}, {});\n", i, j, value(item)?);
+                }
+                init
+            },
+            Object::Obj => String::from("    dpp_new_obj();\n"),
+            Object::Function(_) => return Err(String::from("Function objects cannot be translated to C")),
+        };
+    }
+    c += "}\n";
+    // the runtime implements the same natives as `Native`
+    let natives = Native::new();
+    for (id, func) in functions.iter().enumerate() {
+        c += &function(id, func, functions, obj_list, &natives, &globals)?;
+    }
+    c += "\nint main(void) {\n    dpp_start();\n    fn_0(NULL);\n    dpp_halt();\n}\n";
+    Ok(c)
+}
+
+/// Builds the executable `path` with `$CC`, `cc` by default. The C source
+/// and the runtime are written to a temporary directory that is removed
+/// afterwards.
+pub fn build(functions: &[Function], obj_list: &Heap, path: &str) -> Result<(), String> {
+    static BUILDS: AtomicUsize = AtomicUsize::new(0);
+    let c = transpile(functions, obj_list)?;
+    let dir = std::env::temp_dir().join(format!("dpp_build_{}_{}",
+        std::process::id(), BUILDS.fetch_add(1, Ordering::Relaxed)));
+    fs::create_dir_all(&dir).map_err(|err| format!("Cannot create '{}': {}", dir.display(), err))?;
+    let result = compile_c(&dir, &c, path);
+    _ = fs::remove_dir_all(&dir);
+    result
+}
+
+fn compile_c(dir: &Path, c: &str, path: &str) -> Result<(), String> {
+    let source = dir.join("program.c");
+    let runtime = dir.join("dpp_runtime.c");
+    let write = |path: &Path, text: &str| fs::write(path, text)
+        .map_err(|err| format!("Cannot write '{}': {}", path.display(), err));
+    write(&source, c)?;
+    write(&dir.join("dpp_runtime.h"), RUNTIME_H)?;
+    write(&runtime, RUNTIME_C)?;
+    let compiler = std::env::var("CC").unwrap_or_else(|_| String::from("cc"));
+    let output = Command::new(&compiler)
+        .args(["-O2", "-o", path])
+        .arg(&source)
+        .arg(&runtime)
+        .arg("-lm")
+        .output()
+        .map_err(|err| format!("Cannot run '{}': {}", compiler, err))?;
+    if !output.status.success() {
+        return Err(format!("'{}' failed:\n{}", compiler, String::from_utf8_lossy(&output.stderr)));
+    }
+    Ok(())
+}
+
+/// Global variables live in C arrays, or wasm globals for `wat`. Every
+/// function names globals with constants of its own pool, all constants
+/// with the same name share a slot.
+pub struct Globals {
+    /// Name of each slot.
+    pub names: Vec<String>,
+}
+
+impl Globals {
+    pub fn new(functions: &[Function], obj_list: &Heap) -> Self {
+        let mut names = vec![];
+        for func in functions {
+            for (_, ins) in func.chunk.instructions() {
+                if let ByteCode::DefGlobal(c) | ByteCode::Load(c) | ByteCode::Set(c) = ins {
+                    let name = Self::name(&func.chunk, c, obj_list);
+                    if !names.contains(&name) {
+                        names.push(name);
+                    }
+                }
+            }
+        }
+        Globals { names }
+    }
+
+    /// The slot and the name of the global named by constant `c` of `chunk`.
+    pub fn get(&self, chunk: &Chunk, c: usize, obj_list: &Heap) -> (usize, &str) {
+        let name = Self::name(chunk, c, obj_list);
+        let slot = self.names.iter().position(|other| *other == name).unwrap();
+        (slot, &self.names[slot])
+    }
+
+    fn name(chunk: &Chunk, c: usize, obj_list: &Heap) -> String {
+        match chunk.constants[c] {
+            Value::Obj(obj) => match obj_list.get(obj) {
+                Some(Object::String(s)) => s.clone(),
+                _ => String::new(),
+            },
+            _ => String::new(),
+        }
+    }
+}
+
+fn function(id: usize, func: &Function, functions: &[Function], obj_list: &Heap, natives: &Native,
+            globals: &Globals) -> Result<String, String> {
+    let chunk = &func.chunk;
+    let code = verifier::stack_depths(id, functions, obj_list, natives).map_err(|err| err.to_string())?;
+    let arity = func.arity as usize;
+    let targets: Vec<usize> = code.iter()
+        .filter(|(_, _, depth)| depth.is_some())
+        .filter_map(|(_, ins, _)| ins.jump_target())
+        .collect();
+    let size = code.iter().filter_map(|(_, _, depth)| *depth).max().unwrap_or(0) + 2;
+    let self_tail_call = code.iter().any(|(_, ins, _)| matches!(ins, ByteCode::TailCall(argc) if *argc == arity));
+
+    let mut c = format!("\n/* <fn> {}/{} */\n", func.name.replace("*/", "* /"), func.arity);
+    c += &format!("static Value fn_{}(Value *args) {{\n    Value s[{}];\n", id, size);
+    if arity > 0 {
+        c += &format!("    memcpy(s, args, sizeof(Value) * {});\n", arity);
+    }
+    if self_tail_call {
+        c += "entry:\n";
+    }
+    for &(offset, ins, depth) in &code {
+        let Some(d) = depth else {
+            continue
+        };
+        if targets.contains(&offset) {
+            c += &format!("L{}:\n", offset);
+        }
+        let at = c_string(chunk.source_map.describe(chunk.loc(offset)).as_bytes());
+        let constant = |c: usize| value(&chunk.constants[c]);
+        let global = |c: usize, defined: bool| {
+            let (slot, name) = globals.get(chunk, c, obj_list);
+            let msg = format!("Variable name '{}' is {}!", name, if defined { "defined" } else { "not defined" });
+            (slot, format!("if ({}defined[{}]) dpp_error({}, {}); ", if defined { "" } else { "!" },
+                           slot, c_string(msg.as_bytes()), at))
+        };
+        let binary = |f: &str| format!("s[{}] = {}(s[{}], s[{}], {});", d - 2, f, d - 2, d - 1, at);
+        let slow = |op: &str| format!("s[{}] = dpp_arith_slow({}, s[{}], s[{}], {});", d - 2, op, d - 2, d - 1, at);
+        let compare = |op: &str| format!("s[{}] = DPP_BOOL_V(dpp_compare_slow({}, s[{}], s[{}], {}));",
+                                         d - 2, op, d - 2, d - 1, at);
+        let unary = |f: &str| format!("s[{}] = {}(s[{}], {});", d - 1, f, d - 1, at);
+        let statement = match ins {
+            ByteCode::Add => binary("dpp_add"),
+            ByteCode::Sub => binary("dpp_sub"),
+            ByteCode::Mul => binary("dpp_mul"),
+            ByteCode::Div => slow("DPP_DIV"),
+            ByteCode::Mod => slow("DPP_MOD"),
+            ByteCode::Shl => slow("DPP_SHL"),
+            ByteCode::Shr => slow("DPP_SHR"),
+            ByteCode::LAnd => slow("DPP_LAND"),
+            ByteCode::LOr => slow("DPP_LOR"),
+            ByteCode::LXor => slow("DPP_LXOR"),
+            ByteCode::And => binary("dpp_and"),
+            ByteCode::Or => binary("dpp_or"),
+            ByteCode::Eq => compare("DPP_EQ"),
+            ByteCode::Ne => compare("DPP_NE"),
+            ByteCode::Lt => format!("s[{}] = DPP_BOOL_V(dpp_lt(s[{}], s[{}], {}));", d - 2, d - 2, d - 1, at),
+            ByteCode::Le => compare("DPP_LE"),
+            ByteCode::Gt => compare("DPP_GT"),
+            ByteCode::Ge => compare("DPP_GE"),
+            ByteCode::Neg => unary("dpp_neg"),
+            ByteCode::Not => unary("dpp_not"),
+            ByteCode::LNot => unary("dpp_lnot"),
+            ByteCode::Out => format!("dpp_print(s[{}]);", d - 1),
+            ByteCode::Value(k) => format!("s[{}] = {};", d, constant(k)?),
+            ByteCode::Pop | ByteCode::PopN(_) | ByteCode::Nop => continue,
+            // The VM stops at these like it does at `hlt`.
+            ByteCode::Hlt | ByteCode::Nil | ByteCode::True | ByteCode::False => String::from("dpp_halt();"),
+            ByteCode::J(t) => format!("goto L{};", t),
+            ByteCode::Jz(t) | ByteCode::JzKeep(t) => format!("if (!dpp_truth(s[{}], {})) goto L{};", d - 1, at, t),
+            ByteCode::Jnz(t) | ByteCode::JnzKeep(t) => format!("if (dpp_truth(s[{}], {})) goto L{};", d - 1, at, t),
+            ByteCode::DefGlobal(k) => {
+                let (slot, check) = global(k, true);
+                format!("{}defined[{}] = 1; globals[{}] = s[{}];", check, slot, slot, d - 1)
+            },
+            ByteCode::Load(k) => {
+                let (slot, check) = global(k, false);
+                format!("{}s[{}] = globals[{}];", check, d, slot)
+            },
+            ByteCode::Set(k) => {
+                let (slot, check) = global(k, false);
+                format!("{}globals[{}] = s[{}];", check, slot, d - 1)
+            },
+            ByteCode::LoadNative(k) => {
+                let Value::Obj(name) = chunk.constants[k] else {
+                    return Err(format!("Native name {} is not a string", k));
+                };
+                format!("s[{}] = dpp_load_native({}, {});", d, name, at)
+            },
+            ByteCode::LoadLocal(l) => format!("s[{}] = s[{}];", d, l),
+            ByteCode::SetLocal(l) => format!("s[{}] = s[{}];", l, d - 1),
+            ByteCode::Call(argc) => format!("s[{}] = dpp_call(s[{}], &s[{}], {}, {});",
+                                            d - argc - 1, d - argc - 1, d - argc, argc, at),
+            ByteCode::TailCall(argc) => {
+                let callee = d - argc - 1;
+                let mut call = String::new();
+                if argc == arity {
+                    // a function calling itself starts over in the same frame
+                    call += &format!("if (s[{}].tag == DPP_FN && s[{}].as.p == {}) {{ ", callee, callee, id);
+                    call += &format!("memmove(s, &s[{}], sizeof(Value) * {}); dpp_self_tail_call(); goto entry; }}\n    ",
+                                     d - argc, argc);
+                }
+                call + &format!("return dpp_tail_call(s[{}], &s[{}], {}, {});", callee, d - argc, argc, at)
+            },
+            ByteCode::CallNative(argc) => format!("s[{}] = dpp_call_native(s[{}], &s[{}], {}, {});",
+                                                  d - 1 - argc, d - 1, d - 1 - argc, argc, at),
+            ByteCode::Ret => format!("return s[{}];", d - 1),
+            ByteCode::AddLocalConst(l, k) => format!("s[{}] = dpp_add(s[{}], {}, {});", d, l, constant(k)?, at),
+            ByteCode::AddConstSetLocal(k, l) => format!("s[{}] = dpp_add(s[{}], {}, {});", l, d - 1, constant(k)?, at),
+            ByteCode::LtLocalsJz(a, b, t) => format!("if (!dpp_lt(s[{}], s[{}], {})) goto L{};", a, b, at, t),
+        };
+        c += &format!("    {}\n", statement);
+    }
+    // jumps may land right after the last instruction, which ends the program
+    if targets.contains(&chunk.code.len()) {
+        c += &format!("L{}:\n    dpp_halt();\n", chunk.code.len());
+    }
+    c += "}\n";
+    Ok(c)
+}
+
+/// A constant as a C expression.
+fn value(value: &Value) -> Result<String, String> {
+    Ok(match *value {
+        Value::Nil => String::from("DPP_NIL_V"),
+        Value::Bool(b) => format!("DPP_BOOL_V({})", b as u8),
+        Value::Int(i64::MIN) => String::from("DPP_INT_V(INT64_MIN)"),
+        Value::Int(i) => format!("DPP_INT_V(INT64_C({}))", i),
+        Value::Float(f) if f.is_nan() => String::from("DPP_FLOAT_V(NAN)"),
+        Value::Float(f) if f.is_infinite() => format!("DPP_FLOAT_V({}INFINITY)", if f < 0. { "-" } else { "" }),
+        // `{:?}` reads back as the same double
+        Value::Float(f) => format!("DPP_FLOAT_V({:?})", f),
+        Value::Obj(obj) => format!("DPP_OBJ_V({})", obj),
+        Value::Function(id) => format!("DPP_FN_V({})", id),
+        Value::NativeFunction(obj) => format!("DPP_NATIVE_V({})", obj),
+        Value::Unk | Value::Ptr(_) | Value::StaticPtr(_) =>
+            return Err(format!("{} cannot be translated to C", value.to_str())),
+    })
+}
+
+/// A C string literal, bytes outside printable ASCII are octal escapes.
+fn c_string(bytes: &[u8]) -> String {
+    let mut s = String::from("\"");
+    for &byte in bytes {
+        match byte {
+            b'"' | b'\\' | b'?' => { s.push('\\'); s.push(byte as char); },
+            0x20..=0x7e => s.push(byte as char),
+            _ => s += &format!("\\{:03o}", byte),
+        }
+    }
+    s.push('"');
+    s
+}
@@ -0,0 +1,127 @@
+use crate::bytecode::ByteCode;
+use crate::source_map::Loc;
+use crate::object::{Function, Object};
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Edge {
+    /// A jump is taken.
+    Taken,
+    /// Execution continues with the next instruction.
+    Fallthrough,
+}
+
+/// A run of instructions `start..end` that is only entered at `start`.
+/// Indices refer to the list produced by `Chunk::to_instructions`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BasicBlock {
+    pub start: usize,
+    pub end: usize,
+    /// `(block index, edge kind)`, leaving the end of the code is not an edge.
+    pub successors: Vec<(usize, Edge)>,
+}
+
+/// Instructions that may run right after `code[i]`.
+pub fn successors(code: &[(ByteCode, Loc)], i: usize) -> Vec<(usize, Edge)> {
+    match code[i].0 {
+        ByteCode::Ret | ByteCode::Hlt | ByteCode::TailCall(_) => vec![],
+        ByteCode::J(target) => vec![(target, Edge::Taken)],
+        ins => match ins.jump_target() {
+            Some(target) => vec![(i + 1, Edge::Fallthrough), (target, Edge::Taken)],
+            None => vec![(i + 1, Edge::Fallthrough)],
+        },
+    }
+}
+
+pub fn basic_blocks(code: &[(ByteCode, Loc)]) -> Vec<BasicBlock> {
+    let mut leader = vec![false; code.len() + 1];
+    leader[0] = true;
+    for (i, (ins, _)) in code.iter().enumerate() {
+        if let Some(target) = ins.jump_target() {
+            leader[target] = true;
+        }
+        if ins.jump_target().is_some() || 
+            matches!(ins, ByteCode::Ret | ByteCode::Hlt | ByteCode::TailCall(_)) {
+            leader[i + 1] = true;
+        }
+    }
+    let mut block_of = vec![0; code.len() + 1];
+    let mut starts = vec![];
+    for (i, is_leader) in leader.iter().enumerate().take(code.len()) {
+        if *is_leader {
+            starts.push(i);
+        }
+        block_of[i] = starts.len() - 1;
+    }
+    let mut blocks = Vec::with_capacity(starts.len());
+    for (n, start) in starts.iter().enumerate() {
+        let end = starts.get(n + 1).copied().unwrap_or(code.len());
+        let successors = successors(code, end - 1)
+            .into_iter()
+            .filter(|(target, _)| *target < code.len())
+            .map(|(target, edge)| (block_of[target], edge))
+            .collect();
+        blocks.push(BasicBlock { start: *start, end, successors });
+    }
+    blocks
+}
+
+/// Marks the blocks reachable from the entry block.
+pub fn reachable(blocks: &[BasicBlock]) -> Vec<bool> {
+    let mut seen = vec![false; blocks.len()];
+    let mut work = vec![];
+    if !blocks.is_empty() {
+        work.push(0);
+    }
+    while let Some(block) = work.pop() {
+        if seen[block] {
+            continue;
+        }
+        seen[block] = true;
+        for (next, _) in &blocks[block].successors {
+            work.push(*next);
+        }
+    }
+    seen
+}
+
+/// Graphviz DOT graph of the basic blocks of `func`. Blocks are named after
+/// the offset they start at, like the jump operands, and list their
+/// instructions with source locations. Unreachable blocks are dashed.
+pub fn to_dot(func: &Function, obj_list: &[Object]) -> String {
+    let chunk = &func.chunk;
+    let offsets: Vec<usize> = chunk.instructions().map(|(offset, _)| offset).collect();
+    let code = chunk.to_instructions();
+    let blocks = basic_blocks(&code);
+    let reachable = reachable(&blocks);
+    let mut dot = format!("digraph \"{}\" {{\n", escape(&func.name));
+    dot += "  node [shape=box, fontname=monospace];\n";
+    for (n, block) in blocks.iter().enumerate() {
+        let mut label = format!("L{}\\l", offsets[block.start]);
+        for (ins, loc) in &code[block.start..block.end] {
+            let mut ins = *ins;
+            // show jump targets as offsets again
+            if let Some(target) = ins.jump_target() {
+                ins.set_jump_target(offsets.get(target).copied().unwrap_or(chunk.code.len()));
+            }
+            label += &format!("{:>8}  {}\\l", loc, escape(&chunk.disassemble_detail(&ins, obj_list)));
+        }
+        let style = if reachable[n] { "" } else { ", style=dashed" };
+        dot += &format!("  b{} [label=\"{}\"{}];\n", n, label.replace('\t', " "), style);
+    }
+    for (n, block) in blocks.iter().enumerate() {
+        for (next, edge) in &block.successors {
+            let label = match edge {
+                Edge::Taken => "taken",
+                Edge::Fallthrough => "fallthrough",
+            };
+            dot += &format!("  b{} -> b{} [label=\"{}\"];\n", n, next, label);
+        }
+    }
+    dot += "}\n";
+    dot
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
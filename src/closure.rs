@@ -0,0 +1,357 @@
+use std::fmt;
+use std::ops::{Add, Sub, Mul, Div, Neg, Rem, Shl, BitAnd, BitXor, BitOr, Shr};
+use std::rc::Rc;
+
+use crate::bytecode::ByteCode;
+use crate::heap::Heap;
+use crate::object::{Function, Object};
+use crate::source_map::Loc;
+use crate::value::Value;
+use crate::virtual_machine::*;
+
+
+/// What the loop does after an instruction.
+pub enum Flow {
+    Next,
+    /// Continues at an instruction index.
+    Jump(usize),
+    /// A frame was pushed, popped or replaced.
+    Frame,
+    Halt,
+}
+
+/// One instruction with its operands resolved, e.g. the constant to push or
+/// the name of the global to load.
+pub type Op = Box<dyn Fn(&mut VirtualMachine) -> Result<Flow, InterpretError>>;
+
+/// A function compiled for `Backend::Closure`. Frames of this backend keep
+/// the index of the instruction in `ip`, not its byte offset.
+#[derive(Default, Clone)]
+pub struct ClosureFunction {
+    pub code: Rc<[Op]>,
+    /// The instruction every entry of `code` comes from, for debugging.
+    pub instructions: Vec<ByteCode>,
+    /// Source location of every entry of `code`.
+    pub locs: Vec<Loc>,
+}
+
+impl fmt::Debug for ClosureFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ClosureFunction({} ops)", self.code.len())
+    }
+}
+
+macro_rules! binary {
+    ($check:ident, $func:ident) => {
+        Box::new(|vm| {
+            vm.$check(vm.peek(0), vm.peek(1));
+            let a = vm.pop();
+            let b = vm.pop();
+            let value = StackElem::from(b.$func(a));
+            if value.is_nil() {
+                vm.error("Wrong object type for the operator !");
+            }
+            vm.push(value);
+            Ok(Flow::Next)
+        })
+    };
+}
+
+macro_rules! compare {
+    ($func:ident) => {
+        Box::new(|vm| {
+            vm.check_number(vm.peek(0), vm.peek(1));
+            let a = &vm.pop();
+            let b = &vm.pop();
+            vm.push(StackElem::from(b.$func(a)));
+            Ok(Flow::Next)
+        })
+    };
+}
+
+macro_rules! unary {
+    ($check:ident, $func:ident) => {
+        Box::new(|vm| {
+            vm.$check(vm.peek(0), vm.peek(0));
+            let value = StackElem::from(vm.pop().$func());
+            if value.is_nil() {
+                vm.error("Wrong object type for the operator !");
+            }
+            vm.push(value);
+            Ok(Flow::Next)
+        })
+    };
+}
+
+/// Compiles `func` to one closure per instruction. Names of globals and
+/// natives are looked up here, the verifier has checked them.
+pub fn compile(func: &Function, obj_list: &Heap) -> ClosureFunction {
+    // jump targets are instruction indices
+    let code = func.chunk.to_instructions();
+    let ops: Vec<Op> = code.iter()
+        .map(|(ins, _)| op(*ins, func, obj_list))
+        .collect();
+    ClosureFunction {
+        code: ops.into(),
+        instructions: code.iter().map(|(ins, _)| *ins).collect(),
+        locs: code.iter().map(|(_, loc)| *loc).collect(),
+    }
+}
+
+fn op(ins: ByteCode, func: &Function, obj_list: &Heap) -> Op {
+    let constant = |c: usize| StackElem::pack(func.chunk.constants[c]);
+    // `(object, string)` of a name
+    let name = |c: usize| match func.chunk.constants[c] {
+        Value::Obj(obj) => match &obj_list[obj] {
+            Object::String(s) => (obj, s.clone()),
+            _ => unreachable!("names are checked by the verifier"),
+        },
+        _ => unreachable!("names are checked by the verifier"),
+    };
+    match ins {
+        ByteCode::Add  => binary!(check_number, add),
+        ByteCode::Sub  => binary!(check_number, sub),
+        ByteCode::Mul  => binary!(check_number, mul),
+        ByteCode::Div  => binary!(check_number, div),
+        ByteCode::Mod  => binary!(check_number, rem),
+        ByteCode::Shl  => binary!(check_number, shl),
+        ByteCode::Shr  => binary!(check_number, shr),
+        ByteCode::LAnd => binary!(check_number, bitand),
+        ByteCode::LOr  => binary!(check_number, bitor),
+        ByteCode::LXor => binary!(check_number, bitxor),
+        ByteCode::And  => binary!(check_bool, bool_and),
+        ByteCode::Or   => binary!(check_bool, bool_or),
+        ByteCode::Eq   => compare!(eq),
+        ByteCode::Ne   => compare!(ne),
+        ByteCode::Lt   => compare!(lt),
+        ByteCode::Le   => compare!(le),
+        ByteCode::Gt   => compare!(gt),
+        ByteCode::Ge   => compare!(ge),
+        ByteCode::Neg  => unary!(check_number, neg),
+        ByteCode::Not  => unary!(check_bool, bool_not),
+        ByteCode::LNot => unary!(check_number, bitnot),
+        ByteCode::Out => Box::new(|vm| {
+            if let Value::Obj(c) = vm.top().unpack() {
+                println!("[STDOUT] {}", vm.obj_list[c].to_str());
+            } else {
+                println!("[STDOUT] {}", vm.top().to_str());
+            }
+            Ok(Flow::Next)
+        }),
+        ByteCode::Value(c) => {
+            let value = constant(c);
+            Box::new(move |vm| {
+                vm.push(value);
+                Ok(Flow::Next)
+            })
+        },
+        ByteCode::Pop => Box::new(|vm| {
+            vm.pop();
+            Ok(Flow::Next)
+        }),
+        ByteCode::PopN(n) => Box::new(move |vm| {
+            let len = vm.stack.len();
+            vm.stack.truncate(len - n);
+            Ok(Flow::Next)
+        }),
+        ByteCode::Nop => Box::new(|_| Ok(Flow::Next)),
+        ByteCode::Hlt => Box::new(|_| Ok(Flow::Halt)),
+        ByteCode::Nil | ByteCode::True | ByteCode::False => unreachable!("rejected by the verifier"),
+        ByteCode::J(target) => Box::new(move |_| Ok(Flow::Jump(target))),
+        ByteCode::Jz(target) | ByteCode::Jnz(target) | ByteCode::JzKeep(target) | ByteCode::JnzKeep(target) => {
+            let jump_if = matches!(ins, ByteCode::Jnz(_) | ByteCode::JnzKeep(_));
+            let keep = matches!(ins, ByteCode::JzKeep(_) | ByteCode::JnzKeep(_));
+            Box::new(move |vm| {
+                let Some(b) = vm.peek(0).as_bool() else {
+                    vm.error("Expect bool on stack top!")
+                };
+                if !keep {
+                    vm.pop();
+                }
+                Ok(if b == jump_if { Flow::Jump(target) } else { Flow::Next })
+            })
+        },
+        ByteCode::DefGlobal(c) => {
+            let (_, name) = name(c);
+            Box::new(move |vm| {
+                if vm.global.contains_key(&name) {
+                    vm.error(&format!("Variable name '{}' is defined!", name));
+                }
+                let value = vm.pop().unpack();
+                vm.global.insert(name.clone(), value);
+                Ok(Flow::Next)
+            })
+        },
+        ByteCode::Load(c) => {
+            let (_, name) = name(c);
+            Box::new(move |vm| {
+                let Some(value) = vm.global.get(&name) else {
+                    vm.error(&format!("Variable name '{}' is not defined!", name))
+                };
+                vm.push(StackElem::pack(*value));
+                Ok(Flow::Next)
+            })
+        },
+        ByteCode::Set(c) => {
+            let (_, name) = name(c);
+            Box::new(move |vm| {
+                let value = vm.peek(0).unpack();
+                let Some(global) = vm.global.get_mut(&name) else {
+                    vm.error(&format!("Variable name '{}' is not defined!", name))
+                };
+                *global = value;
+                Ok(Flow::Next)
+            })
+        },
+        ByteCode::LoadNative(c) => {
+            let (obj, name) = name(c);
+            Box::new(move |vm| {
+                if !vm.native_functions.contains_key(&name) {
+                    vm.error(&format!("Variable name '{}' is not defined!", obj));
+                }
+                vm.push(StackElem::pack(Value::NativeFunction(obj)));
+                Ok(Flow::Next)
+            })
+        },
+        ByteCode::LoadLocal(l) => Box::new(move |vm| {
+            let value = vm.stack[vm.frames.last().unwrap().slot_index + l];
+            vm.push(value);
+            Ok(Flow::Next)
+        }),
+        ByteCode::SetLocal(l) => Box::new(move |vm| {
+            let local_index = vm.frames.last().unwrap().slot_index + l;
+            vm.stack[local_index] = *vm.peek(0);
+            Ok(Flow::Next)
+        }),
+        ByteCode::Call(argc) => Box::new(move |vm| {
+            let slot = vm.stack.len() - argc;
+            let Value::Function(func_id) = vm.peek(argc).unpack() else {
+                vm.error("Expect Function in stack");
+            };
+            vm.check_arity(func_id, argc)?;
+            if vm.frames.len() >= vm.max_frames || vm.stack.len() > vm.max_stack {
+                let name = &vm.functions[func_id].name;
+                return Err(vm.runtime_error(&format!("Stack overflow in function '{}'", name)));
+            }
+            // the caller resumes right after this instruction
+            vm.frames.last_mut().unwrap().ip += 1;
+            vm.frames.push(CallFrame { func_id, ip: 0, slot_index: slot, tail_calls: 0 });
+            Ok(Flow::Frame)
+        }),
+        ByteCode::TailCall(argc) => Box::new(move |vm| {
+            let Value::Function(func_id) = vm.peek(argc).unpack() else {
+                vm.error("Expect Function in stack");
+            };
+            vm.check_arity(func_id, argc)?;
+            // the callee and its arguments take the place of the
+            // current function and its locals
+            let callee = vm.stack.len() - argc - 1;
+            let frame = vm.frames.last_mut().unwrap();
+            let base = frame.slot_index - 1;
+            frame.func_id = func_id;
+            frame.ip = 0;
+            frame.tail_calls += 1;
+            vm.stack.drain(base..callee);
+            Ok(Flow::Frame)
+        }),
+        ByteCode::CallNative(argc) => Box::new(move |vm| {
+            let Value::NativeFunction(obj_id) = vm.peek(0).unpack() else {
+                vm.error("Expect Function in stack");
+            };
+            let Object::String(func_name) = &vm.obj_list[obj_id] else {
+                vm.error("Expect String in stack");
+            };
+            let native_fn = vm.native_functions[func_name];
+            vm.pop();
+            // arguments are passed last one first
+            let start = vm.stack.len() - argc;
+            let args = vm.stack.drain(start..).rev().map(|elem| elem.unpack()).collect();
+            let val = match native_fn(&mut vm.obj_list, argc, args) {
+                Ok(val) => val,
+                Err(msg) => return Err(vm.runtime_error(&msg)),
+            };
+            vm.push(StackElem::pack(val));
+            Ok(Flow::Next)
+        }),
+        ByteCode::Ret => Box::new(|vm| {
+            let ret_val = vm.pop();
+            let slot = vm.frames.pop().unwrap().slot_index;
+            vm.stack.truncate(slot - 1);
+            vm.push(ret_val);
+            Ok(Flow::Frame)
+        }),
+        ByteCode::AddLocalConst(l, c) => {
+            let b = constant(c);
+            Box::new(move |vm| {
+                let a = vm.stack[vm.frames.last().unwrap().slot_index + l];
+                vm.check_number(&a, &b);
+                let value = a.add(b);
+                if value.is_nil() {
+                    vm.error("Wrong object type for the operator !");
+                }
+                vm.push(value);
+                Ok(Flow::Next)
+            })
+        },
+        ByteCode::AddConstSetLocal(c, l) => {
+            let b = constant(c);
+            Box::new(move |vm| {
+                vm.check_number(vm.peek(0), &b);
+                let value = vm.pop().add(b);
+                if value.is_nil() {
+                    vm.error("Wrong object type for the operator !");
+                }
+                let local_index = vm.frames.last().unwrap().slot_index + l;
+                vm.stack[local_index] = value;
+                Ok(Flow::Next)
+            })
+        },
+        ByteCode::LtLocalsJz(a, b, target) => Box::new(move |vm| {
+            let slot = vm.frames.last().unwrap().slot_index;
+            let (a, b) = (vm.stack[slot + a], vm.stack[slot + b]);
+            vm.check_number(&a, &b);
+            Ok(if a < b { Flow::Next } else { Flow::Jump(target) })
+        }),
+    }
+}
+
+impl VirtualMachine {
+    /// `run` for `Backend::Closure`.
+    pub fn run_closures(&mut self, steps: usize) -> Result<bool, InterpretError> {
+        if self.closure_code.len() != self.functions.len() {
+            self.closure_code = self.functions.iter()
+                .map(|func| compile(func, &self.obj_list))
+                .collect();
+        }
+        let mut step = 0;
+        loop {
+            let Some(frame) = self.frames.last() else {
+                return Ok(true);
+            };
+            let (func_id, mut pc) = (frame.func_id, frame.ip);
+            let code = Rc::clone(&self.closure_code[func_id].code);
+            // runs the frame until it calls or returns
+            loop {
+                let Some(op) = code.get(pc) else {
+                    return Ok(true);
+                };
+                if step == steps {
+                    return Ok(false);
+                }
+                step += 1;
+                self.check_limits()?;
+                if self.debug {
+                    let function = &self.closure_code[func_id];
+                    println!("C{}\tL{}\t{}", pc, function.locs[pc], function.instructions[pc].disassemble());
+                }
+                match op(self)? {
+                    Flow::Next => pc += 1,
+                    Flow::Jump(target) => pc = target,
+                    Flow::Frame => break,
+                    Flow::Halt => return Ok(true),
+                }
+                self.frames.last_mut().unwrap().ip = pc;
+            }
+        }
+    }
+}
@@ -0,0 +1,609 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{ast::*, bytecode::*, source_map::Loc, value::Value, object::Function, helper::ToObject, native_functions::Native, heap::Heap, optimizer};
+
+
+#[derive(Default, Debug, Clone)]
+struct Environment {
+    pub func_id: usize,
+    pub local: Vec<Local>,
+    pub scope_depth: usize,
+    /// Offset of the last instruction emitted in this function.
+    pub last_ins: Option<usize>,
+}
+
+#[derive(Default, Debug, Clone)]
+struct Local {
+    pub name: String,
+    pub depth: usize,
+    pub init: bool,
+}
+
+/// Lowers the AST produced by `Parser` to bytecode, one `Function` per
+/// declared function with `$main` first.
+#[derive(Default, Debug)]
+pub struct CodeGen {
+    pub functions: Vec<Function>,
+    /// String objects of global and native names, made once per name so the
+    /// constant pools of all functions share them.
+    names: HashMap<String, Value>,
+    /// Global variables declared so far.
+    globals: HashSet<String>,
+    env: Environment,
+    pub obj_list: Heap,
+    pub native_functions: Native,
+    /// Optimization level passed to `optimizer::optimize`, 0 disables it.
+    pub opt_level: u8,
+    pub warnings: Vec<String>,
+    /// Name of the compiled script, recorded in every source map.
+    pub file: String,
+}
+
+
+impl CodeGen {
+    pub fn new(native: Native) -> CodeGen {
+        let default_function = Function {
+            name: String::from("$main"),
+            arity: 0,
+            chunk: Chunk::new()
+        };
+        let mut result =
+        CodeGen { functions: vec![default_function], names: HashMap::new(), globals: HashSet::new(),
+                  env: Environment::default(),
+                  obj_list: Heap::new(), native_functions: native, opt_level: optimizer::DEFAULT_OPT_LEVEL, warnings: vec![],
+                  file: String::new() };
+        result.init_native();
+        result
+    }
+
+    pub fn init_native(&mut self) {
+        for (name, _) in self.native_functions.iter() {
+            let val = name.to_object(&mut self.obj_list).unwrap();
+            self.names.insert(name.clone(), val);
+        }
+    }
+
+    pub fn get_chunk(&self) -> &Chunk {
+        &self.functions[0].chunk
+    }
+
+    pub fn compile(&mut self, program: &Block) {
+        for stmt in &program.stmts {
+            self.statement(stmt);
+        }
+        self.emit_byte(ByteCode::Hlt, program.span.end());
+        for func in self.functions.iter_mut() {
+            func.chunk.source_map.file = self.file.clone();
+            for line in optimizer::unreachable_lines(&func.chunk) {
+                self.warnings.push(format!("[Parsing Warning] 'Unreachable code in {}' at line {}.",
+                                           func.name, line));
+            }
+            optimizer::optimize(&mut func.chunk, self.opt_level);
+        }
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        match &stmt.kind {
+            StmtKind::Expression(expr) => {
+                self.expression(expr);
+                self.emit_byte(ByteCode::Pop, expr.span.end());
+            },
+            StmtKind::Print(expr) => {
+                self.expression(expr);
+                self.emit_byte(ByteCode::Out, stmt.span.end());
+                self.emit_byte(ByteCode::Pop, stmt.span.end());
+            },
+            StmtKind::Let(declarators) => self.let_declaration(declarators),
+            StmtKind::Func(func) => self.func_declaration(func, stmt.span),
+            StmtKind::Return(value) => self.return_statement(value.as_ref(), stmt.span),
+            StmtKind::If { cond, then_branch, else_branch } =>
+                self.if_statement(cond, then_branch, else_branch.as_deref()),
+            StmtKind::While { cond, body } => self.while_statement(cond, body),
+            StmtKind::Block(block) => {
+                self.env.scope_depth += 1;
+                self.block(block);
+                self.end_block(block.span.end());
+            },
+        }
+    }
+
+    fn if_statement(&mut self, cond: &Expr, then_branch: &Stmt, else_branch: Option<&Stmt>) {
+        self.expression(cond);
+        let to_jump = self.emit_byte_to_fill_back(ByteCode::Nop, cond.span.end());
+        self.statement(then_branch);
+        let to_jump_end_if = self.emit_byte_to_fill_back(ByteCode::Nop, then_branch.span.end());
+        let ip = self.current_chunk().len();
+        self.set_chunk(to_jump, ByteCode::Jz(ip));
+        if let Some(else_branch) = else_branch {
+            self.statement(else_branch);
+            let ip = self.current_chunk().len();
+            self.set_chunk(to_jump_end_if, ByteCode::J(ip));
+        }
+    }
+
+    fn while_statement(&mut self, cond: &Expr, body: &Stmt) {
+        let ip_while_start = self.current_chunk().len();
+        self.expression(cond);
+        let to_jump = self.emit_byte_to_fill_back(ByteCode::Nop, cond.span.end());
+        self.statement(body);
+        let to_jump_while_start = self.emit_byte_to_fill_back(ByteCode::Nop, body.span.end());
+        self.set_chunk(to_jump_while_start, ByteCode::J(ip_while_start));
+        let ip = self.current_chunk().len();
+        self.set_chunk(to_jump, ByteCode::Jz(ip));
+    }
+
+    fn let_declaration(&mut self, declarators: &[Declarator]) {
+        for declarator in declarators {
+            let global = self.parse_variable(&declarator.name, declarator.span);
+            match &declarator.init {
+                Some(init) => self.expression(init),
+                None => self.emit_constant(Value::Nil, declarator.span.start()),
+            }
+            if global < usize::MAX {
+                self.emit_byte(ByteCode::DefGlobal(global), declarator.span.end());
+            } else {
+                self.mark_initialized();
+            }
+        }
+    }
+
+    fn return_statement(&mut self, value: Option<&Expr>, span: Span) {
+        if self.env.scope_depth == 0 || self.env.func_id == 0 {
+            self.error("No need to return in the main scope!", span.line);
+        }
+        if let Some(value) = value {
+            self.expression(value);
+            self.mark_tail_call();
+        } else {
+            self.emit_constant(Value::Nil, span.start());
+        }
+        self.emit_byte(ByteCode::Ret, span.end());
+    }
+
+    /// Turns a call that ends a returned expression into a `TailCall`. The
+    /// `Ret` after it is kept for jumps that land there.
+    fn mark_tail_call(&mut self) {
+        let Some(at) = self.env.last_ins else {
+            return
+        };
+        let chunk = self.current_chunk();
+        let (ins, next) = chunk.decode(at);
+        if let ByteCode::Call(arg_num) = ins {
+            chunk.patch(at, ByteCode::TailCall(arg_num), next - at);
+        }
+    }
+
+    fn func_declaration(&mut self, func: &FuncDecl, span: Span) {
+        let global = self.parse_variable(&func.name, span);
+        if global == usize::MAX {
+            self.mark_initialized();
+        }
+        self.functions.push(Function { arity: func.params.len() as i64, chunk: Chunk::new(), name: func.name.clone() });
+        let func_id = self.functions.len() - 1;
+        // parameters and the body share the function's outermost scope
+        let env = Environment { func_id, scope_depth: 1, ..Environment::default() };
+        let enclosing = std::mem::replace(&mut self.env, env);
+        for param in &func.params {
+            self.add_local(param, span);
+            self.mark_initialized();
+        }
+        self.block(&func.body);
+        let loc = func.body.span.end();
+        self.end_block(loc);
+        // return nil when the body does not, dead code elimination drops it otherwise
+        self.emit_constant(Value::Nil, loc);
+        self.emit_byte(ByteCode::Ret, loc);
+        self.env = enclosing;
+        // define global
+        self.emit_constant(Value::Function(func_id), loc);
+        if global < usize::MAX {
+            self.emit_byte(ByteCode::DefGlobal(global), loc);
+        }
+    }
+
+    fn block(&mut self, block: &Block) {
+        for stmt in &block.stmts {
+            self.statement(stmt);
+        }
+    }
+
+    fn end_block(&mut self, loc: Loc) {
+        self.env.scope_depth -= 1;
+        let mut count = 0;
+        while !self.env.local.is_empty() && self.env.local.last().unwrap().depth > self.env.scope_depth {
+            self.env.local.pop();
+            count += 1;
+        }
+        match count {
+            0 => (),
+            1 => self.emit_byte(ByteCode::Pop, loc),
+            _ => self.emit_byte(ByteCode::PopN(count), loc),
+        }
+    }
+
+    fn make_object<T: ToObject>(&mut self, obj: &T, line: usize) -> Value {
+        match obj.to_object(&mut self.obj_list) {
+            Ok(val) => val,
+            Err(msg) => self.error(&msg, line),
+        }
+    }
+
+    /// Adds a global or native name to the constant pool of the current
+    /// function.
+    fn name_constant(&mut self, name: &str, line: usize) -> usize {
+        let val = match self.names.get(name) {
+            Some(val) => *val,
+            None => {
+                let val = self.make_object(&String::from(name), line);
+                self.names.insert(String::from(name), val);
+                val
+            },
+        };
+        self.current_chunk().add_constant(val)
+    }
+
+    /// Declares a local in a scope, or makes a global's name constant.
+    fn parse_variable(&mut self, variable: &str, span: Span) -> usize {
+        if self.env.scope_depth > 0 {
+            self.add_local(variable, span);
+            usize::MAX
+        } else {
+            self.globals.insert(String::from(variable));
+            self.name_constant(variable, span.line)
+        }
+    }
+
+    fn add_local(&mut self, name: &str, span: Span) {
+        for local in self.env.local.iter().rev() {
+            if local.depth != self.env.scope_depth {
+                break;
+            }
+            if local.name == name {
+                self.error("defined variable!", span.line);
+            }
+        }
+        self.env.local.push(Local { name: String::from(name), depth: self.env.scope_depth, init: false })
+    }
+
+    fn mark_initialized(&mut self) {
+        let last_idx = self.env.local.len() - 1;
+        self.env.local[last_idx].init = true;
+    }
+
+    fn get_variable(&mut self, variable: &str, span: Span) -> ByteCode {
+        if variable.starts_with('$') {
+            if !self.native_functions.contains_key(variable) {
+                self.error(&format!("undefined native function {}", variable), span.line);
+            }
+            return ByteCode::LoadNative(self.name_constant(variable, span.line));
+        }
+        // local
+        for (i, local) in self.env.local.iter().enumerate().rev() {
+            if local.name == variable && local.init {
+                return ByteCode::LoadLocal(i);
+            }
+        }
+        // global
+        if !self.globals.contains(variable) {
+            self.error(&format!("undefined variable {}", variable), span.line);
+        }
+        ByteCode::Load(self.name_constant(variable, span.line))
+    }
+
+    fn expression(&mut self, expr: &Expr) {
+        let loc = expr.span.end();
+        match &expr.kind {
+            ExprKind::Int(n) => self.emit_constant(Value::Int(*n), loc),
+            ExprKind::Float(n) => self.emit_constant(Value::Float(*n), loc),
+            ExprKind::Str(s) => {
+                let val = self.make_object(s, loc.line);
+                self.emit_constant(val, loc)
+            },
+            ExprKind::Bool(b) => self.emit_constant(Value::Bool(*b), loc),
+            ExprKind::Nil => self.emit_constant(Value::Nil, loc),
+            ExprKind::Variable(name) => {
+                let index = self.get_variable(name, expr.span);
+                self.emit_byte(index, loc);
+            },
+            ExprKind::Assign { name, value } => {
+                let index = self.get_variable(name, expr.span);
+                self.expression(value);
+                match index {
+                    ByteCode::Load(c) => self.emit_byte(ByteCode::Set(c), loc),
+                    ByteCode::LoadLocal(c) => self.emit_byte(ByteCode::SetLocal(c), loc),
+                    _ => (),
+                }
+            },
+            ExprKind::Group(inner) => self.expression(inner),
+            ExprKind::Unary { op, operand } => {
+                if let Some(value) = Self::fold(expr) {
+                    return self.emit_constant(value, loc);
+                }
+                self.expression(operand);
+                self.emit_byte(Self::unary_op(*op), loc);
+            },
+            ExprKind::Binary { op, lhs, rhs } => {
+                if let Some(value) = Self::fold(expr) {
+                    return self.emit_constant(value, loc);
+                }
+                self.expression(lhs);
+                self.expression(rhs);
+                self.emit_byte(Self::binary_op(*op), loc);
+            },
+            ExprKind::Logical { op, lhs, rhs, op_span } => {
+                if let Some(value) = Self::fold(expr) {
+                    return self.emit_constant(value, loc);
+                }
+                self.short_circuit(*op, lhs, rhs, op_span.end());
+            },
+            ExprKind::Call { callee, args } => {
+                self.expression(callee);
+                for arg in args {
+                    self.expression(arg);
+                }
+                self.emit_byte(ByteCode::Call(args.len()), loc);
+            },
+            ExprKind::List(items) => {
+                let bc = self.get_variable("$list", expr.span);
+                for item in items {
+                    self.expression(item);
+                }
+                self.emit_byte(bc, loc);
+                self.emit_byte(ByteCode::CallNative(items.len()), loc);
+            },
+            ExprKind::NewList(args) => {
+                for arg in args {
+                    self.expression(arg);
+                }
+                // emitted before the closing bracket
+                let loc = args.last().map_or(loc, |arg| arg.span.end());
+                let bc = self.get_variable("$new_empty_list", expr.span);
+                self.emit_byte(bc, loc);
+                self.emit_byte(ByteCode::CallNative(args.len()), loc);
+            },
+            ExprKind::Index { list, index } => {
+                self.expression(list);
+                self.expression(index);
+                let bc = self.get_variable("$list->get", expr.span);
+                self.emit_byte(bc, loc);
+                self.emit_byte(ByteCode::CallNative(2), loc);
+            },
+            ExprKind::SetIndex { list, index, value } => {
+                self.expression(list);
+                self.expression(index);
+                self.expression(value);
+                let bc = self.get_variable("$list->set", expr.span);
+                self.emit_byte(bc, loc);
+                self.emit_byte(ByteCode::CallNative(3), loc);
+            },
+        }
+    }
+
+    /// Compiles the right operand of `and`/`or` so that it only runs when the
+    /// left one does not decide the result, which then stays on the stack.
+    fn short_circuit(&mut self, op: LogicalOp, lhs: &Expr, rhs: &Expr, op_loc: Loc) {
+        self.expression(lhs);
+        let to_jump_end = self.emit_byte_to_fill_back(ByteCode::Nop, op_loc);
+        self.emit_byte(ByteCode::Pop, op_loc);
+        self.expression(rhs);
+        let end = self.current_chunk().len();
+        let jump = match op {
+            LogicalOp::And => ByteCode::JzKeep(end),
+            LogicalOp::Or  => ByteCode::JnzKeep(end),
+        };
+        self.set_chunk(to_jump_end, jump);
+    }
+
+    fn unary_op(op: UnaryOp) -> ByteCode {
+        match op {
+            UnaryOp::Neg  => ByteCode::Neg,
+            UnaryOp::Not  => ByteCode::Not,
+            UnaryOp::LNot => ByteCode::LNot,
+        }
+    }
+
+    fn binary_op(op: BinaryOp) -> ByteCode {
+        match op {
+            BinaryOp::Add  => ByteCode::Add,
+            BinaryOp::Sub  => ByteCode::Sub,
+            BinaryOp::Mul  => ByteCode::Mul,
+            BinaryOp::Div  => ByteCode::Div,
+            BinaryOp::Mod  => ByteCode::Mod,
+            BinaryOp::Eq   => ByteCode::Eq,
+            BinaryOp::Ne   => ByteCode::Ne,
+            BinaryOp::Lt   => ByteCode::Lt,
+            BinaryOp::Le   => ByteCode::Le,
+            BinaryOp::Gt   => ByteCode::Gt,
+            BinaryOp::Ge   => ByteCode::Ge,
+            BinaryOp::Shr  => ByteCode::Shr,
+            BinaryOp::Shl  => ByteCode::Shl,
+            BinaryOp::LAnd => ByteCode::LAnd,
+            BinaryOp::LOr  => ByteCode::LOr,
+            BinaryOp::LXor => ByteCode::LXor,
+        }
+    }
+
+    /// The value of an expression made of literals only, if it can be
+    /// computed at compile time. Strings are left to the VM.
+    fn fold(expr: &Expr) -> Option<Value> {
+        match &expr.kind {
+            ExprKind::Int(n) => Some(Value::Int(*n)),
+            ExprKind::Float(n) => Some(Value::Float(*n)),
+            ExprKind::Bool(b) => Some(Value::Bool(*b)),
+            ExprKind::Nil => Some(Value::Nil),
+            ExprKind::Group(inner) => Self::fold(inner),
+            ExprKind::Unary { op, operand } =>
+                Self::fold_unary(Self::unary_op(*op), Self::fold(operand)?),
+            ExprKind::Binary { op, lhs, rhs } =>
+                Self::fold_binary(Self::binary_op(*op), Self::fold(lhs)?, Self::fold(rhs)?),
+            ExprKind::Logical { op, lhs, rhs, .. } => {
+                let op = match op {
+                    LogicalOp::And => ByteCode::And,
+                    LogicalOp::Or  => ByteCode::Or,
+                };
+                Self::fold_binary(op, Self::fold(lhs)?, Self::fold(rhs)?)
+            },
+            _ => None,
+        }
+    }
+
+    /// Evaluates a unary operator at compile time, following the VM: `None`
+    /// whenever the VM would report an error or panic.
+    fn fold_unary(op: ByteCode, a: Value) -> Option<Value> {
+        let value = match (op, a) {
+            (ByteCode::Neg, Value::Int(c)) => Value::Int(c.checked_neg()?),
+            (ByteCode::Neg, Value::Float(_)) => -a,
+            (ByteCode::Not, Value::Bool(_)) => a.bool_not(),
+            (ByteCode::LNot, Value::Int(_)) => a.bitnot(),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// Evaluates a binary operator at compile time, following the VM: `None`
+    /// whenever the VM would report an error or panic.
+    fn fold_binary(op: ByteCode, a: Value, b: Value) -> Option<Value> {
+        if let ByteCode::And | ByteCode::Or = op {
+            return match (op, a.as_bool(), b.as_bool()) {
+                (ByteCode::And, Some(_), Some(_)) => Some(a.bool_and(b)),
+                (ByteCode::Or,  Some(_), Some(_)) => Some(a.bool_or(b)),
+                _ => None,
+            };
+        }
+        if !(a.is_number() && b.is_number()) {
+            return None;
+        }
+        if let (Value::Int(c1), Value::Int(c2)) = (a, b) {
+            // integer overflow and bad shift amounts panic in the VM
+            let ok = match op {
+                ByteCode::Add => c1.checked_add(c2).is_some(),
+                ByteCode::Sub => c1.checked_sub(c2).is_some(),
+                ByteCode::Mul => c1.checked_mul(c2).is_some(),
+                ByteCode::Mod => c1.checked_rem(c2).is_some(),
+                ByteCode::Shl | ByteCode::Shr => (0..64).contains(&c2),
+                _ => true,
+            };
+            if !ok {
+                return None;
+            }
+        }
+        let value = match op {
+            ByteCode::Add  => a + b,
+            ByteCode::Sub  => a - b,
+            ByteCode::Mul  => a * b,
+            ByteCode::Div  => a / b,
+            ByteCode::Mod  => a % b,
+            ByteCode::Shl  => a << b,
+            ByteCode::Shr  => a >> b,
+            ByteCode::LAnd => a & b,
+            ByteCode::LOr  => a | b,
+            ByteCode::LXor => a ^ b,
+            ByteCode::Eq   => Value::from(a == b),
+            ByteCode::Ne   => Value::from(a != b),
+            ByteCode::Lt   => Value::from(a < b),
+            ByteCode::Le   => Value::from(a <= b),
+            ByteCode::Gt   => Value::from(a > b),
+            ByteCode::Ge   => Value::from(a >= b),
+            _ => return None,
+        };
+        if value.is_nil() { None } else { Some(value) }
+    }
+
+    fn current_chunk(&mut self) -> &mut Chunk {
+        &mut self.functions[self.env.func_id].chunk
+    }
+
+    fn set_chunk(&mut self, ip: usize, value: ByteCode) {
+        if let Some(target) = value.jump_target() {
+            if target > u32::MAX as usize {
+                let line = self.current_chunk().line(ip);
+                self.error("Too much code to jump over!", line);
+            }
+        }
+        let chunk = self.current_chunk();
+        chunk.patch(ip, value, 1 + JUMP_OPERAND_LEN);
+    }
+
+    pub fn emit_byte(&mut self, byte_code: ByteCode, loc: Loc) {
+        let chunk = self.current_chunk();
+        let ip = chunk.len();
+        chunk.add(byte_code, loc);
+        self.env.last_ins = Some(ip);
+    }
+
+    pub fn emit_constant(&mut self, value: Value, loc: Loc) {
+        let idx = self.current_chunk().add_constant(value);
+        self.emit_byte(ByteCode::Value(idx), loc);
+    }
+
+    /// Reserves room for a jump that is filled back by `set_chunk`.
+    pub fn emit_byte_to_fill_back(&mut self, byte_code: ByteCode, loc: Loc) -> usize {
+        let chunk = self.current_chunk();
+        let ip = chunk.len();
+        for _ in 0..1 + JUMP_OPERAND_LEN {
+            chunk.add(byte_code, loc);
+        }
+        self.env.last_ins = Some(ip);
+        ip
+    }
+
+    pub fn error(&self, msg: &str, line: usize) -> ! {
+        panic!("[Parsing Error] '{}' at line {}.", msg, line);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn main_code(source: &str) -> Vec<ByteCode> {
+        let codegen = crate::compile_source(source);
+        codegen.functions[0].chunk.instructions().map(|(_, ins)| ins).collect()
+    }
+
+    fn has_op(source: &str, op: ByteCode) -> bool {
+        main_code(source).contains(&op)
+    }
+
+    #[test]
+    fn folds_constant_expressions() {
+        let code = main_code("print(1 + 2 * 3 - -4)\n");
+        assert_eq!(code.iter().filter(|ins| matches!(ins, ByteCode::Value(_))).count(), 1);
+        assert!(!has_op("print(!(1 < 2))\n", ByteCode::Not));
+        assert!(!has_op("print(1 < 2 and 2 < 3)\n", ByteCode::And));
+    }
+
+    #[test]
+    fn leaves_overflow_to_the_runtime() {
+        assert!(has_op("print(9223372036854775807 + 1)\n", ByteCode::Add));
+        assert!(has_op("print(9223372036854775807 * 2)\n", ByteCode::Mul));
+        assert!(has_op("print(1 << 64)\n", ByteCode::Shl));
+        assert!(has_op("print(1 % 0)\n", ByteCode::Mod));
+    }
+
+    #[test]
+    fn does_not_fold_across_variables() {
+        assert!(has_op("let a = 1\nprint(a + 2)\n", ByteCode::Add));
+        assert!(has_op("let a = 1\nprint(2 + a)\n", ByteCode::Add));
+    }
+
+    #[test]
+    #[should_panic(expected = "No need to return in the main scope!")]
+    fn rejects_return_in_a_top_level_block() {
+        crate::compile_source("func f(a):\n    return a\nif true:\n    return f(1)\n");
+    }
+
+    #[test]
+    fn accepts_return_in_a_block_of_a_function() {
+        let codegen = crate::compile_source("func f(a):\n    if a:\n        return f(false)\n    while true:\n        return 1\nprint(f(true))\n");
+        assert!(codegen.warnings.is_empty());
+    }
+
+    #[test]
+    fn pops_the_locals_of_a_scope_at_once() {
+        let code = main_code("block:\n    let x = 1\n    let y = 2\n    let z = 3\n    print(x + y + z)\nblock:\n    let w = 4\n    print(w)\n");
+        let pop_ns: Vec<&ByteCode> = code.iter().filter(|ins| matches!(ins, ByteCode::PopN(_))).collect();
+        assert_eq!(pop_ns, [&ByteCode::PopN(3)]);
+    }
+}
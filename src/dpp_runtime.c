@@ -0,0 +1,440 @@
+#include "dpp_runtime.h"
+
+#include <math.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+/* Same limit as `virtual_machine::DEFAULT_MAX_FRAMES`. */
+#define DPP_MAX_FRAMES 1024
+
+enum { OBJ_OBJ, OBJ_LIST, OBJ_STRING };
+enum { NATIVE_NONE, NATIVE_LIST, NATIVE_LIST_GET, NATIVE_LIST_SET, NATIVE_NEW_EMPTY_LIST };
+
+typedef struct {
+    int kind;
+    /* strings */
+    char *chars;
+    /* lists */
+    Value *items;
+    size_t len;
+    /* the native a string names, if any */
+    int native;
+} Object;
+
+static Object *objects;
+static size_t object_count, object_cap;
+
+/* `at` is where a frame called the next one. */
+static struct { size_t func; size_t tail_calls; const char *at; } frames[DPP_MAX_FRAMES];
+static size_t depth;
+
+static void *checked_alloc(void *ptr, size_t size) {
+    ptr = realloc(ptr, size ? size : 1);
+    if (!ptr) {
+        dpp_error("Out of memory", "startup");
+    }
+    return ptr;
+}
+
+static size_t new_object(Object obj) {
+    if (object_count == object_cap) {
+        object_cap = object_cap ? object_cap * 2 : 64;
+        objects = checked_alloc(objects, object_cap * sizeof(Object));
+    }
+    objects[object_count] = obj;
+    return object_count++;
+}
+
+size_t dpp_new_string(const char *chars, size_t len) {
+    Object obj = { .kind = OBJ_STRING, .len = len };
+    obj.chars = checked_alloc(NULL, len + 1);
+    memcpy(obj.chars, chars, len);
+    obj.chars[len] = '\0';
+    if (strlen(obj.chars) == len) {
+        if (!strcmp(obj.chars, "$list")) obj.native = NATIVE_LIST;
+        else if (!strcmp(obj.chars, "$list->get")) obj.native = NATIVE_LIST_GET;
+        else if (!strcmp(obj.chars, "$list->set")) obj.native = NATIVE_LIST_SET;
+        else if (!strcmp(obj.chars, "$new_empty_list")) obj.native = NATIVE_NEW_EMPTY_LIST;
+    }
+    return new_object(obj);
+}
+
+size_t dpp_new_list(size_t len) {
+    Object obj = { .kind = OBJ_LIST, .len = len };
+    obj.items = checked_alloc(NULL, len * sizeof(Value));
+    for (size_t i = 0; i < len; i++) {
+        obj.items[i] = DPP_NIL_V;
+    }
+    return new_object(obj);
+}
+
+size_t dpp_new_obj(void) {
+    Object obj = { .kind = OBJ_OBJ };
+    return new_object(obj);
+}
+
+void dpp_list_init(size_t list, size_t index, Value value) {
+    objects[list].items[index] = value;
+}
+
+void dpp_start(void) {
+    depth = 1;
+    frames[0].func = 0;
+    dpp_init_objects();
+}
+
+_Noreturn void dpp_halt(void) {
+    fflush(stdout);
+    exit(0);
+}
+
+/* Prints like `VirtualMachine::runtime_error` and exits. Frames identical to
+ * the one above them, e.g. of a deep recursion, are counted, not listed. */
+_Noreturn void dpp_error(const char *msg, const char *at) {
+    fflush(stdout);
+    fprintf(stderr, "Runtime Error: %s at %s\nStack trace (most recent call first):", msg, at);
+    size_t repeated = 0;
+    for (size_t i = depth; i-- > 0;) {
+        const char *frame_at = i + 1 == depth ? at : frames[i].at;
+        if (i + 1 < depth) {
+            const char *above_at = i + 2 == depth ? at : frames[i + 1].at;
+            if (frames[i].func == frames[i + 1].func && frames[i].tail_calls == frames[i + 1].tail_calls
+                && !strcmp(frame_at, above_at)) {
+                repeated++;
+                continue;
+            }
+        }
+        if (repeated > 0) {
+            fprintf(stderr, "\n  ... %zu more frame(s) of '%s'", repeated, dpp_functions[frames[i + 1].func].name);
+            repeated = 0;
+        }
+        fprintf(stderr, "\n  %s at %s", dpp_functions[frames[i].func].name, frame_at);
+        if (frames[i].tail_calls > 0) {
+            fprintf(stderr, "\n  ... %zu tail call(s) elided", frames[i].tail_calls);
+        }
+    }
+    if (repeated > 0) {
+        fprintf(stderr, "\n  ... %zu more frame(s) of '%s'", repeated, dpp_functions[frames[0].func].name);
+    }
+    fprintf(stderr, "\n");
+    exit(1);
+}
+
+/* Rust's `Display` for f64: the shortest digits that read back as `x`,
+ * never in exponent form. */
+static void format_float(double x, char *out, size_t size) {
+    if (isnan(x)) {
+        snprintf(out, size, "NaN");
+        return;
+    }
+    if (isinf(x)) {
+        snprintf(out, size, x < 0 ? "-inf" : "inf");
+        return;
+    }
+    char sci[40];
+    for (int precision = 0; precision < 17; precision++) {
+        snprintf(sci, sizeof sci, "%.*e", precision, x);
+        if (strtod(sci, NULL) == x) {
+            break;
+        }
+    }
+    /* sci is [-]d[.ddd]e[+-]xx */
+    char digits[40];
+    size_t n = 0;
+    const char *c = sci;
+    int negative = *c == '-';
+    if (negative) c++;
+    for (; *c != 'e'; c++) {
+        if (*c != '.') digits[n++] = *c;
+    }
+    int exponent = atoi(c + 1);
+    while (n > 1 && digits[n - 1] == '0') n--;
+    size_t pos = 0;
+    char *buffer = checked_alloc(NULL, n + 400);
+    if (negative) buffer[pos++] = '-';
+    if (exponent < 0) {
+        buffer[pos++] = '0';
+        buffer[pos++] = '.';
+        for (int i = 0; i < -exponent - 1; i++) buffer[pos++] = '0';
+        for (size_t i = 0; i < n; i++) buffer[pos++] = digits[i];
+    } else {
+        for (size_t i = 0; i < n || (int)i <= exponent; i++) {
+            if ((int)i == exponent + 1) buffer[pos++] = '.';
+            buffer[pos++] = i < n ? digits[i] : '0';
+        }
+    }
+    buffer[pos] = '\0';
+    snprintf(out, size, "%s", buffer);
+    free(buffer);
+}
+
+/* `Value::to_str`. */
+static void print_value(Value value) {
+    char text[400];
+    switch (value.tag) {
+    case DPP_NIL: printf("Nil"); break;
+    case DPP_BOOL: printf(value.as.b ? "true" : "false"); break;
+    case DPP_INT: printf("%lld", (long long)value.as.i); break;
+    case DPP_FLOAT:
+        format_float(value.as.f, text, sizeof text);
+        printf(strchr(text, '.') ? "%s" : "%s.", text);
+        break;
+    case DPP_OBJ: printf("<Object> %zu", value.as.p); break;
+    case DPP_FN: printf("<Function> %zu", value.as.p); break;
+    case DPP_NATIVE: printf("<Native Fn> %zu", value.as.p); break;
+    }
+}
+
+/* `ByteCode::Out`, objects are shown with `Object::to_str`. */
+void dpp_print(Value value) {
+    printf("[STDOUT] ");
+    if (value.tag != DPP_OBJ) {
+        print_value(value);
+    } else {
+        Object *obj = &objects[value.as.p];
+        switch (obj->kind) {
+        case OBJ_OBJ: printf("<Object>"); break;
+        case OBJ_STRING: printf("<string> "); fwrite(obj->chars, 1, obj->len, stdout); break;
+        case OBJ_LIST:
+            printf("<list> [");
+            for (size_t i = 0; i < obj->len; i++) {
+                print_value(obj->items[i]);
+                if (i + 1 < obj->len) printf(", ");
+            }
+            printf("]");
+            break;
+        }
+    }
+    printf("\n");
+}
+
+static const DppFunction *enter(Value callee, size_t argc, const char *at) {
+    if (callee.tag != DPP_FN) {
+        dpp_error("Expect Function in stack", at);
+    }
+    const DppFunction *func = &dpp_functions[callee.as.p];
+    if (argc != func->arity) {
+        char msg[200];
+        snprintf(msg, sizeof msg, "Function '%.100s' expects %zu argument(s) but got %zu", func->name, func->arity, argc);
+        dpp_error(msg, at);
+    }
+    return func;
+}
+
+Value dpp_call(Value callee, Value *args, size_t argc, const char *at) {
+    const DppFunction *func = enter(callee, argc, at);
+    if (depth >= DPP_MAX_FRAMES) {
+        char msg[200];
+        snprintf(msg, sizeof msg, "Stack overflow in function '%.100s'", func->name);
+        dpp_error(msg, at);
+    }
+    frames[depth - 1].at = at;
+    frames[depth].func = callee.as.p;
+    frames[depth].tail_calls = 0;
+    depth++;
+    Value result = func->code(args);
+    depth--;
+    return result;
+}
+
+/* The callee takes the frame of the caller, as in the VM. */
+Value dpp_tail_call(Value callee, Value *args, size_t argc, const char *at) {
+    const DppFunction *func = enter(callee, argc, at);
+    frames[depth - 1].func = callee.as.p;
+    frames[depth - 1].tail_calls++;
+    return func->code(args);
+}
+
+void dpp_self_tail_call(void) {
+    frames[depth - 1].tail_calls++;
+}
+
+Value dpp_load_native(size_t name, const char *at) {
+    if (objects[name].native == NATIVE_NONE) {
+        char msg[100];
+        snprintf(msg, sizeof msg, "Variable name '%zu' is not defined!", name);
+        dpp_error(msg, at);
+    }
+    return DPP_NATIVE_V(name);
+}
+
+static Object *list_arg(Value value, const char *at) {
+    if (value.tag != DPP_OBJ) {
+        dpp_error("Expect object on arg 0", at);
+    }
+    if (objects[value.as.p].kind != OBJ_LIST) {
+        dpp_error("Expect List on arg 0", at);
+    }
+    return &objects[value.as.p];
+}
+
+static size_t list_index(Object *list, Value index, const char *at) {
+    if ((uint64_t)index.as.i >= list->len) {
+        char msg[100];
+        snprintf(msg, sizeof msg, "index out of bounds: the len is %zu but the index is %llu",
+                 list->len, (unsigned long long)index.as.i);
+        dpp_error(msg, at);
+    }
+    return (size_t)index.as.i;
+}
+
+static void check_argc(const char *name, size_t argc, size_t min, size_t max, const char *at) {
+    if (argc < min || argc > max) {
+        char msg[100];
+        snprintf(msg, sizeof msg, "Native '%s' cannot take %zu argument(s)", name, argc);
+        dpp_error(msg, at);
+    }
+}
+
+/* Arguments are in the order they were pushed. */
+Value dpp_call_native(Value native, Value *args, size_t argc, const char *at) {
+    if (native.tag != DPP_NATIVE) {
+        dpp_error("Expect Function in stack", at);
+    }
+    switch (objects[native.as.p].native) {
+    case NATIVE_LIST: {
+        size_t list = dpp_new_list(argc);
+        for (size_t i = 0; i < argc; i++) {
+            objects[list].items[i] = args[i];
+        }
+        return DPP_OBJ_V(list);
+    }
+    case NATIVE_NEW_EMPTY_LIST: {
+        check_argc("$new_empty_list", argc, 1, 2, at);
+        if (args[0].tag != DPP_INT) {
+            dpp_error("Expect int on arg 0", at);
+        }
+        if (args[0].as.i < 0) {
+            char msg[100];
+            snprintf(msg, sizeof msg, "List size cannot be negative: %lld", (long long)args[0].as.i);
+            dpp_error(msg, at);
+        }
+        size_t list = dpp_new_list((size_t)args[0].as.i);
+        if (argc == 2) {
+            for (size_t i = 0; i < objects[list].len; i++) {
+                objects[list].items[i] = args[1];
+            }
+        }
+        return DPP_OBJ_V(list);
+    }
+    case NATIVE_LIST_GET: {
+        check_argc("$list->get", argc, 2, 2, at);
+        if (args[1].tag != DPP_INT) {
+            dpp_error("Expect int on arg 1", at);
+        }
+        Object *list = list_arg(args[0], at);
+        return list->items[list_index(list, args[1], at)];
+    }
+    case NATIVE_LIST_SET: {
+        check_argc("$list->set", argc, 3, 3, at);
+        if (args[1].tag != DPP_INT) {
+            dpp_error("Expect int on arg 1", at);
+        }
+        Object *list = list_arg(args[0], at);
+        list->items[list_index(list, args[1], at)] = args[2];
+        return args[2];
+    }
+    default:
+        dpp_error("Expect String in stack", at);
+    }
+}
+
+static int is_number(Value value) {
+    return value.tag == DPP_INT || value.tag == DPP_FLOAT;
+}
+
+static double as_float(Value value) {
+    return value.tag == DPP_INT ? (double)value.as.i : value.as.f;
+}
+
+static void check_number(Value a, Value b, const char *at) {
+    if (!is_number(a) || !is_number(b)) {
+        dpp_error("The type to be operated shoule be Number", at);
+    }
+}
+
+static void check_bool(Value a, Value b, const char *at) {
+    if (a.tag != DPP_BOOL || b.tag != DPP_BOOL) {
+        dpp_error("The type to be operated shoule be Boolean", at);
+    }
+}
+
+Value dpp_arith_slow(int op, Value a, Value b, const char *at) {
+    check_number(a, b, at);
+    int ints = a.tag == DPP_INT && b.tag == DPP_INT;
+    double x = as_float(a), y = as_float(b);
+    uint64_t i = (uint64_t)a.as.i, j = (uint64_t)b.as.i;
+    switch (op) {
+    case DPP_ADD: return ints ? DPP_INT_V((int64_t)(i + j)) : DPP_FLOAT_V(x + y);
+    case DPP_SUB: return ints ? DPP_INT_V((int64_t)(i - j)) : DPP_FLOAT_V(x - y);
+    case DPP_MUL: return ints ? DPP_INT_V((int64_t)(i * j)) : DPP_FLOAT_V(x * y);
+    case DPP_DIV: return DPP_FLOAT_V(x / y);
+    default: break;
+    }
+    if (!ints) {
+        dpp_error("Wrong object type for the operator !", at);
+    }
+    switch (op) {
+    case DPP_MOD:
+        if (b.as.i == 0) {
+            dpp_error("attempt to calculate the remainder with a divisor of zero", at);
+        }
+        return DPP_INT_V(b.as.i == -1 ? 0 : a.as.i % b.as.i);
+    case DPP_SHL: return DPP_INT_V((int64_t)(i << (j & 63)));
+    case DPP_SHR: return DPP_INT_V(a.as.i >> (j & 63));
+    case DPP_LAND: return DPP_INT_V(a.as.i & b.as.i);
+    case DPP_LOR: return DPP_INT_V(a.as.i | b.as.i);
+    default: return DPP_INT_V(a.as.i ^ b.as.i);
+    }
+}
+
+/* `PartialEq` and `PartialOrd` of `Value`: an int never equals a float, but
+ * they are ordered by value. */
+int dpp_compare_slow(int op, Value a, Value b, const char *at) {
+    check_number(a, b, at);
+    int equal = a.tag != b.tag ? 0 : a.tag == DPP_INT ? a.as.i == b.as.i : a.as.f == b.as.f;
+    if (op == DPP_EQ) return equal;
+    if (op == DPP_NE) return !equal;
+    int order;
+    if (a.tag == DPP_INT && b.tag == DPP_INT) {
+        order = a.as.i == b.as.i ? 0 : a.as.i > b.as.i ? 1 : -1;
+    } else {
+        double x = as_float(a), y = as_float(b);
+        order = x == y ? 0 : x > y ? 1 : -1;
+    }
+    switch (op) {
+    case DPP_LT: return order < 0;
+    case DPP_LE: return order <= 0;
+    case DPP_GT: return order > 0;
+    default: return order >= 0;
+    }
+}
+
+Value dpp_neg(Value a, const char *at) {
+    check_number(a, a, at);
+    return a.tag == DPP_INT ? DPP_INT_V((int64_t)(0 - (uint64_t)a.as.i)) : DPP_FLOAT_V(-a.as.f);
+}
+
+Value dpp_not(Value a, const char *at) {
+    check_bool(a, a, at);
+    return DPP_BOOL_V(!a.as.b);
+}
+
+Value dpp_lnot(Value a, const char *at) {
+    check_number(a, a, at);
+    if (a.tag != DPP_INT) {
+        dpp_error("Wrong object type for the operator !", at);
+    }
+    return DPP_INT_V(~a.as.i);
+}
+
+Value dpp_and(Value a, Value b, const char *at) {
+    check_bool(a, b, at);
+    return DPP_BOOL_V(a.as.b && b.as.b);
+}
+
+Value dpp_or(Value a, Value b, const char *at) {
+    check_bool(a, b, at);
+    return DPP_BOOL_V(a.as.b || b.as.b);
+}
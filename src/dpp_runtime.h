@@ -0,0 +1,110 @@
+/* Runtime of scripts translated to C by `c_backend`. Values, objects and
+ * natives behave like those of the Rust VM, see `value.rs`, `object.rs` and
+ * `native_functions.rs`. */
+#ifndef DPP_RUNTIME_H
+#define DPP_RUNTIME_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+typedef enum { DPP_NIL, DPP_BOOL, DPP_INT, DPP_FLOAT, DPP_OBJ, DPP_FN, DPP_NATIVE } DppTag;
+
+typedef struct {
+    DppTag tag;
+    union {
+        int b;
+        int64_t i;
+        double f;
+        /* index of an object or a function */
+        size_t p;
+    } as;
+} Value;
+
+#define DPP_NIL_V       ((Value){ .tag = DPP_NIL })
+#define DPP_BOOL_V(x)   ((Value){ .tag = DPP_BOOL, .as.b = (x) })
+#define DPP_INT_V(x)    ((Value){ .tag = DPP_INT, .as.i = (x) })
+#define DPP_FLOAT_V(x)  ((Value){ .tag = DPP_FLOAT, .as.f = (x) })
+#define DPP_OBJ_V(x)    ((Value){ .tag = DPP_OBJ, .as.p = (x) })
+#define DPP_FN_V(x)     ((Value){ .tag = DPP_FN, .as.p = (x) })
+#define DPP_NATIVE_V(x) ((Value){ .tag = DPP_NATIVE, .as.p = (x) })
+
+/* A translated function takes its arguments and returns its result. */
+typedef struct {
+    const char *name;
+    size_t arity;
+    Value (*code)(Value *args);
+} DppFunction;
+
+/* Defined by the translated program. */
+extern const DppFunction dpp_functions[];
+void dpp_init_objects(void);
+
+/* `at` describes the source location of the failing instruction. */
+_Noreturn void dpp_error(const char *msg, const char *at);
+_Noreturn void dpp_halt(void);
+void dpp_start(void);
+
+size_t dpp_new_string(const char *chars, size_t len);
+size_t dpp_new_list(size_t len);
+size_t dpp_new_obj(void);
+void dpp_list_init(size_t list, size_t index, Value value);
+
+void dpp_print(Value value);
+Value dpp_call(Value callee, Value *args, size_t argc, const char *at);
+Value dpp_tail_call(Value callee, Value *args, size_t argc, const char *at);
+/* Counts a function calling itself in tail position, which reuses its frame. */
+void dpp_self_tail_call(void);
+Value dpp_call_native(Value native, Value *args, size_t argc, const char *at);
+/* The native named by string object `name`, or an error. */
+Value dpp_load_native(size_t name, const char *at);
+
+Value dpp_arith_slow(int op, Value a, Value b, const char *at);
+int dpp_compare_slow(int op, Value a, Value b, const char *at);
+
+enum { DPP_ADD, DPP_SUB, DPP_MUL, DPP_DIV, DPP_MOD, DPP_SHL, DPP_SHR, DPP_LAND, DPP_LOR, DPP_LXOR };
+enum { DPP_EQ, DPP_NE, DPP_LT, DPP_LE, DPP_GT, DPP_GE };
+
+/* Integers wrap around like the VM built in release mode. */
+static inline Value dpp_add(Value a, Value b, const char *at) {
+    if (a.tag == DPP_INT && b.tag == DPP_INT) {
+        return DPP_INT_V((int64_t)((uint64_t)a.as.i + (uint64_t)b.as.i));
+    }
+    return dpp_arith_slow(DPP_ADD, a, b, at);
+}
+
+static inline Value dpp_sub(Value a, Value b, const char *at) {
+    if (a.tag == DPP_INT && b.tag == DPP_INT) {
+        return DPP_INT_V((int64_t)((uint64_t)a.as.i - (uint64_t)b.as.i));
+    }
+    return dpp_arith_slow(DPP_SUB, a, b, at);
+}
+
+static inline Value dpp_mul(Value a, Value b, const char *at) {
+    if (a.tag == DPP_INT && b.tag == DPP_INT) {
+        return DPP_INT_V((int64_t)((uint64_t)a.as.i * (uint64_t)b.as.i));
+    }
+    return dpp_arith_slow(DPP_MUL, a, b, at);
+}
+
+static inline int dpp_lt(Value a, Value b, const char *at) {
+    if (a.tag == DPP_INT && b.tag == DPP_INT) {
+        return a.as.i < b.as.i;
+    }
+    return dpp_compare_slow(DPP_LT, a, b, at);
+}
+
+/* Conditional jumps only accept booleans. */
+static inline int dpp_truth(Value value, const char *at) {
+    if (value.tag != DPP_BOOL) {
+        dpp_error("Expect bool on stack top!", at);
+    }
+    return value.as.b;
+}
+
+Value dpp_neg(Value a, const char *at);
+Value dpp_not(Value a, const char *at);
+Value dpp_lnot(Value a, const char *at);
+Value dpp_and(Value a, Value b, const char *at);
+Value dpp_or(Value a, Value b, const char *at);
+
+#endif
@@ -0,0 +1,159 @@
+  ;; Runtime of modules translated by `wat`, spliced into each module. Values,
+  ;; errors and calls behave like `dpp_runtime.c`.
+  ;;
+  ;; A value is a tag and 64 bits: 0 nil, 1 bool, 2 int, 3 float (the bits of
+  ;; the double), 4 function (its index in the table). Globals that are not
+  ;; defined yet have tag 5. Messages and locations are the address of their
+  ;; length, followed by the bytes.
+
+  (global $depth (mut i32) (i32.const 1))
+
+  ;; Reports like `VirtualMachine::runtime_error`, `error` does not return.
+  (func $fail (param $msg i32) (param $at i32)
+    (call $error
+      (i32.add (local.get $msg) (i32.const 4)) (i32.load (local.get $msg))
+      (i32.add (local.get $at) (i32.const 4)) (i32.load (local.get $at)))
+    unreachable)
+
+  (func $check_number (param $ta i32) (param $tb i32) (param $at i32)
+    (if (i32.or
+          (i32.or (i32.lt_u (local.get $ta) (i32.const 2)) (i32.gt_u (local.get $ta) (i32.const 3)))
+          (i32.or (i32.lt_u (local.get $tb) (i32.const 2)) (i32.gt_u (local.get $tb) (i32.const 3))))
+      (then (call $fail (global.get $msg_number) (local.get $at)))))
+
+  (func $check_bool (param $ta i32) (param $tb i32) (param $at i32)
+    (if (i32.or (i32.ne (local.get $ta) (i32.const 1)) (i32.ne (local.get $tb) (i32.const 1)))
+      (then (call $fail (global.get $msg_bool) (local.get $at)))))
+
+  (func $as_float (param $t i32) (param $b i64) (result f64)
+    (if (result f64) (i32.eq (local.get $t) (i32.const 2))
+      (then (f64.convert_i64_s (local.get $b)))
+      (else (f64.reinterpret_i64 (local.get $b)))))
+
+  ;; `op` is 0 add, 1 sub, 2 mul, 3 div, 4 mod, 5 shl, 6 shr, 7 and, 8 or,
+  ;; 9 xor. Integers wrap around like the VM built in release mode.
+  (func $arith (param $op i32) (param $ta i32) (param $a i64) (param $tb i32) (param $b i64) (param $at i32)
+               (result i32 i64)
+    (local $ints i32) (local $x f64) (local $y f64)
+    (call $check_number (local.get $ta) (local.get $tb) (local.get $at))
+    (local.set $ints (i32.and (i32.eq (local.get $ta) (i32.const 2)) (i32.eq (local.get $tb) (i32.const 2))))
+    (if (i32.lt_u (local.get $op) (i32.const 4))
+      (then
+        (if (i32.and (local.get $ints) (i32.lt_u (local.get $op) (i32.const 3)))
+          (then
+            (if (i32.eqz (local.get $op))
+              (then (return (i32.const 2) (i64.add (local.get $a) (local.get $b)))))
+            (if (i32.eq (local.get $op) (i32.const 1))
+              (then (return (i32.const 2) (i64.sub (local.get $a) (local.get $b)))))
+            (return (i32.const 2) (i64.mul (local.get $a) (local.get $b)))))
+        (local.set $x (call $as_float (local.get $ta) (local.get $a)))
+        (local.set $y (call $as_float (local.get $tb) (local.get $b)))
+        (if (i32.eqz (local.get $op))
+          (then (return (i32.const 3) (i64.reinterpret_f64 (f64.add (local.get $x) (local.get $y))))))
+        (if (i32.eq (local.get $op) (i32.const 1))
+          (then (return (i32.const 3) (i64.reinterpret_f64 (f64.sub (local.get $x) (local.get $y))))))
+        (if (i32.eq (local.get $op) (i32.const 2))
+          (then (return (i32.const 3) (i64.reinterpret_f64 (f64.mul (local.get $x) (local.get $y))))))
+        (return (i32.const 3) (i64.reinterpret_f64 (f64.div (local.get $x) (local.get $y))))))
+    (if (i32.eqz (local.get $ints))
+      (then (call $fail (global.get $msg_wrong_type) (local.get $at))))
+    (if (i32.eq (local.get $op) (i32.const 4))
+      (then
+        (if (i64.eqz (local.get $b))
+          (then (call $fail (global.get $msg_mod_zero) (local.get $at))))
+        (if (i64.eq (local.get $b) (i64.const -1))
+          (then (return (i32.const 2) (i64.const 0))))
+        (return (i32.const 2) (i64.rem_s (local.get $a) (local.get $b)))))
+    (if (i32.eq (local.get $op) (i32.const 5))
+      (then (return (i32.const 2) (i64.shl (local.get $a) (local.get $b)))))
+    (if (i32.eq (local.get $op) (i32.const 6))
+      (then (return (i32.const 2) (i64.shr_s (local.get $a) (local.get $b)))))
+    (if (i32.eq (local.get $op) (i32.const 7))
+      (then (return (i32.const 2) (i64.and (local.get $a) (local.get $b)))))
+    (if (i32.eq (local.get $op) (i32.const 8))
+      (then (return (i32.const 2) (i64.or (local.get $a) (local.get $b)))))
+    (i32.const 2) (i64.xor (local.get $a) (local.get $b)))
+
+  ;; `op` is 0 eq, 1 ne, 2 lt, 3 le, 4 gt, 5 ge. An int never equals a float,
+  ;; but they are ordered by value.
+  (func $compare (param $op i32) (param $ta i32) (param $a i64) (param $tb i32) (param $b i64) (param $at i32)
+                 (result i32)
+    (local $order i32) (local $x f64) (local $y f64)
+    (call $check_number (local.get $ta) (local.get $tb) (local.get $at))
+    (if (i32.lt_u (local.get $op) (i32.const 2))
+      (then
+        (return (i32.xor
+          (i32.eq (local.get $op) (i32.const 1))
+          (if (result i32) (i32.ne (local.get $ta) (local.get $tb))
+            (then (i32.const 0))
+            (else (if (result i32) (i32.eq (local.get $ta) (i32.const 2))
+              (then (i64.eq (local.get $a) (local.get $b)))
+              (else (f64.eq (f64.reinterpret_i64 (local.get $a)) (f64.reinterpret_i64 (local.get $b)))))))))))
+    (if (i32.and (i32.eq (local.get $ta) (i32.const 2)) (i32.eq (local.get $tb) (i32.const 2)))
+      (then (local.set $order (i32.sub (i64.gt_s (local.get $a) (local.get $b)) (i64.lt_s (local.get $a) (local.get $b)))))
+      (else
+        (local.set $x (call $as_float (local.get $ta) (local.get $a)))
+        (local.set $y (call $as_float (local.get $tb) (local.get $b)))
+        (local.set $order
+          (if (result i32) (f64.eq (local.get $x) (local.get $y))
+            (then (i32.const 0))
+            (else (select (i32.const 1) (i32.const -1) (f64.gt (local.get $x) (local.get $y))))))))
+    (if (i32.eq (local.get $op) (i32.const 2))
+      (then (return (i32.lt_s (local.get $order) (i32.const 0)))))
+    (if (i32.eq (local.get $op) (i32.const 3))
+      (then (return (i32.le_s (local.get $order) (i32.const 0)))))
+    (if (i32.eq (local.get $op) (i32.const 4))
+      (then (return (i32.gt_s (local.get $order) (i32.const 0)))))
+    (i32.ge_s (local.get $order) (i32.const 0)))
+
+  ;; Conditional jumps only accept booleans.
+  (func $truth (param $t i32) (param $b i64) (param $at i32) (result i32)
+    (if (i32.ne (local.get $t) (i32.const 1))
+      (then (call $fail (global.get $msg_truth) (local.get $at))))
+    (i32.wrap_i64 (local.get $b)))
+
+  (func $neg (param $t i32) (param $b i64) (param $at i32) (result i32 i64)
+    (call $check_number (local.get $t) (local.get $t) (local.get $at))
+    (if (i32.eq (local.get $t) (i32.const 2))
+      (then (return (i32.const 2) (i64.sub (i64.const 0) (local.get $b)))))
+    (i32.const 3) (i64.reinterpret_f64 (f64.neg (f64.reinterpret_i64 (local.get $b)))))
+
+  (func $not (param $t i32) (param $b i64) (param $at i32) (result i32 i64)
+    (call $check_bool (local.get $t) (local.get $t) (local.get $at))
+    (i32.const 1) (i64.xor (local.get $b) (i64.const 1)))
+
+  (func $lnot (param $t i32) (param $b i64) (param $at i32) (result i32 i64)
+    (call $check_number (local.get $t) (local.get $t) (local.get $at))
+    (if (i32.ne (local.get $t) (i32.const 2))
+      (then (call $fail (global.get $msg_wrong_type) (local.get $at))))
+    (i32.const 2) (i64.xor (local.get $b) (i64.const -1)))
+
+  (func $and (param $ta i32) (param $a i64) (param $tb i32) (param $b i64) (param $at i32) (result i32 i64)
+    (call $check_bool (local.get $ta) (local.get $tb) (local.get $at))
+    (i32.const 1) (i64.and (local.get $a) (local.get $b)))
+
+  (func $or (param $ta i32) (param $a i64) (param $tb i32) (param $b i64) (param $at i32) (result i32 i64)
+    (call $check_bool (local.get $ta) (local.get $tb) (local.get $at))
+    (i32.const 1) (i64.or (local.get $a) (local.get $b)))
+
+  ;; The index of the function `callee`. `mismatch` is the address of the
+  ;; arity errors for the number of arguments passed, 0 when the arity is right.
+  (func $enter (param $t i32) (param $b i64) (param $mismatch i32) (param $at i32) (result i32)
+    (local $f i32) (local $msg i32)
+    (if (i32.ne (local.get $t) (i32.const 4))
+      (then (call $fail (global.get $msg_not_function) (local.get $at))))
+    (local.set $f (i32.wrap_i64 (local.get $b)))
+    (local.set $msg (i32.load (i32.add (local.get $mismatch) (i32.shl (local.get $f) (i32.const 2)))))
+    (if (local.get $msg)
+      (then (call $fail (local.get $msg) (local.get $at))))
+    (local.get $f))
+
+  ;; Counts the frame of a call that is not in tail position, like the VM
+  ;; with `DEFAULT_MAX_FRAMES`.
+  (func $push (param $f i32) (param $at i32) (result i32)
+    (if (i32.ge_u (global.get $depth) (i32.const 1024))
+      (then (call $fail
+        (i32.load (i32.add (global.get $overflow) (i32.shl (local.get $f) (i32.const 2))))
+        (local.get $at))))
+    (global.set $depth (i32.add (global.get $depth) (i32.const 1)))
+    (local.get $f))
@@ -0,0 +1,65 @@
+use std::{mem::size_of, ops::{Deref, DerefMut}};
+
+use crate::{object::Object, value::Value};
+
+
+/// Heap limit of a new `VirtualMachine`.
+pub const DEFAULT_HEAP_LIMIT: usize = 1 << 30;
+
+/// Every object created by a script lives here. Allocations must be announced
+/// through `allocate` before the object is built, so that a script cannot get
+/// past `limit` by asking for one huge object.
+#[derive(Debug, Default, Clone)]
+pub struct Heap {
+    objects: Vec<Object>,
+    pub bytes_allocated: usize,
+    /// Maximum number of bytes the objects may take, unlimited when `None`.
+    pub limit: Option<usize>,
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Heap { objects: vec![], bytes_allocated: 0, limit: None }
+    }
+
+    pub fn string_size(len: usize) -> usize {
+        size_of::<Object>().saturating_add(len)
+    }
+
+    pub fn list_size(len: usize) -> usize {
+        size_of::<Object>().saturating_add(len.saturating_mul(size_of::<Value>()))
+    }
+
+    /// Accounts for `bytes` more bytes, failing when that would exceed `limit`.
+    pub fn allocate(&mut self, bytes: usize) -> Result<(), String> {
+        let total = self.bytes_allocated.saturating_add(bytes);
+        if let Some(limit) = self.limit {
+            if total > limit {
+                return Err(format!("Out of memory: {} more bytes exceed the heap limit of {} bytes", 
+                                   bytes, limit));
+            }
+        }
+        self.bytes_allocated = total;
+        Ok(())
+    }
+
+    /// Stores an object whose size has already been passed to `allocate`.
+    pub fn insert(&mut self, obj: Object) -> Value {
+        self.objects.push(obj);
+        Value::Obj(self.objects.len() - 1)
+    }
+}
+
+
+impl Deref for Heap {
+    type Target = Vec<Object>;
+    fn deref(&self) -> &Self::Target {
+        &self.objects
+    }
+}
+
+impl DerefMut for Heap {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.objects
+    }
+}
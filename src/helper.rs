@@ -1,21 +1,21 @@
-use crate::{object::Object, value::Value};
+use crate::{object::Object, value::Value, heap::Heap};
 
 
 pub trait ToObject {
-    fn to_object(&self, obj_list: &mut Vec<Object>) -> Value;
+    fn to_object(&self, heap: &mut Heap) -> Result<Value, String>;
 }
 
 impl ToObject for String {
-    fn to_object(&self, obj_list: &mut Vec<Object>) -> Value {
-        obj_list.push(Object::String(self.clone()));
-        Value::Obj(obj_list.len() - 1)
+    fn to_object(&self, heap: &mut Heap) -> Result<Value, String> {
+        heap.allocate(Heap::string_size(self.len()))?;
+        Ok(heap.insert(Object::String(self.clone())))
     }
 }
 
 impl ToObject for Vec<Value> {
-    fn to_object(&self, obj_list: &mut Vec<Object>) -> Value {
-        obj_list.push(Object::List(self.clone()));
-        Value::Obj(obj_list.len() - 1)
+    fn to_object(&self, heap: &mut Heap) -> Result<Value, String> {
+        heap.allocate(Heap::list_size(self.len()))?;
+        Ok(heap.insert(Object::List(self.clone())))
     }
 }
 
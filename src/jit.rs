@@ -0,0 +1,781 @@
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+compile_error!("the `jit` feature needs Linux on x86-64");
+
+use std::collections::HashMap;
+use std::ffi::c_void;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::bytecode::ByteCode;
+use crate::heap::Heap;
+use crate::native_functions::Native;
+use crate::object::Function;
+use crate::value::Value;
+use crate::verifier;
+use crate::virtual_machine::*;
+
+
+/// Calls of a function before it is compiled.
+pub const JIT_THRESHOLD: u32 = 50;
+/// Deoptimizations after which a function goes back to the interpreter for good.
+pub const MAX_DEOPTS: u32 = 16;
+
+/// A value in a frame of machine code: a tag and the bits of an `i64`, an
+/// `f64` or a bool.
+#[repr(C)]
+#[derive(Default, Debug, Clone, Copy)]
+pub struct Slot {
+    tag: u64,
+    bits: u64,
+}
+
+const INT: u64 = 0;
+const FLOAT: u64 = 1;
+const BOOL: u64 = 2;
+
+impl Slot {
+    /// Only numbers and booleans can enter machine code.
+    fn pack(value: Value) -> Option<Self> {
+        match value {
+            Value::Int(i) => Some(Slot { tag: INT, bits: i as u64 }),
+            Value::Float(f) => Some(Slot { tag: FLOAT, bits: f.to_bits() }),
+            Value::Bool(b) => Some(Slot { tag: BOOL, bits: b as u64 }),
+            _ => None,
+        }
+    }
+
+    fn unpack(self) -> Value {
+        match self.tag {
+            INT => Value::Int(self.bits as i64),
+            FLOAT => Value::Float(f64::from_bits(self.bits)),
+            _ => Value::Bool(self.bits != 0),
+        }
+    }
+}
+
+/// Returned by machine code that ran to `ret`, the result is in slot 0.
+/// Any other value is the offset of the instruction to continue
+/// interpreting at.
+const RETURNED: u64 = u64::MAX;
+
+type Entry = unsafe extern "sysv64" fn(*mut Slot) -> u64;
+
+const PROT_READ: i32 = 1;
+const PROT_WRITE: i32 = 2;
+const PROT_EXEC: i32 = 4;
+const MAP_PRIVATE: i32 = 2;
+const MAP_ANONYMOUS: i32 = 0x20;
+
+extern "C" {
+    fn mmap(addr: *mut c_void, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut c_void;
+    fn mprotect(addr: *mut c_void, len: usize, prot: i32) -> i32;
+    fn munmap(addr: *mut c_void, len: usize) -> i32;
+}
+
+/// Machine code in memory mapped readable and executable, never writable
+/// at the same time.
+struct ExecutableCode {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+impl ExecutableCode {
+    fn new(code: &[u8]) -> Option<Self> {
+        let len = code.len().max(1);
+        // SAFETY: a fresh private mapping, written before it becomes executable
+        unsafe {
+            let ptr = mmap(std::ptr::null_mut(), len, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+            if ptr as isize == -1 {
+                return None;
+            }
+            std::ptr::copy_nonoverlapping(code.as_ptr(), ptr as *mut u8, code.len());
+            if mprotect(ptr, len, PROT_READ | PROT_EXEC) != 0 {
+                munmap(ptr, len);
+                return None;
+            }
+            Some(ExecutableCode { ptr, len })
+        }
+    }
+}
+
+impl Drop for ExecutableCode {
+    fn drop(&mut self) {
+        // SAFETY: mapped in `new` and not used after this
+        unsafe {
+            munmap(self.ptr, self.len);
+        }
+    }
+}
+
+/// A function compiled to machine code.
+pub struct JitFunction {
+    code: ExecutableCode,
+    /// Slots the machine code uses, arguments included.
+    frame_size: usize,
+    /// Stack depth before the instruction at each offset, to rebuild the
+    /// interpreter's frame on deoptimization.
+    depths: Vec<Option<usize>>,
+}
+
+impl JitFunction {
+    /// Runs the machine code on `args`, returns `RETURNED` or the offset to
+    /// continue at with the frame left in `frame`.
+    fn run(&self, args: &[Slot], frame: &mut Vec<Slot>) -> u64 {
+        frame.clear();
+        frame.extend_from_slice(args);
+        frame.resize(self.frame_size, Slot::default());
+        // SAFETY: the code only touches the `frame_size` slots of `frame`
+        unsafe {
+            let entry: Entry = std::mem::transmute(self.code.ptr);
+            entry(frame.as_mut_ptr())
+        }
+    }
+}
+
+pub enum JitState {
+    /// Number of calls so far.
+    Counting(u32),
+    Compiled { function: Rc<JitFunction>, deopts: u32 },
+    /// The function uses instructions the compiler does not support, or
+    /// deoptimizes too often.
+    Interpreted,
+}
+
+/// Machine code of the functions of a VM, and when to make it.
+#[derive(Default)]
+pub struct Jit {
+    pub functions: Vec<JitState>,
+    /// Scratch frame machine code runs in.
+    frame: Vec<Slot>,
+}
+
+impl fmt::Debug for Jit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let compiled = self.functions.iter().filter(|state| matches!(state, JitState::Compiled { .. })).count();
+        write!(f, "Jit({} compiled)", compiled)
+    }
+}
+
+impl VirtualMachine {
+    /// Counts a call of `func_id` with arguments from `slot` on, and runs its
+    /// machine code when there is some. Returns whether the call was taken
+    /// care of: the result replaces the function on the stack, or a frame
+    /// was pushed to finish the call in the interpreter after a guard failed.
+    pub fn call_compiled(&mut self, func_id: usize, slot: usize) -> bool {
+        if self.debug || self.instruction_limit.is_some() || self.deadline.is_some() {
+            return false;
+        }
+        if self.jit.functions.len() != self.functions.len() {
+            self.jit.functions.resize_with(self.functions.len(), || JitState::Counting(0));
+        }
+        let function = match &mut self.jit.functions[func_id] {
+            JitState::Counting(count) if *count + 1 < JIT_THRESHOLD => {
+                *count += 1;
+                return false;
+            },
+            JitState::Counting(_) => {
+                self.jit.functions[func_id] = match compile(func_id, &self.functions, &self.obj_list, &self.native_functions) {
+                    Some(function) => JitState::Compiled { function: Rc::new(function), deopts: 0 },
+                    None => JitState::Interpreted,
+                };
+                return false;
+            },
+            JitState::Compiled { function, .. } => Rc::clone(function),
+            JitState::Interpreted => return false,
+        };
+        if self.stack.len() - slot != self.functions[func_id].arity as usize {
+            return false;
+        }
+        let Some(args) = self.stack[slot..].iter().map(|arg| Slot::pack(arg.unpack())).collect::<Option<Vec<_>>>() else {
+            return false;
+        };
+        let status = function.run(&args, &mut self.jit.frame);
+        if status == RETURNED {
+            let result = self.jit.frame[0].unpack();
+            self.stack.truncate(slot - 1);
+            self.push(StackElem::pack(result));
+            return true;
+        }
+        // continue in the interpreter where the guard failed
+        let ip = status as usize;
+        let depth = function.depths[ip].expect("deoptimized at an unreachable instruction");
+        self.stack.truncate(slot);
+        for value in &self.jit.frame[..depth] {
+            self.stack.push(StackElem::pack(value.unpack()));
+        }
+        self.frames.push(CallFrame { func_id, ip, slot_index: slot, tail_calls: 0 });
+        if let JitState::Compiled { deopts, .. } = &mut self.jit.functions[func_id] {
+            *deopts += 1;
+            if *deopts >= MAX_DEOPTS {
+                self.jit.functions[func_id] = JitState::Interpreted;
+            }
+        }
+        true
+    }
+}
+
+/// Compiles function `func_id` with a template per instruction, or `None` if
+/// it uses something other than numbers, booleans, locals and jumps. Every
+/// stack slot lives in memory at a place fixed by the verifier's stack depths.
+pub fn compile(func_id: usize, functions: &[Function], obj_list: &Heap, natives: &Native) -> Option<JitFunction> {
+    let func = &functions[func_id];
+    let code = verifier::stack_depths(func_id, functions, obj_list, natives).ok()?;
+    let chunk = &func.chunk;
+    let mut depths = vec![None; chunk.code.len() + 1];
+    for &(offset, _, depth) in &code {
+        depths[offset] = depth;
+    }
+    let mut compiler = Compiler {
+        asm: Asm::default(),
+        targets: HashMap::new(),
+        deopts: HashMap::new(),
+    };
+    for &(offset, ins, depth) in &code {
+        let Some(depth) = depth else {
+            continue
+        };
+        if let Some(target) = ins.jump_target() {
+            // the interpreter ends the program there
+            depths[target]?;
+        }
+        let label = compiler.target(offset);
+        compiler.asm.bind(label);
+        let constant = |c: usize| Slot::pack(chunk.constants[c]).map(Operand::Const);
+        compiler.instruction(ins, offset, depth, &constant)?;
+    }
+    let mut deopts: Vec<(usize, Label)> = compiler.deopts.into_iter().collect();
+    deopts.sort_by_key(|(offset, _)| *offset);
+    for (offset, label) in deopts {
+        if !compiler.asm.is_used(label) {
+            continue;
+        }
+        compiler.asm.bind(label);
+        compiler.asm.mov_imm(RAX, offset as u64);
+        compiler.asm.ret();
+    }
+    let frame_size = depths.iter().flatten().max().copied().unwrap_or(0).max(func.arity as usize) + 1;
+    let code = ExecutableCode::new(&compiler.asm.finish())?;
+    Some(JitFunction { code, frame_size, depths })
+}
+
+/// Where an operand comes from.
+#[derive(Clone, Copy)]
+enum Operand {
+    Slot(usize),
+    Const(Slot),
+}
+
+struct Compiler {
+    asm: Asm,
+    /// Label of the code of each bytecode offset.
+    targets: HashMap<usize, Label>,
+    /// Label of the stub that leaves at each bytecode offset.
+    deopts: HashMap<usize, Label>,
+}
+
+const RAX: u8 = 0;
+const RCX: u8 = 1;
+const RDX: u8 = 2;
+const RDI: u8 = 7;
+const R8: u8 = 8;
+const R9: u8 = 9;
+const XMM0: u8 = 0;
+const XMM1: u8 = 1;
+
+// condition codes
+const CC_O: u8 = 0x0;
+const CC_B: u8 = 0x2;
+const CC_AE: u8 = 0x3;
+const CC_E: u8 = 0x4;
+const CC_NE: u8 = 0x5;
+const CC_BE: u8 = 0x6;
+const CC_A: u8 = 0x7;
+const CC_P: u8 = 0xa;
+const CC_L: u8 = 0xc;
+const CC_GE: u8 = 0xd;
+const CC_LE: u8 = 0xe;
+const CC_G: u8 = 0xf;
+
+fn tag(slot: usize) -> i32 {
+    (slot * 16) as i32
+}
+
+fn bits(slot: usize) -> i32 {
+    (slot * 16 + 8) as i32
+}
+
+impl Compiler {
+    fn target(&mut self, offset: usize) -> Label {
+        *self.targets.entry(offset).or_insert_with(|| self.asm.label())
+    }
+
+    fn deopt(&mut self, offset: usize) -> Label {
+        *self.deopts.entry(offset).or_insert_with(|| self.asm.label())
+    }
+
+    /// Tag to `tag_reg` and bits to `bits_reg`.
+    fn load(&mut self, operand: Operand, tag_reg: u8, bits_reg: u8) {
+        match operand {
+            Operand::Slot(slot) => {
+                self.asm.load(tag_reg, tag(slot));
+                self.asm.load(bits_reg, bits(slot));
+            },
+            Operand::Const(value) => {
+                self.asm.mov_imm(tag_reg, value.tag);
+                self.asm.mov_imm(bits_reg, value.bits);
+            },
+        }
+    }
+
+    fn store(&mut self, slot: usize, tag_value: u64, bits_reg: u8) {
+        self.asm.store_imm(tag(slot), tag_value as i32);
+        self.asm.store(bits(slot), bits_reg);
+    }
+
+    fn copy(&mut self, from: usize, to: usize) {
+        self.asm.load(RAX, tag(from));
+        self.asm.load(RCX, bits(from));
+        self.asm.store(tag(to), RAX);
+        self.asm.store(bits(to), RCX);
+    }
+
+    /// Leaves unless the value with tag in `tag_reg` has tag `expected`.
+    fn guard(&mut self, tag_reg: u8, expected: u64, deopt: Label) {
+        self.asm.cmp_imm(tag_reg, expected as i8);
+        self.asm.jcc(CC_NE, deopt);
+    }
+
+    /// Leaves unless `reg` holds a 48-bit integer, the integers of a VM
+    /// built with `nan_boxing`.
+    fn guard_width(&mut self, reg: u8, deopt: Label) {
+        if cfg!(feature = "nan_boxing") {
+            self.asm.mov_rr(RDX, reg);
+            self.asm.shift_imm(4, RDX, 16);
+            self.asm.shift_imm(7, RDX, 16);
+            self.asm.alu(0x39, RDX, reg);
+            self.asm.jcc(CC_NE, deopt);
+        }
+    }
+
+    /// Converts the number with tag `tag_reg` and bits `bits_reg` to a
+    /// double in `xmm`.
+    fn load_double(&mut self, tag_reg: u8, bits_reg: u8, xmm: u8, deopt: Label) {
+        let (float, done) = (self.asm.label(), self.asm.label());
+        self.asm.cmp_imm(tag_reg, INT as i8);
+        self.asm.jcc(CC_NE, float);
+        self.asm.cvtsi2sd(xmm, bits_reg);
+        self.asm.jmp(done);
+        self.asm.bind(float);
+        self.guard(tag_reg, FLOAT, deopt);
+        self.asm.movq_to_xmm(xmm, bits_reg);
+        self.asm.bind(done);
+    }
+
+    /// `dst = lhs op rhs` for the arithmetic operators. Anything the VM
+    /// would not compute the same way, like an overflow, deoptimizes.
+    fn arithmetic(&mut self, op: ByteCode, lhs: Operand, rhs: Operand, dst: usize, deopt: Label) {
+        self.load(lhs, R8, RAX);
+        self.load(rhs, R9, RCX);
+        let (doubles, done) = (self.asm.label(), self.asm.label());
+        let int_only = matches!(op, ByteCode::Mod | ByteCode::Shl | ByteCode::Shr |
+                                    ByteCode::LAnd | ByteCode::LOr | ByteCode::LXor);
+        if op != ByteCode::Div {
+            let not_int = if int_only { deopt } else { doubles };
+            self.asm.cmp_imm(R8, INT as i8);
+            self.asm.jcc(CC_NE, not_int);
+            self.asm.cmp_imm(R9, INT as i8);
+            self.asm.jcc(CC_NE, not_int);
+            match op {
+                ByteCode::Add | ByteCode::Sub | ByteCode::Mul => {
+                    match op {
+                        ByteCode::Add => self.asm.alu(0x01, RAX, RCX),
+                        ByteCode::Sub => self.asm.alu(0x29, RAX, RCX),
+                        _ => self.asm.imul(RAX, RCX),
+                    }
+                    self.asm.jcc(CC_O, deopt);
+                },
+                ByteCode::Mod => {
+                    // division by zero and `i64::MIN % -1` panic in the VM
+                    self.asm.alu(0x85, RCX, RCX);
+                    self.asm.jcc(CC_E, deopt);
+                    self.asm.cmp_imm(RCX, -1);
+                    self.asm.jcc(CC_E, deopt);
+                    self.asm.cqo();
+                    self.asm.unary(7, RCX);
+                    self.asm.mov_rr(RAX, RDX);
+                },
+                ByteCode::Shl | ByteCode::Shr => {
+                    // shifting by 64 or more, or by a negative amount, panics
+                    // in the VM built in debug mode
+                    self.asm.cmp_imm(RCX, 63);
+                    self.asm.jcc(CC_A, deopt);
+                    self.asm.shift_cl(if op == ByteCode::Shl { 4 } else { 7 }, RAX);
+                },
+                ByteCode::LAnd => self.asm.alu(0x21, RAX, RCX),
+                ByteCode::LOr => self.asm.alu(0x09, RAX, RCX),
+                _ => self.asm.alu(0x31, RAX, RCX),
+            }
+            self.guard_width(RAX, deopt);
+            self.store(dst, INT, RAX);
+            self.asm.jmp(done);
+        }
+        if !int_only {
+            self.asm.bind(doubles);
+            self.load_double(R8, RAX, XMM0, deopt);
+            self.load_double(R9, RCX, XMM1, deopt);
+            let opcode = match op {
+                ByteCode::Add => 0x58,
+                ByteCode::Sub => 0x5c,
+                ByteCode::Mul => 0x59,
+                _ => 0x5e,
+            };
+            self.asm.sse(0xf2, opcode, XMM0, XMM1);
+            self.asm.movq_from_xmm(RAX, XMM0);
+            self.store(dst, FLOAT, RAX);
+        }
+        self.asm.bind(done);
+    }
+
+    /// `al = lhs op rhs` for the comparison operators.
+    fn compare(&mut self, op: ByteCode, lhs: Operand, rhs: Operand, deopt: Label) {
+        self.load(lhs, R8, RAX);
+        self.load(rhs, R9, RCX);
+        let (doubles, done) = (self.asm.label(), self.asm.label());
+        self.asm.cmp_imm(R8, INT as i8);
+        self.asm.jcc(CC_NE, doubles);
+        self.asm.cmp_imm(R9, INT as i8);
+        self.asm.jcc(CC_NE, doubles);
+        self.asm.alu(0x39, RAX, RCX);
+        self.asm.setcc(match op {
+            ByteCode::Eq => CC_E,
+            ByteCode::Ne => CC_NE,
+            ByteCode::Lt => CC_L,
+            ByteCode::Le => CC_LE,
+            ByteCode::Gt => CC_G,
+            _ => CC_GE,
+        });
+        self.asm.jmp(done);
+        self.asm.bind(doubles);
+        if let ByteCode::Eq | ByteCode::Ne = op {
+            // an integer is never equal to a double, leave that to the VM
+            self.guard(R8, FLOAT, deopt);
+            self.guard(R9, FLOAT, deopt);
+        }
+        self.load_double(R8, RAX, XMM0, deopt);
+        self.load_double(R9, RCX, XMM1, deopt);
+        self.asm.sse(0x66, 0x2e, XMM0, XMM1);
+        // `partial_cmp` of the VM orders NaN below everything
+        let cc = match op {
+            ByteCode::Eq | ByteCode::Ne => {
+                self.asm.jcc(CC_P, deopt);
+                if op == ByteCode::Eq { CC_E } else { CC_NE }
+            },
+            ByteCode::Lt => CC_B,
+            ByteCode::Le => CC_BE,
+            ByteCode::Gt => CC_A,
+            _ => CC_AE,
+        };
+        self.asm.setcc(cc);
+        self.asm.bind(done);
+    }
+
+    /// Emits one instruction that runs with `depth` values on the stack.
+    fn instruction(&mut self, ins: ByteCode, offset: usize, depth: usize,
+                   constant: &dyn Fn(usize) -> Option<Operand>) -> Option<()> {
+        let top = depth.wrapping_sub(1);
+        let deopt = self.deopt(offset);
+        match ins {
+            ByteCode::Add | ByteCode::Sub | ByteCode::Mul | ByteCode::Div | ByteCode::Mod |
+            ByteCode::Shl | ByteCode::Shr | ByteCode::LAnd | ByteCode::LOr | ByteCode::LXor =>
+                self.arithmetic(ins, Operand::Slot(top - 1), Operand::Slot(top), top - 1, deopt),
+            ByteCode::Eq | ByteCode::Ne | ByteCode::Lt | ByteCode::Le | ByteCode::Gt | ByteCode::Ge => {
+                self.compare(ins, Operand::Slot(top - 1), Operand::Slot(top), deopt);
+                self.asm.movzx_al(RAX);
+                self.store(top - 1, BOOL, RAX);
+            },
+            ByteCode::And | ByteCode::Or => {
+                self.load(Operand::Slot(top - 1), R8, RAX);
+                self.load(Operand::Slot(top), R9, RCX);
+                self.guard(R8, BOOL, deopt);
+                self.guard(R9, BOOL, deopt);
+                self.asm.alu(if ins == ByteCode::And { 0x21 } else { 0x09 }, RAX, RCX);
+                self.asm.store(bits(top - 1), RAX);
+            },
+            ByteCode::Not => {
+                self.load(Operand::Slot(top), R8, RAX);
+                self.guard(R8, BOOL, deopt);
+                self.asm.xor_imm(RAX, 1);
+                self.asm.store(bits(top), RAX);
+            },
+            ByteCode::LNot => {
+                self.load(Operand::Slot(top), R8, RAX);
+                self.guard(R8, INT, deopt);
+                self.asm.unary(2, RAX);
+                self.asm.store(bits(top), RAX);
+            },
+            ByteCode::Neg => {
+                let float = self.asm.label();
+                let done = self.asm.label();
+                self.load(Operand::Slot(top), R8, RAX);
+                self.asm.cmp_imm(R8, INT as i8);
+                self.asm.jcc(CC_NE, float);
+                self.asm.unary(3, RAX);
+                self.asm.jcc(CC_O, deopt);
+                self.guard_width(RAX, deopt);
+                self.asm.store(bits(top), RAX);
+                self.asm.jmp(done);
+                self.asm.bind(float);
+                self.guard(R8, FLOAT, deopt);
+                self.asm.mov_imm(RCX, 1 << 63);
+                self.asm.alu(0x31, RAX, RCX);
+                self.asm.store(bits(top), RAX);
+                self.asm.bind(done);
+            },
+            ByteCode::Value(c) => {
+                let Operand::Const(value) = constant(c)? else {
+                    unreachable!()
+                };
+                self.asm.store_imm(tag(depth), value.tag as i32);
+                self.asm.mov_imm(RAX, value.bits);
+                self.asm.store(bits(depth), RAX);
+            },
+            ByteCode::Pop | ByteCode::PopN(_) | ByteCode::Nop => (),
+            ByteCode::J(target) => {
+                let label = self.target(target);
+                self.asm.jmp(label);
+            },
+            ByteCode::Jz(target) | ByteCode::Jnz(target) | ByteCode::JzKeep(target) | ByteCode::JnzKeep(target) => {
+                let label = self.target(target);
+                self.load(Operand::Slot(top), R8, RAX);
+                self.guard(R8, BOOL, deopt);
+                self.asm.alu(0x85, RAX, RAX);
+                let jump_if_true = matches!(ins, ByteCode::Jnz(_) | ByteCode::JnzKeep(_));
+                self.asm.jcc(if jump_if_true { CC_NE } else { CC_E }, label);
+            },
+            ByteCode::LoadLocal(l) => self.copy(l, depth),
+            ByteCode::SetLocal(l) => self.copy(top, l),
+            ByteCode::Ret => {
+                self.copy(top, 0);
+                self.asm.mov_imm(RAX, RETURNED);
+                self.asm.ret();
+            },
+            ByteCode::AddLocalConst(l, c) => {
+                let rhs = constant(c)?;
+                self.arithmetic(ByteCode::Add, Operand::Slot(l), rhs, depth, deopt);
+            },
+            ByteCode::AddConstSetLocal(c, l) => {
+                let rhs = constant(c)?;
+                self.arithmetic(ByteCode::Add, Operand::Slot(top), rhs, l, deopt);
+            },
+            ByteCode::LtLocalsJz(a, b, target) => {
+                let label = self.target(target);
+                self.compare(ByteCode::Lt, Operand::Slot(a), Operand::Slot(b), deopt);
+                self.asm.test_al();
+                self.asm.jcc(CC_E, label);
+            },
+            _ => return None,
+        }
+        Some(())
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Label(usize);
+
+/// Encodes the few x86-64 instructions the templates use. Memory operands
+/// are always `[rdi + disp32]`, the frame's slots.
+#[derive(Default)]
+struct Asm {
+    code: Vec<u8>,
+    labels: Vec<Option<usize>>,
+    /// `(position of a rel32, label it points to)`
+    fixups: Vec<(usize, Label)>,
+}
+
+impl Asm {
+    fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    fn bind(&mut self, label: Label) {
+        self.labels[label.0] = Some(self.code.len());
+    }
+
+    fn is_used(&self, label: Label) -> bool {
+        self.fixups.iter().any(|(_, used)| used.0 == label.0)
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        for (pos, label) in std::mem::take(&mut self.fixups) {
+            let target = self.labels[label.0].expect("unbound label") as i64;
+            let rel = (target - (pos as i64 + 4)) as i32;
+            self.code[pos..pos + 4].copy_from_slice(&rel.to_le_bytes());
+        }
+        self.code
+    }
+
+    fn rex(&mut self, reg: u8, rm: u8) {
+        self.code.push(0x48 | ((reg >> 3) << 2) | (rm >> 3));
+    }
+
+    fn modrm_reg(&mut self, reg: u8, rm: u8) {
+        self.code.push(0xc0 | ((reg & 7) << 3) | (rm & 7));
+    }
+
+    fn modrm_frame(&mut self, reg: u8, disp: i32) {
+        self.code.push(0x80 | ((reg & 7) << 3) | RDI);
+        self.code.extend_from_slice(&disp.to_le_bytes());
+    }
+
+    fn rel32(&mut self, label: Label) {
+        self.fixups.push((self.code.len(), label));
+        self.code.extend_from_slice(&[0; 4]);
+    }
+
+    /// `mov reg, [rdi + disp]`
+    fn load(&mut self, reg: u8, disp: i32) {
+        self.rex(reg, RDI);
+        self.code.push(0x8b);
+        self.modrm_frame(reg, disp);
+    }
+
+    /// `mov [rdi + disp], reg`
+    fn store(&mut self, disp: i32, reg: u8) {
+        self.rex(reg, RDI);
+        self.code.push(0x89);
+        self.modrm_frame(reg, disp);
+    }
+
+    /// `mov qword [rdi + disp], imm`
+    fn store_imm(&mut self, disp: i32, imm: i32) {
+        self.rex(0, RDI);
+        self.code.push(0xc7);
+        self.modrm_frame(0, disp);
+        self.code.extend_from_slice(&imm.to_le_bytes());
+    }
+
+    fn mov_imm(&mut self, reg: u8, imm: u64) {
+        self.rex(0, reg);
+        self.code.push(0xb8 | (reg & 7));
+        self.code.extend_from_slice(&imm.to_le_bytes());
+    }
+
+    fn mov_rr(&mut self, dst: u8, src: u8) {
+        self.alu(0x89, dst, src);
+    }
+
+    /// `op dst, src` for the `r/m64, r64` forms: add, or, and, sub, xor,
+    /// cmp, test and mov.
+    fn alu(&mut self, op: u8, dst: u8, src: u8) {
+        self.rex(src, dst);
+        self.code.push(op);
+        self.modrm_reg(src, dst);
+    }
+
+    fn cmp_imm(&mut self, reg: u8, imm: i8) {
+        self.rex(0, reg);
+        self.code.push(0x83);
+        self.modrm_reg(7, reg);
+        self.code.push(imm as u8);
+    }
+
+    fn xor_imm(&mut self, reg: u8, imm: i8) {
+        self.rex(0, reg);
+        self.code.push(0x83);
+        self.modrm_reg(6, reg);
+        self.code.push(imm as u8);
+    }
+
+    fn imul(&mut self, dst: u8, src: u8) {
+        self.rex(dst, src);
+        self.code.extend_from_slice(&[0x0f, 0xaf]);
+        self.modrm_reg(dst, src);
+    }
+
+    /// `not`, `neg` or `idiv` for `ext` 2, 3 and 7.
+    fn unary(&mut self, ext: u8, reg: u8) {
+        self.rex(0, reg);
+        self.code.push(0xf7);
+        self.modrm_reg(ext, reg);
+    }
+
+    /// `shl` or `sar` by `cl` for `ext` 4 and 7.
+    fn shift_cl(&mut self, ext: u8, reg: u8) {
+        self.rex(0, reg);
+        self.code.push(0xd3);
+        self.modrm_reg(ext, reg);
+    }
+
+    fn shift_imm(&mut self, ext: u8, reg: u8, imm: u8) {
+        self.rex(0, reg);
+        self.code.push(0xc1);
+        self.modrm_reg(ext, reg);
+        self.code.push(imm);
+    }
+
+    fn cqo(&mut self) {
+        self.code.extend_from_slice(&[0x48, 0x99]);
+    }
+
+    fn setcc(&mut self, cc: u8) {
+        self.code.extend_from_slice(&[0x0f, 0x90 | cc, 0xc0]);
+    }
+
+    /// `movzx reg, al`
+    fn movzx_al(&mut self, reg: u8) {
+        self.rex(reg, RAX);
+        self.code.extend_from_slice(&[0x0f, 0xb6]);
+        self.modrm_reg(reg, RAX);
+    }
+
+    /// `test al, al`
+    fn test_al(&mut self) {
+        self.code.extend_from_slice(&[0x84, 0xc0]);
+    }
+
+    fn jcc(&mut self, cc: u8, label: Label) {
+        self.code.extend_from_slice(&[0x0f, 0x80 | cc]);
+        self.rel32(label);
+    }
+
+    fn jmp(&mut self, label: Label) {
+        self.code.push(0xe9);
+        self.rel32(label);
+    }
+
+    fn ret(&mut self) {
+        self.code.push(0xc3);
+    }
+
+    /// `cvtsi2sd xmm, reg`
+    fn cvtsi2sd(&mut self, xmm: u8, reg: u8) {
+        self.code.push(0xf2);
+        self.rex(xmm, reg);
+        self.code.extend_from_slice(&[0x0f, 0x2a]);
+        self.modrm_reg(xmm, reg);
+    }
+
+    /// `movq xmm, reg`
+    fn movq_to_xmm(&mut self, xmm: u8, reg: u8) {
+        self.code.push(0x66);
+        self.rex(xmm, reg);
+        self.code.extend_from_slice(&[0x0f, 0x6e]);
+        self.modrm_reg(xmm, reg);
+    }
+
+    /// `movq reg, xmm`
+    fn movq_from_xmm(&mut self, reg: u8, xmm: u8) {
+        self.code.push(0x66);
+        self.rex(xmm, reg);
+        self.code.extend_from_slice(&[0x0f, 0x7e]);
+        self.modrm_reg(xmm, reg);
+    }
+
+    /// A scalar double instruction on two of the low xmm registers, e.g.
+    /// `addsd` with prefix `f2` or `ucomisd` with prefix `66`.
+    fn sse(&mut self, prefix: u8, op: u8, dst: u8, src: u8) {
+        self.code.extend_from_slice(&[prefix, 0x0f, op]);
+        self.modrm_reg(dst, src);
+    }
+}
@@ -0,0 +1,86 @@
+use std::fmt;
+
+
+/// A JSON document, just enough to dump compiler output.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Str(String),
+    Array(Vec<Json>),
+    /// Members in the order they are written.
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn str(s: &str) -> Json {
+        Json::Str(String::from(s))
+    }
+
+    pub fn object(members: Vec<(&str, Json)>) -> Json {
+        Json::Object(members.into_iter().map(|(key, value)| (String::from(key), value)).collect())
+    }
+
+    fn is_scalar(&self) -> bool {
+        !matches!(self, Json::Array(_) | Json::Object(_))
+    }
+
+    /// Writes arrays and objects that only hold scalars on one line, and
+    /// everything else one member per line.
+    fn write(&self, f: &mut fmt::Formatter<'_>, indent: usize) -> fmt::Result {
+        let (open, close, items): (&str, &str, Vec<(Option<&String>, &Json)>) = match self {
+            Json::Null => return write!(f, "null"),
+            Json::Bool(b) => return write!(f, "{}", b),
+            Json::Int(i) => return write!(f, "{}", i),
+            Json::Str(s) => return write_string(f, s),
+            Json::Array(items) => ("[", "]", items.iter().map(|item| (None, item)).collect()),
+            Json::Object(members) => ("{", "}", members.iter().map(|(key, value)| (Some(key), value)).collect()),
+        };
+        let inline = items.iter().all(|(_, item)| item.is_scalar());
+        write!(f, "{}", open)?;
+        for (i, (key, item)) in items.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            if inline {
+                if i > 0 {
+                    write!(f, " ")?;
+                }
+            } else {
+                write!(f, "\n{:width$}", "", width = indent + 2)?;
+            }
+            if let Some(key) = key {
+                write_string(f, key)?;
+                write!(f, ": ")?;
+            }
+            item.write(f, indent + 2)?;
+        }
+        if !inline && !items.is_empty() {
+            write!(f, "\n{:width$}", "", width = indent)?;
+        }
+        write!(f, "{}", close)
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, 0)
+    }
+}
@@ -1,24 +1,181 @@
 mod bytecode;
 mod virtual_machine;
 mod scanner;
+mod ast;
 mod parser;
+mod codegen;
 mod precidence;
 mod value;
 mod object;
 mod native_functions;
 mod helper;
+mod heap;
+mod optimizer;
+mod cfg;
+mod register;
+mod closure;
+mod ngrams;
+mod verifier;
+mod module;
+mod assembler;
+mod json;
+mod source_map;
+mod c_backend;
+mod wat;
+#[cfg(feature = "nan_boxing")]
+mod nan_boxing;
+#[cfg(feature = "jit")]
+mod jit;
 
 use bytecode::*;
 use virtual_machine::*;
 use scanner::*;
 use parser::*;
+use codegen::*;
 use value::*;
 use object::*;
 use std::time::{Duration,Instant};
 
 use crate::native_functions::Native;
 
+/// Scans, parses and compiles a script.
+fn compile(path: &str, opt_level: u8) -> CodeGen {
+    let mut scanner = Scanner::from_file(path).unwrap();
+    let mut parser = Parser::from_tokens(scanner.scan());
+    let program = parser.parse();
+    let mut codegen = CodeGen::new(Native::new());
+    codegen.opt_level = opt_level;
+    codegen.file = String::from(path);
+    codegen.compile(&program);
+    codegen
+}
+
+/// `ngrams <n> <script>...`: the most frequent opcode sequences of the
+/// scripts, compiled without superinstructions.
+fn print_ngrams(args: &[String]) {
+    let n = args.first().and_then(|n| n.parse().ok()).unwrap_or(2);
+    let compiled: Vec<CodeGen> = args.iter().skip(1).map(|path| compile(path, 1)).collect();
+    let chunks: Vec<&Chunk> = compiled.iter()
+        .flat_map(|codegen| codegen.functions.iter().map(|func| &func.chunk))
+        .collect();
+    for (ops, count) in ngrams::count(&chunks, n).iter().take(20) {
+        let ops: Vec<String> = ops.iter().map(|op| format!("{:?}", op)).collect();
+        println!("{}\t{}", count, ops.join(" "));
+    }
+}
+
+/// `run <script>`: runs a script without any of the debug output.
+fn run_script(path: &str, args: &[String]) {
+    let codegen = compile(path, opt_level(args));
+    for warning in &codegen.warnings {
+        eprintln!("{}", warning);
+    }
+    let mut vm = VirtualMachine::with_backend(&codegen, backend(args));
+    vm.debug = false;
+    if let Some(limit) = heap_limit(args) {
+        vm.obj_list.limit = Some(limit);
+    }
+    if let Err(err) = vm.interpret() {
+        println!("{}", err);
+    }
+}
+
+/// `run <module.dppc>`: runs a script compiled with `--emit dppc`, or
+/// assembly like `test_out.asm`.
+fn run_module(path: &str, args: &[String]) {
+    let loaded = if path.ends_with(".asm") {
+        assembler::load(path)
+    } else {
+        module::load(path)
+    };
+    let mut vm = match loaded {
+        Ok(vm) => vm,
+        Err(msg) => {
+            println!("{}", msg);
+            return;
+        },
+    };
+    vm.debug = false;
+    vm.backend = backend(args);
+    if let Some(limit) = heap_limit(args) {
+        vm.obj_list.limit = Some(limit);
+    }
+    if let Err(err) = vm.interpret() {
+        println!("{}", err);
+    }
+}
+
+fn backend(args: &[String]) -> Backend {
+    if args.iter().any(|arg| arg == "--register") {
+        Backend::Register
+    } else if args.iter().any(|arg| arg == "--closure") {
+        Backend::Closure
+    } else {
+        Backend::Stack
+    }
+}
+
+/// The level of `-O<n>`, `optimizer::DEFAULT_OPT_LEVEL` without one.
+fn opt_level(args: &[String]) -> u8 {
+    args.iter()
+        .find_map(|arg| arg.strip_prefix("-O")?.parse().ok())
+        .unwrap_or(optimizer::DEFAULT_OPT_LEVEL)
+}
+
+/// The byte count of `--heap-limit <bytes>`.
+fn heap_limit(args: &[String]) -> Option<usize> {
+    let pos = args.iter().position(|arg| arg == "--heap-limit")?;
+    args.get(pos + 1).and_then(|bytes| bytes.parse().ok())
+}
+
+/// `build <script> --emit c [-o <executable>]`: translates a script to C and
+/// builds a standalone executable with the system C compiler.
+fn build(args: &[String]) {
+    let Some(path) = args.first() else {
+        println!("Usage: build <script> --emit c [-o <executable>] [-O<level>]");
+        return;
+    };
+    if emit_format(args) != Some("c") {
+        println!("Only '--emit c' is supported by build");
+        return;
+    }
+    let output = args.iter().position(|arg| arg == "-o")
+        .and_then(|pos| args.get(pos + 1))
+        .cloned()
+        .unwrap_or_else(|| String::from(path.strip_suffix(".dpp").unwrap_or("a.out")));
+    let codegen = compile(path, opt_level(args));
+    for warning in &codegen.warnings {
+        eprintln!("{}", warning);
+    }
+    if let Err(msg) = c_backend::build(&codegen.functions, &codegen.obj_list, &output) {
+        println!("{}", msg);
+    }
+}
+
+/// The value of `--emit <format>`.
+fn emit_format(args: &[String]) -> Option<&str> {
+    let pos = args.iter().position(|arg| arg == "--emit")?;
+    args.get(pos + 1).map(|format| format.as_str())
+}
+
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).is_some_and(|arg| arg == "ngrams") {
+        print_ngrams(&args[2..]);
+        return;
+    }
+    if args.get(1).is_some_and(|arg| arg == "build") {
+        build(&args[2..]);
+        return;
+    }
+    if args.get(1).is_some_and(|arg| arg == "run") {
+        match args.get(2) {
+            Some(path) if path.ends_with(".dppc") || path.ends_with(".asm") => run_module(path, &args[3..]),
+            Some(path) => run_script(path, &args[3..]),
+            None => println!("Usage: run <script.dpp | module.dppc | program.asm> [-O<level>] [--register | --closure] [--heap-limit <bytes>]"),
+        }
+        return;
+    }
 
     let native_functions = Native::new();
     
@@ -38,23 +195,57 @@ fn main() {
         println!("{}\t{:>?}", line, token);
     }
 
-    let mut parser = Parser::from_tokens(token_list, native_functions);
-    parser.compile();
+    let mut parser = Parser::from_tokens(token_list);
+    let program = parser.parse();
+    let mut codegen = CodeGen::new(native_functions);
+    codegen.opt_level = opt_level(&args);
+    codegen.file = String::from("test.dpp");
+    codegen.compile(&program);
+    for warning in &codegen.warnings {
+        eprintln!("{}", warning);
+    }
     
-    // parser.get_chunk().write_file("test_out.asm");
+    // codegen.get_chunk().write_file("test_out.asm");
+    match emit_format(&args) {
+        Some("dppc") => if let Err(msg) = module::save(&codegen, "test_out.dppc") {
+            println!("{}", msg);
+        },
+        Some("dot") => {
+            let dot: String = codegen.functions.iter()
+                .map(|func| cfg::to_dot(func, &codegen.obj_list))
+                .collect();
+            std::fs::write("test_out.dot", dot).unwrap();
+        },
+        Some("c") => if let Err(msg) = c_backend::build(&codegen.functions, &codegen.obj_list, "test_out") {
+            println!("{}", msg);
+        },
+        Some("wat") => match wat::to_wat(&codegen.functions, &codegen.obj_list) {
+            Ok(wat) => std::fs::write("test_out.wat", wat).unwrap(),
+            Err(msg) => println!("{}", msg),
+        },
+        Some("json") => {
+            let json = assembler::to_json(&codegen.functions, &codegen.obj_list);
+            std::fs::write("test_out.json", json).unwrap();
+        },
+        _ => (),
+    }
 
     println!();
-    println!("{}", parser.get_chunk().disassemble());
-    let mut vm = VirtualMachine::from_parser(&parser);
+    println!("{}", codegen.get_chunk().disassemble());
+    let mut vm = VirtualMachine::with_backend(&codegen, backend(&args));
     vm.debug = false;
-    vm.constants = parser.constants.clone();
+    if let Some(limit) = heap_limit(&args) {
+        vm.obj_list.limit = Some(limit);
+    }
     vm.write_file_detail("test_out.asm");
     let now = Instant::now();
-    _ = vm.interpret();
+    if let Err(err) = vm.interpret() {
+        println!("{}", err);
+    }
     println!("{} ms", now.elapsed().as_nanos() as f64 / 1000. / 1000.);
     println!("{} s", now.elapsed().as_secs() as f64 / 1000. / 1000.);
     println!("\nConstants:");
-    for x in &vm.constants {
+    for x in vm.functions.iter().flat_map(|func| &func.chunk.constants) {
         if let Value::Obj(c) = x {
             println!("{}", vm.obj_list[*c].to_str());
         } else {
@@ -79,3 +270,13 @@ fn main() {
     }
 
 }
+
+/// Compiles a script kept in a string, for tests.
+#[cfg(test)]
+fn compile_source(source: &str) -> CodeGen {
+    let mut parser = Parser::from_tokens(Scanner::from_source(source).scan());
+    let program = parser.parse();
+    let mut codegen = CodeGen::new(Native::new());
+    codegen.compile(&program);
+    codegen
+}
@@ -0,0 +1,357 @@
+use std::fs;
+
+use crate::bytecode::Chunk;
+use crate::codegen::CodeGen;
+use crate::heap::Heap;
+use crate::native_functions::Native;
+use crate::object::{Function, Object};
+use crate::source_map::{Loc, SourceMap};
+use crate::value::Value;
+use crate::verifier;
+use crate::virtual_machine::VirtualMachine;
+
+
+/// A compiled script, `.dppc`:
+///
+/// ```text
+/// magic "DPPC" | version: u16 | checksum: u32 | payload
+/// payload = functions, objects
+/// ```
+///
+/// Integers are little endian, counts and indices are u32. The checksum is
+/// FNV-1a of the payload.
+pub const MAGIC: &[u8; 4] = b"DPPC";
+pub const VERSION: u16 = 3;
+const HEADER_LEN: usize = MAGIC.len() + 2 + 4;
+
+/// Writes the output of `codegen` to `path`.
+pub fn save(codegen: &CodeGen, path: &str) -> Result<(), String> {
+    let bytes = to_bytes(&codegen.functions, &codegen.obj_list);
+    fs::write(path, bytes).map_err(|err| format!("Cannot write '{}': {}", path, err))
+}
+
+/// Reads a module written by `save` into a VM ready to `interpret`.
+pub fn load(path: &str) -> Result<VirtualMachine, String> {
+    let bytes = fs::read(path).map_err(|err| format!("Cannot read '{}': {}", path, err))?;
+    let (functions, obj_list) = from_bytes(&bytes)?;
+    let natives = Native::new();
+    verifier::verify(&functions, &obj_list, &natives).map_err(|err| err.to_string())?;
+    Ok(VirtualMachine::from_parts(functions, obj_list, natives))
+}
+
+pub fn to_bytes(functions: &[Function], obj_list: &Heap) -> Vec<u8> {
+    let mut payload = Writer::default();
+    payload.u32(functions.len());
+    for func in functions {
+        payload.function(func);
+    }
+    payload.u32(obj_list.len());
+    for obj in obj_list.iter() {
+        payload.object(obj);
+    }
+
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.extend_from_slice(&checksum(&payload.bytes).to_le_bytes());
+    bytes.extend_from_slice(&payload.bytes);
+    bytes
+}
+
+pub fn from_bytes(bytes: &[u8]) -> Result<(Vec<Function>, Heap), String> {
+    if bytes.len() < HEADER_LEN || &bytes[..MAGIC.len()] != MAGIC {
+        return Err(String::from("Not a compiled module"));
+    }
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    if version != VERSION {
+        return Err(format!("Module version {} is not supported, expected {}", version, VERSION));
+    }
+    let expected = u32::from_le_bytes([bytes[6], bytes[7], bytes[8], bytes[9]]);
+    let payload = &bytes[HEADER_LEN..];
+    if checksum(payload) != expected {
+        return Err(String::from("Module checksum mismatch"));
+    }
+
+    let mut reader = Reader { bytes: payload, pos: 0 };
+    let functions = (0..reader.u32()?).map(|_| reader.function()).collect::<Result<Vec<_>, _>>()?;
+    let mut obj_list = Heap::new();
+    for _ in 0..reader.u32()? {
+        let obj = reader.object()?;
+        let size = match &obj {
+            Object::String(s) => Heap::string_size(s.len()),
+            Object::List(list) => Heap::list_size(list.len()),
+            _ => Heap::string_size(0),
+        };
+        obj_list.allocate(size)?;
+        obj_list.insert(obj);
+    }
+    if reader.pos != payload.len() {
+        return Err(String::from("Trailing bytes after the module"));
+    }
+    Ok((functions, obj_list))
+}
+
+/// 32-bit FNV-1a.
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5, |hash, &byte| (hash ^ byte as u32).wrapping_mul(0x01000193))
+}
+
+
+#[derive(Default)]
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn u32(&mut self, value: usize) {
+        let value = u32::try_from(value).expect("Module is too large");
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len());
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    fn value(&mut self, value: &Value) {
+        match *value {
+            Value::Nil => self.u8(0),
+            Value::Unk => self.u8(1),
+            Value::Bool(b) => { self.u8(2); self.u8(b as u8); },
+            Value::Int(i) => { self.u8(3); self.u64(i as u64); },
+            Value::Float(f) => { self.u8(4); self.u64(f.to_bits()); },
+            Value::Ptr(p) => { self.u8(5); self.u32(p); },
+            Value::StaticPtr(p) => { self.u8(6); self.u32(p); },
+            Value::Obj(p) => { self.u8(7); self.u32(p); },
+            Value::Function(p) => { self.u8(8); self.u32(p); },
+            Value::NativeFunction(p) => { self.u8(9); self.u32(p); },
+        }
+    }
+
+    fn values(&mut self, values: &[Value]) {
+        self.u32(values.len());
+        for value in values {
+            self.value(value);
+        }
+    }
+
+    fn chunk(&mut self, chunk: &Chunk) {
+        self.bytes(&chunk.code);
+        self.source_map(&chunk.source_map);
+        self.values(&chunk.constants);
+    }
+
+    /// The file name, then `(line, column, end column, number of bytes)` runs.
+    fn source_map(&mut self, source_map: &SourceMap) {
+        self.bytes(source_map.file.as_bytes());
+        self.u32(source_map.runs().count());
+        for (loc, count) in source_map.runs() {
+            self.u32(loc.line);
+            self.u32(loc.column);
+            self.u32(loc.end_column);
+            self.u32(count);
+        }
+    }
+
+    fn function(&mut self, func: &Function) {
+        self.bytes(func.name.as_bytes());
+        self.u64(func.arity as u64);
+        self.chunk(&func.chunk);
+    }
+
+    fn object(&mut self, obj: &Object) {
+        match obj {
+            Object::Obj => self.u8(0),
+            Object::Function(func) => { self.u8(1); self.function(func); },
+            Object::List(list) => { self.u8(2); self.values(list); },
+            Object::String(s) => { self.u8(3); self.bytes(s.as_bytes()); },
+        }
+    }
+}
+
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], String> {
+        let end = self.pos.checked_add(len).filter(|end| *end <= self.bytes.len())
+            .ok_or("Unexpected end of module")?;
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<usize, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, String> {
+        let len = self.u32()?;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        String::from_utf8(self.bytes()?).map_err(|_| String::from("Invalid UTF-8 in module"))
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        Ok(match self.u8()? {
+            0 => Value::Nil,
+            1 => Value::Unk,
+            2 => Value::Bool(self.u8()? != 0),
+            3 => Value::Int(self.u64()? as i64),
+            4 => Value::Float(f64::from_bits(self.u64()?)),
+            5 => Value::Ptr(self.u32()?),
+            6 => Value::StaticPtr(self.u32()?),
+            7 => Value::Obj(self.u32()?),
+            8 => Value::Function(self.u32()?),
+            9 => Value::NativeFunction(self.u32()?),
+            tag => return Err(format!("Unknown value tag {}", tag)),
+        })
+    }
+
+    fn values(&mut self) -> Result<Vec<Value>, String> {
+        (0..self.u32()?).map(|_| self.value()).collect()
+    }
+
+    fn chunk(&mut self) -> Result<Chunk, String> {
+        let code = self.bytes()?;
+        let source_map = self.source_map(code.len())?;
+        let constants = self.values()?;
+        Ok(Chunk::from_parts(code, source_map, constants))
+    }
+
+    /// A source map that must cover exactly `len` bytes.
+    fn source_map(&mut self, len: usize) -> Result<SourceMap, String> {
+        let mut source_map = SourceMap::default();
+        source_map.file = self.string()?;
+        for _ in 0..self.u32()? {
+            let loc = Loc::new(self.u32()?, self.u32()?, self.u32()?);
+            let count = self.u32()?;
+            if source_map.len() + count > len {
+                return Err(String::from("Source map does not match the code"));
+            }
+            source_map.push(loc, count);
+        }
+        if source_map.len() != len {
+            return Err(String::from("Source map does not match the code"));
+        }
+        Ok(source_map)
+    }
+
+    fn function(&mut self) -> Result<Function, String> {
+        let name = self.string()?;
+        let arity = self.u64()? as i64;
+        let chunk = self.chunk()?;
+        Ok(Function { arity, chunk, name })
+    }
+
+    fn object(&mut self) -> Result<Object, String> {
+        Ok(match self.u8()? {
+            0 => Object::Obj,
+            1 => Object::Function(self.function()?),
+            2 => Object::List(self.values()?),
+            3 => Object::String(self.string()?),
+            tag => return Err(format!("Unknown object tag {}", tag)),
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compiled() -> CodeGen {
+        crate::compile_source("func add(a, b):\n    return a + b\n\nlet s = add(40, 2)\nlet xs = list(3)\nlet t = \"text\"\n")
+    }
+
+    fn bytes() -> Vec<u8> {
+        to_bytes_of(&compiled())
+    }
+
+    fn to_bytes_of(codegen: &CodeGen) -> Vec<u8> {
+        to_bytes(&codegen.functions, &codegen.obj_list)
+    }
+
+    #[test]
+    fn round_trips_and_runs() {
+        let codegen = compiled();
+        let (functions, obj_list) = from_bytes(&to_bytes_of(&codegen)).unwrap();
+        assert_eq!(functions, codegen.functions);
+        assert!(obj_list.iter().eq(codegen.obj_list.iter()));
+
+        let natives = Native::new();
+        assert_eq!(verifier::verify(&functions, &obj_list, &natives), Ok(()));
+        let mut vm = VirtualMachine::from_parts(functions, obj_list, natives);
+        vm.debug = false;
+        assert!(vm.interpret().is_ok());
+        assert_eq!(vm.global.get("s"), Some(&Value::Int(42)));
+    }
+
+    #[test]
+    fn rejects_a_bad_magic() {
+        let mut bytes = bytes();
+        bytes[0] = b'X';
+        assert_eq!(from_bytes(&bytes).err(), Some(String::from("Not a compiled module")));
+    }
+
+    #[test]
+    fn rejects_another_version() {
+        let mut bytes = bytes();
+        bytes[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert_eq!(from_bytes(&bytes).err(), Some(format!("Module version {} is not supported, expected {}", VERSION + 1, VERSION)));
+    }
+
+    #[test]
+    fn rejects_a_corrupted_payload() {
+        let mut bytes = bytes();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert_eq!(from_bytes(&bytes).err(), Some(String::from("Module checksum mismatch")));
+    }
+
+    /// Cuts or extends the payload and fixes up the checksum, so only the
+    /// payload reader can notice.
+    fn with_payload_len(len: usize) -> Vec<u8> {
+        let mut bytes = bytes();
+        bytes.resize(HEADER_LEN + len, 0);
+        let sum = checksum(&bytes[HEADER_LEN..]);
+        bytes[6..10].copy_from_slice(&sum.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn rejects_a_truncated_module() {
+        let len = bytes().len() - HEADER_LEN;
+        assert_eq!(from_bytes(&with_payload_len(len - 1)).err(), Some(String::from("Unexpected end of module")));
+        assert_eq!(from_bytes(&bytes()[..HEADER_LEN - 1]).err(), Some(String::from("Not a compiled module")));
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        let len = bytes().len() - HEADER_LEN;
+        assert_eq!(from_bytes(&with_payload_len(len + 1)).err(), Some(String::from("Trailing bytes after the module")));
+    }
+}
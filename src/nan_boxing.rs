@@ -0,0 +1,316 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{LazyLock, Mutex};
+use std::ops::{Add, Sub, Mul, Div, Neg, Rem, Shr, Shl, BitAnd, BitOr, BitXor};
+
+use crate::value::Value;
+
+// A packed value is either a plain `f64`, or a negative quiet NaN carrying a
+// 3-bit type tag in bits 48..51 and a 48-bit payload. Float NaNs are stored as
+// the positive canonical NaN so they can never be mistaken for a boxed value.
+// Integers that do not fit in 48 bits are interned in `BIG_INTS` and boxed as
+// a `TAG_MISC` index, so both builds see the same 64-bit integers.
+const BOXED: u64 = 0xfff8_0000_0000_0000;
+const CANONICAL_NAN: u64 = 0x7ff8_0000_0000_0000;
+const TAG_SHIFT: u32 = 48;
+const TAG_MASK: u64 = 0x7 << TAG_SHIFT;
+const PAYLOAD_MASK: u64 = (1 << TAG_SHIFT) - 1;
+
+const TAG_MISC: u64 = 0; // payload 0 is `Nil`, 1 is `Unk`, the rest index `BIG_INTS`
+const TAG_BOOL: u64 = 1;
+const TAG_INT: u64 = 2;
+const TAG_OBJ: u64 = 3;
+const TAG_FUNCTION: u64 = 4;
+const TAG_NATIVE: u64 = 5;
+const TAG_PTR: u64 = 6;
+const TAG_STATIC_PTR: u64 = 7;
+
+const FIRST_BIG_INT: u64 = 2;
+
+#[derive(Default)]
+struct BigInts {
+    values: Vec<i64>,
+    index: HashMap<i64, u64>,
+}
+
+// Interned so that equal integers always box to equal bits. Like the heap it
+// is never collected.
+static BIG_INTS: LazyLock<Mutex<BigInts>> = LazyLock::new(Default::default);
+
+enum Number {
+    Int(i64),
+    Float(f64),
+}
+
+/// 64-bit representation of `Value` used for the VM stack when the
+/// `nan_boxing` feature is enabled.
+#[derive(Clone, Copy)]
+pub struct PackedValue(u64);
+
+impl PackedValue {
+    pub const NIL: PackedValue = PackedValue::boxed(TAG_MISC, 0);
+
+    const fn boxed(tag: u64, payload: u64) -> Self {
+        PackedValue(BOXED | (tag << TAG_SHIFT) | (payload & PAYLOAD_MASK))
+    }
+
+    fn from_int(n: i64) -> Self {
+        if (n << 16) >> 16 == n {
+            return Self::boxed(TAG_INT, n as u64);
+        }
+        let mut big_ints = BIG_INTS.lock().unwrap();
+        let big_ints = &mut *big_ints;
+        let id = *big_ints.index.entry(n).or_insert_with(|| {
+            big_ints.values.push(n);
+            big_ints.values.len() as u64 - 1
+        });
+        Self::boxed(TAG_MISC, FIRST_BIG_INT + id)
+    }
+
+    fn is_big_int(&self) -> bool {
+        !self.is_float() && self.tag() == TAG_MISC && self.payload() >= FIRST_BIG_INT
+    }
+
+    fn from_float(n: f64) -> Self {
+        if n.is_nan() { PackedValue(CANONICAL_NAN) } else { PackedValue(n.to_bits()) }
+    }
+
+    fn is_float(&self) -> bool {
+        self.0 & BOXED != BOXED
+    }
+
+    fn tag(&self) -> u64 {
+        (self.0 & TAG_MASK) >> TAG_SHIFT
+    }
+
+    fn payload(&self) -> u64 {
+        self.0 & PAYLOAD_MASK
+    }
+
+    fn number(&self) -> Option<Number> {
+        if self.is_float() {
+            Some(Number::Float(f64::from_bits(self.0)))
+        } else if self.tag() == TAG_INT {
+            // sign-extend the 48-bit payload
+            Some(Number::Int(((self.0 << 16) as i64) >> 16))
+        } else if self.is_big_int() {
+            let id = (self.payload() - FIRST_BIG_INT) as usize;
+            Some(Number::Int(BIG_INTS.lock().unwrap().values[id]))
+        } else {
+            None
+        }
+    }
+
+    pub fn pack(value: Value) -> Self {
+        match value {
+            Value::Nil => Self::NIL,
+            Value::Unk => Self::boxed(TAG_MISC, 1),
+            Value::Bool(c) => Self::boxed(TAG_BOOL, c as u64),
+            Value::Int(c) => Self::from_int(c),
+            Value::Float(c) => Self::from_float(c),
+            Value::Ptr(c) => Self::boxed(TAG_PTR, c as u64),
+            Value::StaticPtr(c) => Self::boxed(TAG_STATIC_PTR, c as u64),
+            Value::Obj(c) => Self::boxed(TAG_OBJ, c as u64),
+            Value::Function(c) => Self::boxed(TAG_FUNCTION, c as u64),
+            Value::NativeFunction(c) => Self::boxed(TAG_NATIVE, c as u64),
+        }
+    }
+
+    pub fn unpack(self) -> Value {
+        if let Some(n) = self.number() {
+            return match n {
+                Number::Int(c) => Value::Int(c),
+                Number::Float(c) => Value::Float(c),
+            };
+        }
+        let payload = self.payload();
+        match self.tag() {
+            TAG_MISC => if payload == 0 { Value::Nil } else { Value::Unk },
+            TAG_BOOL => Value::Bool(payload != 0),
+            TAG_OBJ => Value::Obj(payload as usize),
+            TAG_FUNCTION => Value::Function(payload as usize),
+            TAG_NATIVE => Value::NativeFunction(payload as usize),
+            TAG_PTR => Value::Ptr(payload as usize),
+            TAG_STATIC_PTR => Value::StaticPtr(payload as usize),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn is_nil(&self) -> bool {
+        self.0 == Self::NIL.0
+    }
+
+    pub fn is_number(&self) -> bool {
+        self.is_float() || self.tag() == TAG_INT || self.is_big_int()
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        if !self.is_float() && self.tag() == TAG_BOOL {
+            Some(self.payload() != 0)
+        } else {
+            None
+        }
+    }
+
+    pub fn to_str(self) -> String {
+        self.unpack().to_str()
+    }
+
+    pub fn bool_and(self, rhs: Self) -> Self {
+        match (self.as_bool(), rhs.as_bool()) {
+            (Some(c1), Some(c2)) => Self::from(c1 && c2),
+            _ => Self::NIL
+        }
+    }
+
+    pub fn bool_or(self, rhs: Self) -> Self {
+        match (self.as_bool(), rhs.as_bool()) {
+            (Some(c1), Some(c2)) => Self::from(c1 || c2),
+            _ => Self::NIL
+        }
+    }
+
+    pub fn bool_not(self) -> Self {
+        match self.as_bool() {
+            Some(c) => Self::from(!c),
+            _ => Self::NIL
+        }
+    }
+
+    pub fn bitnot(self) -> Self {
+        match self.number() {
+            Some(Number::Int(c)) => Self::from_int((-1) ^ c),
+            _ => Self::NIL,
+        }
+    }
+}
+
+impl Default for PackedValue {
+    fn default() -> Self {
+        Self::NIL
+    }
+}
+
+impl fmt::Debug for PackedValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.unpack())
+    }
+}
+
+impl From<bool> for PackedValue {
+    fn from(value: bool) -> Self {
+        Self::boxed(TAG_BOOL, value as u64)
+    }
+}
+
+macro_rules! impl_binary_op_for_constant {
+    ($clz:ident, $op:ident, $int_op:ident) => {
+        impl $clz for PackedValue {
+            type Output = PackedValue;
+            fn $op(self, rhs: Self) -> Self::Output {
+                match (self.number(), rhs.number()) {
+                    (Some(Number::Float(c1)), Some(Number::Float(c2))) => Self::from_float(c1.$op(c2)),
+                    (Some(Number::Int(c1)),   Some(Number::Int(c2)))   => Self::from_int(c1.$int_op(c2)),
+                    (Some(Number::Int(c1)),   Some(Number::Float(c2))) => Self::from_float((c1 as f64).$op(c2)),
+                    (Some(Number::Float(c1)), Some(Number::Int(c2)))   => Self::from_float(c1.$op(c2 as f64)),
+                    _ => Self::NIL
+                }
+            }
+        }
+    };
+}
+
+macro_rules! impl_binary_op_for_integer {
+    ($clz:ident, $op:ident) => {
+        impl $clz for PackedValue {
+            type Output = PackedValue;
+            fn $op(self, rhs: Self) -> Self::Output {
+                match (self.number(), rhs.number()) {
+                    (Some(Number::Int(c1)), Some(Number::Int(c2))) => Self::from_int(c1.$op(c2)),
+                    _ => Self::NIL
+                }
+            }
+        }
+    };
+}
+
+impl Neg for PackedValue {
+    type Output = Self;
+    fn neg(self) -> Self::Output {
+        match self.number() {
+            Some(Number::Int(c)) => Self::from_int(c.wrapping_neg()),
+            Some(Number::Float(c)) => Self::from_float(-c),
+            _ => Self::NIL,
+        }
+    }
+}
+
+impl_binary_op_for_constant!(Add, add, wrapping_add);
+impl_binary_op_for_constant!(Sub, sub, wrapping_sub);
+impl_binary_op_for_constant!(Mul, mul, wrapping_mul);
+
+impl_binary_op_for_integer!(Rem, rem);
+impl_binary_op_for_integer!(Shr, shr);
+impl_binary_op_for_integer!(Shl, shl);
+impl_binary_op_for_integer!(BitAnd, bitand);
+impl_binary_op_for_integer!(BitOr,  bitor);
+impl_binary_op_for_integer!(BitXor, bitxor);
+
+impl Div for PackedValue {
+    type Output = Self;
+    fn div(self, rhs: Self) -> Self::Output {
+        match (self.number(), rhs.number()) {
+            (Some(Number::Int(c1)),   Some(Number::Int(c2)))   => Self::from_float((c1 as f64).div(c2 as f64)),
+            (Some(Number::Int(c1)),   Some(Number::Float(c2))) => Self::from_float((c1 as f64).div(c2)),
+            (Some(Number::Float(c1)), Some(Number::Int(c2)))   => Self::from_float(c1.div(c2 as f64)),
+            (Some(Number::Float(c1)), Some(Number::Float(c2))) => Self::from_float(c1.div(c2)),
+            _ => Self::NIL
+        }
+    }
+}
+
+impl PartialEq for PackedValue {
+    fn eq(&self, other: &Self) -> bool {
+        if self.is_float() && other.is_float() {
+            f64::from_bits(self.0) == f64::from_bits(other.0)
+        } else {
+            self.0 == other.0
+        }
+    }
+}
+
+impl PartialOrd for PackedValue {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self.number()?, other.number()?) {
+            (Number::Int(c1),   Number::Int(c2))   => Some(c1.cmp(&c2)),
+            (Number::Int(c1),   Number::Float(c2)) => Some(if c1 as f64 == c2 { Ordering::Equal } else if c1 as f64 > c2 { Ordering::Greater } else { Ordering::Less }),
+            (Number::Float(c1), Number::Int(c2))   => Some(if c1 == c2 as f64 { Ordering::Equal } else if c1 > c2 as f64 { Ordering::Greater } else { Ordering::Less }),
+            (Number::Float(c1), Number::Float(c2)) => Some(if c1 == c2 { Ordering::Equal } else if c1 > c2 { Ordering::Greater } else { Ordering::Less }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ints_outside_48_bits_round_trip() {
+        for n in [0, -1, 140737488355327, 140737488355328, -140737488355329, i64::MAX, i64::MIN] {
+            assert!(matches!(PackedValue::pack(Value::Int(n)).unpack(), Value::Int(c) if c == n));
+        }
+        assert!(PackedValue::pack(Value::Int(i64::MAX)).is_number());
+        assert!(matches!(PackedValue::pack(Value::Unk).unpack(), Value::Unk));
+    }
+
+    #[test]
+    fn big_ints_compare_and_compute_like_value() {
+        let big = PackedValue::pack(Value::Int(140737488355327));
+        let one = PackedValue::pack(Value::Int(1));
+        assert!(big + one == PackedValue::pack(Value::Int(140737488355328)));
+        assert!(big + one > big);
+        assert!(matches!((big + one - one).unpack(), Value::Int(140737488355327)));
+        assert!(matches!((big * big).unpack(), Value::Int(c) if c == 140737488355327i64.wrapping_mul(140737488355327)));
+    }
+}
@@ -1,9 +1,10 @@
-use std::{collections::HashMap, ops::{Deref, DerefMut}};
+use std::{collections::HashMap, ops::{Deref, DerefMut, RangeInclusive}};
 
-use crate::{value::Value, object::Object, helper::ToObject};
+use crate::{value::Value, object::Object, helper::ToObject, heap::Heap};
 
 
-pub type NativeFunction = fn(&mut Vec<Object>, usize, Vec<Value>) -> Value;
+/// Natives allocate through the `Heap` and report running out of memory as an error.
+pub type NativeFunction = fn(&mut Heap, usize, Vec<Value>) -> Result<Value, String>;
 
 #[derive(Debug, Default, Clone)]
 pub struct Native {
@@ -21,36 +22,53 @@ impl Native {
         Native { functions }
     }
 
-    fn list(objs: &mut Vec<Object>, argc: usize, args: Vec<Value>) -> Value {
+    fn list(objs: &mut Heap, argc: usize, args: Vec<Value>) -> Result<Value, String> {
         let mut list = Vec::new();
         // println!("{} {:?}", argc, &args);
         for i in (0..argc).rev() {
-            list.push(args[i].clone());
+            list.push(args[i]);
         }
-        let val = list.to_object(objs);
-        val
+        list.to_object(objs)
     }
 
-    fn new_empty_list(objs: &mut Vec<Object>, argc: usize, args: Vec<Value>) -> Value {
-        assert!(argc == 1 || argc == 2);
+    fn new_empty_list(objs: &mut Heap, argc: usize, args: Vec<Value>) -> Result<Value, String> {
+        Self::check_argc("$new_empty_list", argc, 1..=2)?;
         if argc == 1 {
             let Value::Int(index) = args[0] else {
                 panic!("Expect int on arg 0")
             };
+            Self::check_list_size(index)?;
+            objs.allocate(Heap::list_size(index as usize))?;
             let list = vec![Value::Nil; index as usize];
-            list.to_object(objs)
+            Ok(objs.insert(Object::List(list)))
         } else {
             let Value::Int(index) = args[1] else {
                 panic!("Expect int on arg 0")
             };
             let val = args[0];
+            Self::check_list_size(index)?;
+            objs.allocate(Heap::list_size(index as usize))?;
             let list = vec![val; index as usize];
-            list.to_object(objs)
+            Ok(objs.insert(Object::List(list)))
         }
     }
 
-    fn list_get(objs: &mut Vec<Object>, argc: usize, args: Vec<Value>) -> Value {
-        assert_eq!(argc, 2);
+    fn check_argc(name: &str, argc: usize, expected: RangeInclusive<usize>) -> Result<(), String> {
+        if !expected.contains(&argc) {
+            return Err(format!("Native '{}' cannot take {} argument(s)", name, argc));
+        }
+        Ok(())
+    }
+
+    fn check_list_size(size: i64) -> Result<(), String> {
+        if size < 0 {
+            return Err(format!("List size cannot be negative: {}", size));
+        }
+        Ok(())
+    }
+
+    fn list_get(objs: &mut Heap, argc: usize, args: Vec<Value>) -> Result<Value, String> {
+        Self::check_argc("$list->get", argc, 2..=2)?;
         let Value::Int(index) = args[0] else {
             panic!("Expect int on arg 1")
         };
@@ -60,11 +78,11 @@ impl Native {
         let Object::List(list) = &objs[i] else {
             panic!("Expect List on arg 0")
         };
-        list[index as usize]
+        Ok(list[index as usize])
     }
 
-    fn list_set(objs: &mut Vec<Object>, argc: usize, args: Vec<Value>) -> Value {
-        assert_eq!(argc, 3);
+    fn list_set(objs: &mut Heap, argc: usize, args: Vec<Value>) -> Result<Value, String> {
+        Self::check_argc("$list->set", argc, 3..=3)?;
         let val = args[0];
         let Value::Int(index) = args[1] else {
             panic!("Expect int on arg 1")
@@ -76,20 +94,7 @@ impl Native {
             panic!("Expect List on arg 0")
         };
         list[index as usize] = val;
-        val
-    }
-
-    fn list_push(objs: &mut Vec<Object>, argc: usize, args: Vec<Value>) -> Value {
-        assert_eq!(argc, 2);
-        let val = args[0];
-        let Value::Obj(i) = args[1] else {
-            panic!("Expect object on arg 0")
-        };
-        let Object::List(list) = &mut objs[i] else {
-            panic!("Expect List on arg 0")
-        };
-        list.push(val);
-        Value::Nil
+        Ok(val)
     }
 
 }
@@ -108,3 +113,20 @@ impl DerefMut for Native {
         &mut self.functions
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn natives_reject_wrong_argument_counts() {
+        let natives = Native::new();
+        let mut heap = Heap::new();
+        let list = natives["$list"](&mut heap, 2, vec![Value::Int(1), Value::Int(2)]).unwrap();
+        assert_eq!(natives["$list->get"](&mut heap, 1, vec![list]).unwrap_err(),
+                   "Native '$list->get' cannot take 1 argument(s)");
+        assert!(natives["$list->set"](&mut heap, 2, vec![Value::Int(0), list]).is_err());
+        assert!(natives["$new_empty_list"](&mut heap, 0, vec![]).is_err());
+        assert_eq!(natives["$list->get"](&mut heap, 2, vec![Value::Int(1), list]), Ok(Value::Int(1)));
+    }
+}
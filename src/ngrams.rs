@@ -0,0 +1,26 @@
+use std::collections::HashMap;
+
+use crate::bytecode::{Chunk, OpCode};
+use crate::optimizer;
+
+
+/// Counts the opcode sequences of length `n` that no jump lands inside of,
+/// i.e. the candidates for a superinstruction. Most frequent first.
+pub fn count(chunks: &[&Chunk], n: usize) -> Vec<(Vec<OpCode>, usize)> {
+    let mut counts: HashMap<Vec<OpCode>, usize> = HashMap::new();
+    for chunk in chunks {
+        let code = chunk.to_instructions();
+        let targets = optimizer::jump_targets(&code);
+        for (i, window) in code.windows(n).enumerate() {
+            if targets[i + 1..i + n].contains(&true) {
+                continue;
+            }
+            let ops = window.iter().map(|(ins, _)| ins.opcode()).collect();
+            *counts.entry(ops).or_insert(0) += 1;
+        }
+    }
+    let mut result: Vec<_> = counts.into_iter().collect();
+    result.sort_by(|a, b| b.1.cmp(&a.1)
+        .then_with(|| a.0.iter().map(|op| *op as u8).cmp(b.0.iter().map(|op| *op as u8))));
+    result
+}
@@ -1,5 +1,3 @@
-use std::vec;
-
 use crate::{bytecode::Chunk, value::Value};
 
 
@@ -26,7 +24,7 @@ pub struct Function {
 
 impl Function {
     pub fn new(s: String) -> Self {
-        Function { arity: 0, chunk: Chunk { code: vec![], lines: vec![] }, name: s }
+        Function { arity: 0, chunk: Chunk::new(), name: s }
     }
 }
 
@@ -0,0 +1,290 @@
+use crate::bytecode::{ByteCode, Chunk};
+use crate::source_map::Loc;
+use crate::cfg;
+
+
+/// `(instruction, location)` pairs with jump targets as instruction indices,
+/// as produced by `Chunk::to_instructions`.
+type Instructions = Vec<(ByteCode, Loc)>;
+
+/// Level used unless another one is asked for.
+pub const DEFAULT_OPT_LEVEL: u8 = 2;
+
+/// Optimizes a compiled chunk in place. Levels:
+///   0: nothing
+///   1: peephole rewrites and dead code elimination
+///   2: also fuses common sequences into superinstructions
+pub fn optimize(chunk: &mut Chunk, level: u8) {
+    if level == 0 {
+        return;
+    }
+    let mut code = chunk.to_instructions();
+    while eliminate_dead_code(&mut code) | peephole(&mut code) {}
+    if level >= 2 {
+        fuse(&mut code);
+    }
+    chunk.set_instructions(&code);
+}
+
+/// Source lines none of whose code can run. Lines where only compiler
+/// generated cleanup (pops, jumps, returns) is unreachable are left out.
+pub fn unreachable_lines(chunk: &Chunk) -> Vec<usize> {
+    let code = chunk.to_instructions();
+    let blocks = cfg::basic_blocks(&code);
+    let reachable = cfg::reachable(&blocks);
+    let mut live_lines = vec![];
+    let mut dead_lines = vec![];
+    for (block, is_reachable) in blocks.iter().zip(reachable) {
+        for (ins, loc) in &code[block.start..block.end] {
+            if is_reachable {
+                live_lines.push(loc.line);
+            } else if !matches!(ins, ByteCode::Nop | ByteCode::Pop | ByteCode::PopN(_) | ByteCode::J(_) | ByteCode::Ret | ByteCode::Hlt) {
+                dead_lines.push(loc.line);
+            }
+        }
+    }
+    dead_lines.retain(|line| !live_lines.contains(line));
+    dead_lines.sort();
+    dead_lines.dedup();
+    dead_lines
+}
+
+/// Drops the basic blocks that cannot be reached from the entry.
+fn eliminate_dead_code(code: &mut Instructions) -> bool {
+    let blocks = cfg::basic_blocks(code);
+    let reachable = cfg::reachable(&blocks);
+    if !reachable.contains(&false) {
+        return false;
+    }
+    let mut removed = vec![false; code.len()];
+    for (block, is_reachable) in blocks.iter().zip(reachable) {
+        if !is_reachable {
+            removed[block.start..block.end].fill(true);
+        }
+    }
+    remove(code, &removed);
+    true
+}
+
+/// One round of peephole rewrites, returns whether anything changed.
+fn peephole(code: &mut Instructions) -> bool {
+    let mut changed = thread_jumps(code);
+    let targets = jump_targets(code);
+    let mut removed = vec![false; code.len()];
+    let mut i = 0;
+    while i < code.len() {
+        let next = code.get(i + 1).map(|(ins, _)| *ins);
+        let after_next = code.get(i + 2).map(|(ins, _)| *ins);
+        match (code[i].0, next, after_next) {
+            (ByteCode::Nop, _, _) => removed[i] = true,
+            // a jump to the following instruction
+            (ByteCode::J(target), _, _) if target == i + 1 => removed[i] = true,
+            // a constant that is dropped right away
+            (ByteCode::Value(_), Some(ByteCode::Pop), _) if !targets[i + 1] => {
+                removed[i] = true;
+                removed[i + 1] = true;
+                i += 1;
+            },
+            // `x = ...` followed by a statement reading `x`: keep the value
+            (ByteCode::Set(c1), Some(ByteCode::Pop), Some(ByteCode::Load(c2))) |
+            (ByteCode::SetLocal(c1), Some(ByteCode::Pop), Some(ByteCode::LoadLocal(c2)))
+                if c1 == c2 && !targets[i + 1] && !targets[i + 2] => {
+                removed[i + 1] = true;
+                removed[i + 2] = true;
+                i += 2;
+            },
+            _ => (),
+        }
+        i += 1;
+    }
+    if removed.contains(&true) {
+        remove(code, &removed);
+        changed = true;
+    }
+    changed
+}
+
+/// The superinstruction standing for `window`, see `ByteCode::expand`, and
+/// the index in `window` of the operation whose source location it keeps.
+fn superinstruction(window: &[ByteCode]) -> Option<(ByteCode, usize)> {
+    match *window {
+        [ByteCode::Value(c), ByteCode::Add, ByteCode::SetLocal(l), ByteCode::Pop] =>
+            Some((ByteCode::AddConstSetLocal(c, l), 1)),
+        [ByteCode::LoadLocal(a), ByteCode::LoadLocal(b), ByteCode::Lt, ByteCode::Jz(t)] =>
+            Some((ByteCode::LtLocalsJz(a, b, t), 2)),
+        [ByteCode::LoadLocal(l), ByteCode::Value(c), ByteCode::Add] =>
+            Some((ByteCode::AddLocalConst(l, c), 2)),
+        _ => None,
+    }
+}
+
+/// Replaces sequences no jump lands inside of by superinstructions. Longer
+/// ones go first, so that `i = i + 1` fuses its store rather than its load.
+fn fuse(code: &mut Instructions) -> bool {
+    let mut changed = false;
+    for len in [4, 3] {
+        let targets = jump_targets(code);
+        let mut removed = vec![false; code.len()];
+        let mut i = 0;
+        while i + len <= code.len() {
+            let window: Vec<ByteCode> = code[i..i + len].iter().map(|(ins, _)| *ins).collect();
+            match superinstruction(&window) {
+                Some((fused, op)) if !targets[i + 1..i + len].contains(&true) => {
+                    code[i] = (fused, code[i + op].1);
+                    removed[i + 1..i + len].fill(true);
+                    i += len;
+                },
+                _ => i += 1,
+            }
+        }
+        if removed.contains(&true) {
+            remove(code, &removed);
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Redirects jumps whose target is an unconditional jump to its final target.
+fn thread_jumps(code: &mut Instructions) -> bool {
+    let mut changed = false;
+    for i in 0..code.len() {
+        let Some(mut target) = code[i].0.jump_target() else {
+            continue
+        };
+        let mut hops = 0;
+        while let Some((ByteCode::J(next), _)) = code.get(target) {
+            if *next == target || hops == code.len() {
+                break;
+            }
+            target = *next;
+            hops += 1;
+        }
+        if code[i].0.jump_target() != Some(target) {
+            code[i].0.set_jump_target(target);
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Marks the instructions (and the end of the code) some jump lands on.
+pub fn jump_targets(code: &Instructions) -> Vec<bool> {
+    let mut targets = vec![false; code.len() + 1];
+    for (ins, _) in code {
+        if let Some(target) = ins.jump_target() {
+            targets[target] = true;
+        }
+    }
+    targets
+}
+
+/// Drops the instructions marked in `removed`. Jumps to a removed
+/// instruction land on the next one kept.
+pub fn remove(code: &mut Instructions, removed: &[bool]) {
+    let mut new_index = Vec::with_capacity(code.len() + 1);
+    let mut kept = 0;
+    for is_removed in removed {
+        new_index.push(kept);
+        if !is_removed {
+            kept += 1;
+        }
+    }
+    new_index.push(kept);
+    let mut i = 0;
+    code.retain(|_| {
+        i += 1;
+        !removed[i - 1]
+    });
+    for (ins, _) in code.iter_mut() {
+        if let Some(target) = ins.jump_target() {
+            ins.set_jump_target(new_index[target]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_peephole(code: &[ByteCode]) -> Vec<ByteCode> {
+        let mut code: Instructions = code.iter().map(|ins| (*ins, Loc::default())).collect();
+        while peephole(&mut code) {}
+        code.into_iter().map(|(ins, _)| ins).collect()
+    }
+
+    #[test]
+    fn drops_unused_constants_and_nops() {
+        use ByteCode::*;
+        assert_eq!(run_peephole(&[Value(0), Pop, Nop, Load(1), Ret]), [Load(1), Ret]);
+        assert_eq!(run_peephole(&[Load(0), J(2), Out, Ret]), [Load(0), Out, Ret]);
+    }
+
+    #[test]
+    fn keeps_an_assigned_value_that_is_read_next() {
+        use ByteCode::*;
+        assert_eq!(run_peephole(&[Value(0), Set(1), Pop, Load(1), Out]), [Value(0), Set(1), Out]);
+        assert_eq!(run_peephole(&[Value(0), SetLocal(1), Pop, LoadLocal(1), Out]), [Value(0), SetLocal(1), Out]);
+        assert_eq!(run_peephole(&[Value(0), Set(1), Pop, Load(2), Out]), [Value(0), Set(1), Pop, Load(2), Out]);
+    }
+
+    #[test]
+    fn leaves_pairs_split_by_a_jump_target() {
+        use ByteCode::*;
+        let code = [Load(0), Jz(3), Value(0), Pop, Ret];
+        assert_eq!(run_peephole(&code), code);
+        let code = [Value(0), Set(1), Pop, Load(1), Out, J(3)];
+        assert_eq!(run_peephole(&code), code);
+    }
+
+    #[test]
+    fn threads_jumps_through_unconditional_jumps() {
+        use ByteCode::*;
+        assert_eq!(run_peephole(&[Load(0), Jz(3), Out, J(5), Out, Ret]), [Load(0), Jz(5), Out, J(5), Out, Ret]);
+        assert_eq!(run_peephole(&[J(0)]), [J(0)]);
+    }
+
+    #[test]
+    fn drops_code_after_return() {
+        use ByteCode::*;
+        let mut code: Instructions = [Load(0), Ret, Out, J(0), Ret].iter().map(|ins| (*ins, Loc::default())).collect();
+        assert!(eliminate_dead_code(&mut code));
+        assert_eq!(code, [(Load(0), Loc::default()), (Ret, Loc::default())]);
+        assert!(!eliminate_dead_code(&mut code));
+    }
+
+    #[test]
+    fn warns_about_lines_after_return() {
+        let codegen = crate::compile_source("func f(a):\n    return a\n    print(a)\n    a = 2\nprint(f(1))\n");
+        assert_eq!(codegen.warnings, ["[Parsing Warning] 'Unreachable code in f' at line 3.",
+                                      "[Parsing Warning] 'Unreachable code in f' at line 4."]);
+        let code: Vec<ByteCode> = codegen.functions[1].chunk.instructions().map(|(_, ins)| ins).collect();
+        assert!(!code.contains(&ByteCode::Out));
+    }
+
+    fn run_fuse(code: &[ByteCode]) -> Vec<ByteCode> {
+        let mut code: Instructions = code.iter().map(|ins| (*ins, Loc::default())).collect();
+        fuse(&mut code);
+        code.into_iter().map(|(ins, _)| ins).collect()
+    }
+
+    #[test]
+    fn fuses_superinstructions() {
+        use ByteCode::*;
+        assert_eq!(run_fuse(&[LoadLocal(0), Value(1), Add, Out]), [AddLocalConst(0, 1), Out]);
+        assert_eq!(run_fuse(&[LoadLocal(0), Value(1), Add, SetLocal(0), Pop]), [LoadLocal(0), AddConstSetLocal(1, 0)]);
+        assert_eq!(run_fuse(&[LoadLocal(0), LoadLocal(1), Lt, Jz(6), Out, J(0), Ret]),
+                   [LtLocalsJz(0, 1, 3), Out, J(0), Ret]);
+    }
+
+    #[test]
+    fn does_not_fuse_across_a_jump_target() {
+        use ByteCode::*;
+        let code = [LoadLocal(0), Value(1), Add, Out, J(1)];
+        assert_eq!(run_fuse(&code), code);
+        let code = [Load(0), Jz(3), LoadLocal(0), Value(1), Add, Out];
+        assert_eq!(run_fuse(&code), code);
+        let code = [Load(0), Jz(2), LoadLocal(0), Value(1), Add, Out];
+        assert_eq!(run_fuse(&code), [Load(0), Jz(2), AddLocalConst(0, 1), Out]);
+    }
+}
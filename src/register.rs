@@ -0,0 +1,529 @@
+use std::ops::{Add, Sub, Mul, Div, Neg, Rem, Shl, BitAnd, BitXor, BitOr, Shr};
+
+use crate::bytecode::ByteCode;
+use crate::object::{Function, Object};
+use crate::optimizer;
+use crate::source_map::Loc;
+use crate::value::Value;
+use crate::virtual_machine::*;
+
+
+/// Source of a register instruction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    /// A slot of the current frame, locals come first.
+    Reg(usize),
+    /// An entry of the function's constant pool.
+    Const(usize),
+}
+
+/// Three-address instruction of the register backend. Registers are the
+/// frame's stack slots, so arguments and locals are addressed directly.
+/// Jump targets are instruction indices.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegOp {
+    Move { dst: usize, src: Operand },
+    /// `op` is one of the stack machine's unary operators.
+    Unary { op: ByteCode, dst: usize, src: Operand },
+    /// `op` is one of the stack machine's binary operators.
+    Binary { op: ByteCode, dst: usize, lhs: Operand, rhs: Operand },
+    LoadGlobal { dst: usize, name: usize },
+    SetGlobal { name: usize, src: Operand },
+    DefGlobal { name: usize, src: Operand },
+    LoadNative { dst: usize, name: usize },
+    Out { src: Operand },
+    J(usize),
+    Jz { cond: Operand, target: usize },
+    Jnz { cond: Operand, target: usize },
+    /// Calls the function in `func` with the `argc` registers after it,
+    /// the result replaces the function.
+    Call { func: usize, argc: usize },
+    TailCall { func: usize, argc: usize },
+    /// Calls the native in `func` with the `argc` registers before it,
+    /// the result goes to the first argument's register.
+    CallNative { func: usize, argc: usize },
+    Ret { src: Operand },
+    Hlt,
+}
+
+impl Operand {
+    fn disassemble(&self) -> String {
+        match self {
+            Operand::Reg(r) => format!("r{}", r),
+            Operand::Const(c) => format!("k{}", c),
+        }
+    }
+}
+
+impl RegOp {
+    pub fn disassemble(&self) -> String {
+        match self {
+            RegOp::Move { dst, src } => format!("move\tr{}, {}", dst, src.disassemble()),
+            RegOp::Unary { op, dst, src } => format!("{}\tr{}, {}", op.disassemble(), dst, src.disassemble()),
+            RegOp::Binary { op, dst, lhs, rhs } =>
+                format!("{}\tr{}, {}, {}", op.disassemble(), dst, lhs.disassemble(), rhs.disassemble()),
+            RegOp::LoadGlobal { dst, name } => format!("load\tr{}, {}", dst, name),
+            RegOp::SetGlobal { name, src } => format!("set\t{}, {}", name, src.disassemble()),
+            RegOp::DefGlobal { name, src } => format!("def_global\t{}, {}", name, src.disassemble()),
+            RegOp::LoadNative { dst, name } => format!("load_native\tr{}, {}", dst, name),
+            RegOp::Out { src } => format!("out\t{}", src.disassemble()),
+            RegOp::J(target) => format!("j\t{}", target),
+            RegOp::Jz { cond, target } => format!("jz\t{}, {}", cond.disassemble(), target),
+            RegOp::Jnz { cond, target } => format!("jnz\t{}, {}", cond.disassemble(), target),
+            RegOp::Call { func, argc } => format!("call\tr{}, {}", func, argc),
+            RegOp::TailCall { func, argc } => format!("tail_call\tr{}, {}", func, argc),
+            RegOp::CallNative { func, argc } => format!("call_native\tr{}, {}", func, argc),
+            RegOp::Ret { src } => format!("ret\t{}", src.disassemble()),
+            RegOp::Hlt => String::from("hlt"),
+        }
+    }
+
+    fn set_jump_target(&mut self, new_target: usize) {
+        match self {
+            RegOp::J(target) |
+            RegOp::Jz { target, .. } |
+            RegOp::Jnz { target, .. } => *target = new_target,
+            _ => (),
+        }
+    }
+}
+
+/// A function translated for the register backend.
+#[derive(Default, Debug, Clone)]
+pub struct RegisterFunction {
+    pub code: Vec<RegOp>,
+    /// Source location of every instruction in `code`.
+    pub locs: Vec<Loc>,
+    pub constants: Vec<StackElem>,
+    /// Registers a frame of this function uses, arguments included.
+    pub frame_size: usize,
+}
+
+/// Translates stack bytecode by simulating the stack: slot `n` becomes
+/// register `n`. Constants and locals are passed on as operands instead of
+/// being copied, and only written to their slot when a call, a jump or a
+/// write to the local needs it.
+struct Translator {
+    code: Vec<RegOp>,
+    locs: Vec<Loc>,
+    stack: Vec<Operand>,
+    frame_size: usize,
+    loc: Loc,
+}
+
+pub fn translate(func: &Function) -> RegisterFunction {
+    let code = func.chunk.to_instructions();
+    let targets = optimizer::jump_targets(&code);
+    let arity = func.arity as usize;
+    let mut translator = Translator {
+        code: vec![], locs: vec![], stack: (0..arity).map(Operand::Reg).collect(), frame_size: arity,
+        loc: Loc::default(),
+    };
+    // stack depth expected where a forward jump lands
+    let mut depth_at = vec![None; code.len() + 1];
+    let mut index = vec![0; code.len() + 1];
+    let mut falls_through = true;
+    for (i, (ins, loc)) in code.iter().enumerate() {
+        translator.loc = *loc;
+        if targets[i] {
+            if falls_through {
+                translator.flush();
+            } else if let Some(depth) = depth_at[i] {
+                translator.stack = (0..depth).map(Operand::Reg).collect();
+            }
+        }
+        index[i] = translator.code.len();
+        falls_through = !matches!(ins, ByteCode::J(_) | ByteCode::Ret | ByteCode::Hlt);
+        if let Some(target) = translator.instruction(*ins) {
+            depth_at[target].get_or_insert(translator.stack.len());
+        }
+    }
+    index[code.len()] = translator.code.len();
+    for op in translator.code.iter_mut() {
+        if let RegOp::J(target) | RegOp::Jz { target, .. } | RegOp::Jnz { target, .. } = *op {
+            op.set_jump_target(index[target]);
+        }
+    }
+    RegisterFunction {
+        code: translator.code,
+        locs: translator.locs,
+        constants: func.chunk.constants.iter().map(|c| StackElem::pack(*c)).collect(),
+        frame_size: translator.frame_size,
+    }
+}
+
+impl Translator {
+    fn emit(&mut self, op: RegOp) {
+        self.code.push(op);
+        self.locs.push(self.loc);
+    }
+
+    fn push(&mut self, operand: Operand) {
+        self.stack.push(operand);
+        self.frame_size = self.frame_size.max(self.stack.len());
+    }
+
+    fn pop(&mut self) -> Operand {
+        self.stack.pop().expect("stack underflow in bytecode")
+    }
+
+    fn top(&self) -> usize {
+        self.stack.len() - 1
+    }
+
+    /// Writes slot `n` to its register.
+    fn materialize(&mut self, n: usize) {
+        if self.stack[n] != Operand::Reg(n) {
+            self.emit(RegOp::Move { dst: n, src: self.stack[n] });
+            self.stack[n] = Operand::Reg(n);
+        }
+    }
+
+    fn flush(&mut self) {
+        for n in 0..self.stack.len() {
+            self.materialize(n);
+        }
+    }
+
+    /// Saves the slots that still refer to register `r` before it changes.
+    fn clobber(&mut self, r: usize) {
+        for n in 0..self.stack.len() {
+            if n != r && self.stack[n] == Operand::Reg(r) {
+                self.materialize(n);
+            }
+        }
+    }
+
+    /// Translates one instruction, returns the target of a jump.
+    fn instruction(&mut self, ins: ByteCode) -> Option<usize> {
+        match ins {
+            ByteCode::Add | ByteCode::Sub | ByteCode::Mul | ByteCode::Div | ByteCode::Mod |
+            ByteCode::Shl | ByteCode::Shr | ByteCode::LAnd | ByteCode::LOr | ByteCode::LXor |
+            ByteCode::And | ByteCode::Or |
+            ByteCode::Eq | ByteCode::Ne | ByteCode::Lt | ByteCode::Le | ByteCode::Gt | ByteCode::Ge => {
+                let rhs = self.pop();
+                let lhs = self.pop();
+                let dst = self.stack.len();
+                self.emit(RegOp::Binary { op: ins, dst, lhs, rhs });
+                self.push(Operand::Reg(dst));
+            },
+            ByteCode::Neg | ByteCode::Not | ByteCode::LNot => {
+                let src = self.pop();
+                let dst = self.stack.len();
+                self.emit(RegOp::Unary { op: ins, dst, src });
+                self.push(Operand::Reg(dst));
+            },
+            ByteCode::Value(c) => self.push(Operand::Const(c)),
+            ByteCode::Pop => { self.pop(); },
+            ByteCode::PopN(n) => for _ in 0..n { self.pop(); },
+            ByteCode::Nop => (),
+            ByteCode::Out => self.emit(RegOp::Out { src: self.stack[self.top()] }),
+            ByteCode::Load(name) => {
+                let dst = self.stack.len();
+                self.emit(RegOp::LoadGlobal { dst, name });
+                self.push(Operand::Reg(dst));
+            },
+            ByteCode::LoadNative(name) => {
+                let dst = self.stack.len();
+                self.emit(RegOp::LoadNative { dst, name });
+                self.push(Operand::Reg(dst));
+            },
+            ByteCode::Set(name) => self.emit(RegOp::SetGlobal { name, src: self.stack[self.top()] }),
+            ByteCode::DefGlobal(name) => {
+                let src = self.pop();
+                self.emit(RegOp::DefGlobal { name, src });
+            },
+            ByteCode::LoadLocal(n) => self.push(self.stack[n]),
+            ByteCode::SetLocal(n) => {
+                let src = self.stack[self.top()];
+                if src != Operand::Reg(n) {
+                    self.clobber(n);
+                    self.emit(RegOp::Move { dst: n, src });
+                    self.stack[n] = Operand::Reg(n);
+                }
+            },
+            ByteCode::Call(argc) | ByteCode::TailCall(argc) => {
+                let func = self.stack.len() - argc - 1;
+                for n in func..self.stack.len() {
+                    self.materialize(n);
+                }
+                if let ByteCode::Call(_) = ins {
+                    self.emit(RegOp::Call { func, argc });
+                } else {
+                    self.emit(RegOp::TailCall { func, argc });
+                }
+                self.stack.truncate(func);
+                self.push(Operand::Reg(func));
+            },
+            ByteCode::CallNative(argc) => {
+                let func = self.top();
+                for n in func - argc..=func {
+                    self.materialize(n);
+                }
+                self.emit(RegOp::CallNative { func, argc });
+                self.stack.truncate(func - argc);
+                self.push(Operand::Reg(func - argc));
+            },
+            ByteCode::Ret => {
+                let src = self.pop();
+                self.emit(RegOp::Ret { src });
+            },
+            ByteCode::Hlt => self.emit(RegOp::Hlt),
+            ByteCode::Nil | ByteCode::True | ByteCode::False => unreachable!("rejected by the verifier"),
+            ByteCode::J(target) => {
+                self.flush();
+                self.emit(RegOp::J(target));
+                return Some(target);
+            },
+            ByteCode::Jz(target) | ByteCode::Jnz(target) => {
+                let cond = self.pop();
+                self.flush();
+                if let ByteCode::Jz(_) = ins {
+                    self.emit(RegOp::Jz { cond, target });
+                } else {
+                    self.emit(RegOp::Jnz { cond, target });
+                }
+                return Some(target);
+            },
+            ByteCode::JzKeep(target) | ByteCode::JnzKeep(target) => {
+                self.flush();
+                let cond = Operand::Reg(self.top());
+                if let ByteCode::JzKeep(_) = ins {
+                    self.emit(RegOp::Jz { cond, target });
+                } else {
+                    self.emit(RegOp::Jnz { cond, target });
+                }
+                return Some(target);
+            },
+            ByteCode::AddLocalConst(..) | ByteCode::AddConstSetLocal(..) | ByteCode::LtLocalsJz(..) => {
+                let mut target = None;
+                for part in ins.expand() {
+                    target = self.instruction(part).or(target);
+                }
+                return target;
+            },
+        }
+        None
+    }
+}
+
+impl VirtualMachine {
+    fn operand(&self, base: usize, func_id: usize, operand: Operand) -> StackElem {
+        match operand {
+            Operand::Reg(r) => self.stack[base + r],
+            Operand::Const(c) => self.register_code[func_id].constants[c],
+        }
+    }
+
+    fn global_name(&self, func_id: usize, c: usize) -> String {
+        let Value::Obj(s) = self.functions[func_id].chunk.constants[c] else {
+            self.error("Error variable name type!")
+        };
+        let Object::String(s) = &self.obj_list[s] else {
+            self.error("Expect String")
+        };
+        s.clone()
+    }
+
+    /// Sizes the stack for the registers of the innermost frame.
+    fn enter_frame(&mut self) {
+        let frame = self.frames.last().unwrap();
+        let size = frame.slot_index + self.register_code[frame.func_id].frame_size;
+        self.stack.resize(size, StackElem::default());
+    }
+
+    fn binary(&self, op: ByteCode, a: StackElem, b: StackElem) -> StackElem {
+        if let ByteCode::And | ByteCode::Or = op {
+            self.check_bool(&a, &b);
+        } else {
+            self.check_number(&a, &b);
+        }
+        let value = match op {
+            ByteCode::Add  => a.add(b),
+            ByteCode::Sub  => a.sub(b),
+            ByteCode::Mul  => a.mul(b),
+            ByteCode::Div  => a.div(b),
+            ByteCode::Mod  => a.rem(b),
+            ByteCode::Shl  => a.shl(b),
+            ByteCode::Shr  => a.shr(b),
+            ByteCode::LAnd => a.bitand(b),
+            ByteCode::LOr  => a.bitor(b),
+            ByteCode::LXor => a.bitxor(b),
+            ByteCode::And  => a.bool_and(b),
+            ByteCode::Or   => a.bool_or(b),
+            ByteCode::Eq   => StackElem::from(a == b),
+            ByteCode::Ne   => StackElem::from(a != b),
+            ByteCode::Lt   => StackElem::from(a < b),
+            ByteCode::Le   => StackElem::from(a <= b),
+            ByteCode::Gt   => StackElem::from(a > b),
+            ByteCode::Ge   => StackElem::from(a >= b),
+            _ => StackElem::default(),
+        };
+        if value.is_nil() {
+            self.error("Wrong object type for the operator !");
+        }
+        value
+    }
+
+    fn unary(&self, op: ByteCode, a: StackElem) -> StackElem {
+        let value = match op {
+            ByteCode::Neg  => { self.check_number(&a, &a); a.neg() },
+            ByteCode::Not  => { self.check_bool(&a, &a); a.bool_not() },
+            ByteCode::LNot => { self.check_number(&a, &a); a.bitnot() },
+            _ => StackElem::default(),
+        };
+        if value.is_nil() {
+            self.error("Wrong object type for the operator !");
+        }
+        value
+    }
+
+    fn condition(&self, value: StackElem) -> bool {
+        value.as_bool().unwrap_or_else(|| self.error("Expect bool on stack top!"))
+    }
+
+    /// `run` for `Backend::Register`.
+    pub fn run_registers(&mut self, steps: usize) -> Result<bool, InterpretError> {
+        if self.register_code.len() != self.functions.len() {
+            self.register_code = self.functions.iter().map(translate).collect();
+        }
+        self.enter_frame();
+        let mut step = 0;
+        loop {
+            let frame = self.frames.last().unwrap();
+            let (func_id, pc, base) = (frame.func_id, frame.ip, frame.slot_index);
+            let Some(&op) = self.register_code[func_id].code.get(pc) else {
+                return Ok(true);
+            };
+            if step == steps {
+                return Ok(false);
+            }
+            step += 1;
+            self.check_limits()?;
+            if self.debug {
+                println!("R{}\tL{}\t{}", pc, self.register_code[func_id].locs[pc], op.disassemble());
+            }
+            let mut next_pc = pc + 1;
+            match op {
+                RegOp::Move { dst, src } => self.stack[base + dst] = self.operand(base, func_id, src),
+                RegOp::Unary { op, dst, src } => {
+                    let a = self.operand(base, func_id, src);
+                    self.stack[base + dst] = self.unary(op, a);
+                },
+                RegOp::Binary { op, dst, lhs, rhs } => {
+                    let a = self.operand(base, func_id, lhs);
+                    let b = self.operand(base, func_id, rhs);
+                    self.stack[base + dst] = self.binary(op, a, b);
+                },
+                RegOp::LoadGlobal { dst, name } => {
+                    let name = self.global_name(func_id, name);
+                    let Some(value) = self.global.get(&name) else {
+                        self.error(&format!("Variable name '{}' is not defined!", name))
+                    };
+                    self.stack[base + dst] = StackElem::pack(*value);
+                },
+                RegOp::SetGlobal { name, src } => {
+                    let name = self.global_name(func_id, name);
+                    if !self.global.contains_key(&name) {
+                        self.error(&format!("Variable name '{}' is not defined!", name));
+                    }
+                    let value = self.operand(base, func_id, src).unpack();
+                    self.global.insert(name, value);
+                },
+                RegOp::DefGlobal { name, src } => {
+                    let name = self.global_name(func_id, name);
+                    if self.global.contains_key(&name) {
+                        self.error(&format!("Variable name '{}' is defined!", name));
+                    }
+                    let value = self.operand(base, func_id, src).unpack();
+                    self.global.insert(name, value);
+                },
+                RegOp::LoadNative { dst, name } => {
+                    let Value::Obj(s) = self.functions[func_id].chunk.constants[name] else {
+                        self.error("Error variable name type!")
+                    };
+                    if !self.native_functions.contains_key(&self.global_name(func_id, name)) {
+                        self.error(&format!("Variable name '{}' is not defined!", s));
+                    }
+                    self.stack[base + dst] = StackElem::pack(Value::NativeFunction(s));
+                },
+                RegOp::Out { src } => {
+                    let value = self.operand(base, func_id, src);
+                    if let Value::Obj(c) = value.unpack() {
+                        println!("[STDOUT] {}", self.obj_list[c].to_str());
+                    } else {
+                        println!("[STDOUT] {}", value.to_str());
+                    }
+                },
+                RegOp::J(target) => next_pc = target,
+                RegOp::Jz { cond, target } => {
+                    if !self.condition(self.operand(base, func_id, cond)) {
+                        next_pc = target;
+                    }
+                },
+                RegOp::Jnz { cond, target } => {
+                    if self.condition(self.operand(base, func_id, cond)) {
+                        next_pc = target;
+                    }
+                },
+                RegOp::Call { func, argc } => {
+                    let Value::Function(callee) = self.stack[base + func].unpack() else {
+                        self.error("Expect Function in stack");
+                    };
+                    self.check_arity(callee, argc)?;
+                    if self.frames.len() >= self.max_frames || self.stack.len() > self.max_stack {
+                        let name = &self.functions[callee].name;
+                        return Err(self.runtime_error(&format!("Stack overflow in function '{}'", name)));
+                    }
+                    self.frames.last_mut().unwrap().ip = next_pc;
+                    self.frames.push(CallFrame { func_id: callee, ip: 0, slot_index: base + func + 1, tail_calls: 0 });
+                    self.enter_frame();
+                    continue;
+                },
+                RegOp::TailCall { func, argc } => {
+                    let Value::Function(callee) = self.stack[base + func].unpack() else {
+                        self.error("Expect Function in stack");
+                    };
+                    self.check_arity(callee, argc)?;
+                    // the callee and its arguments take the place of the
+                    // current function and its locals
+                    self.stack.copy_within(base + func..=base + func + argc, base - 1);
+                    let frame = self.frames.last_mut().unwrap();
+                    frame.func_id = callee;
+                    frame.ip = 0;
+                    frame.tail_calls += 1;
+                    self.enter_frame();
+                    continue;
+                },
+                RegOp::CallNative { func, argc } => {
+                    let Value::NativeFunction(obj_id) = self.stack[base + func].unpack() else {
+                        self.error("Expect Function in stack");
+                    };
+                    let Object::String(func_name) = &self.obj_list[obj_id] else {
+                        self.error("Expect String in stack");
+                    };
+                    let native_fn = self.native_functions[func_name];
+                    // the stack machine pops the arguments, last one first
+                    let args = (1..=argc).map(|n| self.stack[base + func - n].unpack()).collect();
+                    let val = match native_fn(&mut self.obj_list, argc, args) {
+                        Ok(val) => val,
+                        Err(msg) => return Err(self.runtime_error(&msg)),
+                    };
+                    self.stack[base + func - argc] = StackElem::pack(val);
+                },
+                RegOp::Ret { src } => {
+                    let value = self.operand(base, func_id, src);
+                    if self.frames.len() == 1 {
+                        return Ok(true);
+                    }
+                    self.stack[base - 1] = value;
+                    self.frames.pop();
+                    self.enter_frame();
+                    continue;
+                },
+                RegOp::Hlt => return Ok(true),
+            }
+            self.frames.last_mut().unwrap().ip = next_pc;
+        }
+    }
+}
@@ -1,6 +1,8 @@
 use core::prelude;
 use std::{fs::File, error::Error, io::Read};
 
+use crate::ast::Span;
+
 
 #[derive(Clone, Default, Debug)]
 pub enum Token {
@@ -45,21 +47,33 @@ pub struct Scanner {
     pub code: String,
     ptr: usize,
     line: usize,
+    /// `ptr` of the first character of the current line.
+    line_start: usize,
 }
 
 #[derive(Default, Debug, Clone)]
 pub struct TokenWithInfo {
     pub token: Token,
     pub line: usize,
-    pub level: usize
+    /// Columns of the first and the last character, 0 for the tokens made
+    /// up by `post_process`.
+    pub column: usize,
+    pub end_column: usize,
+}
+
+impl TokenWithInfo {
+    pub fn span(&self) -> Span {
+        Span::new(self.line, self.column, self.line, self.end_column)
+    }
 }
 
 
 impl Scanner {
     
     pub fn scan(&mut self) -> Vec<TokenWithInfo> {
-        let mut token_seq: Vec<(Token, usize)> = Vec::new();
+        let mut token_seq: Vec<(Token, usize, usize, usize)> = Vec::new();
         while !self.is_finished() {
+            let (start, line_start) = (self.ptr, self.line_start);
             // println!("{} {}", self.ptr, self.cur_char());
             let ch = self.cur_char();
             let mut next_flag = true;
@@ -129,39 +143,39 @@ impl Scanner {
                     }
                 },
             };
-            token_seq.push((tok, self.line));
             if next_flag {
                 self.next();
             }
+            token_seq.push((tok, self.line, start - line_start + 1, self.ptr - line_start));
         }
         let line = if token_seq.is_empty() {0usize} else {token_seq.last().unwrap().1};
-        token_seq.push((Token::Eof, line));
+        token_seq.push((Token::Eof, line, 0, 0));
         self.post_process(&mut token_seq)
     }
 
-    fn post_process(&mut self, token_seq: &mut Vec<(Token, usize)>) -> Vec<TokenWithInfo> {
+    fn post_process(&mut self, token_seq: &mut Vec<(Token, usize, usize, usize)>) -> Vec<TokenWithInfo> {
         let mut result: Vec<TokenWithInfo> = Vec::new();
         let mut pre_line = 0usize;
         let mut indents: Vec<isize> = vec![0];
         let token_seq: Vec<_> = token_seq
                         .iter()
                         .enumerate()
-                        .filter(|(i, (tok, line))| {
+                        .filter(|(i, (tok, line, _, _))| {
                             if *i <= 1 || *i >= token_seq.len() - 2 {true}
                             else {!(matches!(tok, Token::Space(_)) && *line != token_seq[*i+1].1) }
                         })
                         .map(|(_, k)| k)
-                        .filter(|(token, _)| !matches!(token, Token::Empty))
+                        .filter(|(token, _, _, _)| !matches!(token, Token::Empty))
                         .collect();
 
-        for (i, (token, line)) in token_seq.iter().enumerate() {
+        for (token, line, column, end_column) in token_seq.iter() {
             if let Token::Empty = token {
                 continue
             }
             let mut has_space = false;
             // let pre_token = result.last().unwrap().token.clone();
             if !result.is_empty() && *line != pre_line && !matches!(result.last().unwrap().token.clone(), Token::NewLine) {
-                result.push(TokenWithInfo {token: Token::NewLine, line: pre_line + 1, level: 0});
+                result.push(TokenWithInfo {token: Token::NewLine, line: pre_line + 1, column: 0, end_column: 0});
                 has_space = true;
             }
             pre_line = *line;
@@ -173,13 +187,12 @@ impl Scanner {
                 // let Token::Space(space) = token
                 if (space as isize) > (*indents.last().unwrap()) {
                     indents.push(space as isize);
-                    result.push(TokenWithInfo { token: Token::BeginBlock, line: *line + 1, level: 0 });
+                    result.push(TokenWithInfo { token: Token::BeginBlock, line: *line + 1, column: 0, end_column: 0 });
                 } else if (space as isize) < (*indents.last().unwrap()) {
-                    let mut cnt: i32 = 0;
                     while (space as isize) < (*indents.last().unwrap()) {
                         indents.pop();
-                        result.push(TokenWithInfo { token: Token::EndBlock, line: *line, level: 0 });
-                        result.push(TokenWithInfo { token: Token::NewLine,  line: *line, level: 0 });
+                        result.push(TokenWithInfo { token: Token::EndBlock, line: *line, column: 0, end_column: 0 });
+                        result.push(TokenWithInfo { token: Token::NewLine,  line: *line, column: 0, end_column: 0 });
                     }
                     if (space as isize) > (*indents.last().unwrap()) {
                         panic!("Wrong indent at line {}", *line + 1);
@@ -191,13 +204,14 @@ impl Scanner {
                 result.push(TokenWithInfo {
                     token: token.clone(),
                     line: *line + 1,
-                    level: 0
+                    column: *column,
+                    end_column: *end_column,
                 });
             }
         }
 
         // println!("Final Tok Seq");
-        // for TokenWithInfo { token, line, level: _ } in &mut result.iter() {
+        // for TokenWithInfo { token, line, .. } in &mut result.iter() {
         //     println!("{}\t{:?}", line, token);
         // }
         // println!();
@@ -310,7 +324,7 @@ impl Scanner {
                 break;
             }
             match self.cur_char() {
-                '\n' => { new_line = true; space = 0; self.line += 1; },
+                '\n' => { new_line = true; space = 0; self.line += 1; self.line_start = self.ptr + 1; },
                 ' ' => if new_line {space += 1},
                 '\t' => if new_line {space += 4},
                 _ => (),
@@ -328,7 +342,7 @@ impl Scanner {
                 while !self.is_finished() {
                     self.next();
                     let ch = self.cur_char();
-                    if ch == '\n' { self.line += 1; return; }
+                    if ch == '\n' { self.line += 1; self.line_start = self.ptr + 1; return; }
                 }
             },
             '*' => {
@@ -336,7 +350,7 @@ impl Scanner {
                     self.next();
                     let ch = self.cur_char();
                     if ch == '*'  { if let Some('/') = self.peek() { self.next(); return; }}
-                    else if ch == '\n' {self.line += 1;}
+                    else if ch == '\n' { self.line += 1; self.line_start = self.ptr + 1; }
                 }
             },
             _ => panic!("Error Comment"),
@@ -347,8 +361,13 @@ impl Scanner {
         let mut f = File::open(path)?;
         let mut code = String::new();
         _ = f.read_to_string(&mut code)?;
+        Ok(Self::from_source(&code))
+    }
+
+    pub fn from_source(source: &str) -> Self {
+        let mut code = String::from(source);
         code.push('\n');
-        Ok(Self { code, ..Default::default() })
+        Self { code, ..Default::default() }
     }
 
     fn is_finished(&self) -> bool {
@@ -0,0 +1,133 @@
+use std::fmt;
+
+
+/// A position in the source: the line and the columns on it, both 1-based.
+/// Column 0 means the column is not known.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Loc {
+    pub line: usize,
+    pub column: usize,
+    pub end_column: usize,
+}
+
+impl Loc {
+    pub fn new(line: usize, column: usize, end_column: usize) -> Self {
+        Loc { line, column, end_column }
+    }
+}
+
+/// Code emitted without a column, e.g. by hand written bytecode.
+impl From<usize> for Loc {
+    fn from(line: usize) -> Self {
+        Loc { line, column: 0, end_column: 0 }
+    }
+}
+
+/// `3`, `3:5` or `3:5-9`, the form used by the assembly listing.
+impl fmt::Display for Loc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut text = self.line.to_string();
+        if (self.column, self.end_column) != (0, 0) {
+            text += &format!(":{}", self.column);
+            if self.end_column != self.column {
+                text += &format!("-{}", self.end_column);
+            }
+        }
+        f.pad(&text)
+    }
+}
+
+impl std::str::FromStr for Loc {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let number = |s: &str| s.parse::<usize>().map_err(|_| format!("Bad source location '{}'", s));
+        let (line, columns) = s.split_once(':').unwrap_or((s, ""));
+        let mut loc = Loc::from(number(line)?);
+        if !columns.is_empty() {
+            let (column, end_column) = columns.split_once('-').unwrap_or((columns, columns));
+            loc.column = number(column)?;
+            loc.end_column = number(end_column)?;
+        }
+        Ok(loc)
+    }
+}
+
+
+/// Where each byte of a chunk comes from, stored as runs of bytes sharing
+/// a location. `file` is the script the chunk was compiled from.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct SourceMap {
+    pub file: String,
+    /// `(first byte, location)`, ordered by the first byte.
+    runs: Vec<(usize, Loc)>,
+    len: usize,
+}
+
+impl SourceMap {
+    /// Appends `count` bytes at `loc`.
+    pub fn push(&mut self, loc: Loc, count: usize) {
+        if count == 0 {
+            return;
+        }
+        if self.runs.last().is_none_or(|(_, last)| *last != loc) {
+            self.runs.push((self.len, loc));
+        }
+        self.len += count;
+    }
+
+    /// The location of the byte at `offset`.
+    pub fn get(&self, offset: usize) -> Option<Loc> {
+        if offset >= self.len {
+            return None;
+        }
+        let run = self.runs.partition_point(|(start, _)| *start <= offset) - 1;
+        Some(self.runs[run].1)
+    }
+
+    /// Number of bytes covered.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.runs.clear();
+        self.len = 0;
+    }
+
+    /// `(location, number of bytes)` for each run.
+    pub fn runs(&self) -> impl Iterator<Item = (Loc, usize)> + '_ {
+        self.runs.iter().enumerate().map(|(i, (start, loc))| {
+            let end = self.runs.get(i + 1).map_or(self.len, |(next, _)| *next);
+            (*loc, end - start)
+        })
+    }
+
+    /// The location of the byte at `offset`, or of the last byte when
+    /// `offset` is past the end.
+    pub fn get_or_last(&self, offset: usize) -> Loc {
+        if self.is_empty() {
+            return Loc::default();
+        }
+        self.get(offset.min(self.len - 1)).unwrap()
+    }
+
+    /// `line N, column C` of `loc`, with the file if known.
+    pub fn describe(&self, loc: Loc) -> String {
+        let mut text = format!("line {}", loc.line);
+        if loc.column > 0 {
+            text += &format!(", column {}", loc.column);
+            if loc.end_column > loc.column {
+                text += &format!("-{}", loc.end_column);
+            }
+        }
+        if !self.file.is_empty() {
+            text += &format!(" of {}", self.file);
+        }
+        text
+    }
+}
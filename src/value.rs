@@ -40,6 +40,18 @@ impl Value {
         }
     }
 
+    pub fn is_nil(&self) -> bool {
+        matches!(self, Value::Nil)
+    }
+
+    pub fn is_number(&self) -> bool {
+        matches!(self, Value::Int(_) | Value::Float(_))
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        if let Value::Bool(c) = self { Some(*c) } else { None }
+    }
+
     pub fn to_str_detail(&self, obj_list: &Vec<Object>) -> String {
         match self {
             Value::Obj(c) => obj_list[*c].to_str(),
@@ -78,6 +90,19 @@ impl Value {
     }
 }
 
+// Without the `nan_boxing` feature `Value` is itself the VM stack element;
+// these keep the VM code identical for both representations.
+#[cfg(not(feature = "nan_boxing"))]
+impl Value {
+    pub fn pack(value: Value) -> Self {
+        value
+    }
+
+    pub fn unpack(self) -> Value {
+        self
+    }
+}
+
 macro_rules! impl_binary_op_for_constant {
     ($clz:ident, $op:ident) => {
         impl $clz for Value {
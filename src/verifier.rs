@@ -0,0 +1,263 @@
+use std::fmt;
+
+use crate::bytecode::{ByteCode, Chunk};
+use crate::heap::Heap;
+use crate::native_functions::Native;
+use crate::object::{Function, Object};
+use crate::value::Value;
+
+
+/// Why a function was rejected, `index` counts instructions from the start
+/// of the function and `offset` is the byte offset shown by the disassembly.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifyError {
+    pub function: String,
+    pub index: usize,
+    pub offset: usize,
+    pub msg: String,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[Verify Error] {} at instruction {} (I{}) in function '{}'",
+               self.msg, self.index, self.offset, self.function)
+    }
+}
+
+/// Checks that running `functions` cannot index out of range: every
+/// instruction decodes, jumps land on instructions, constants, globals and
+/// functions and natives exist, locals are inside the frame, only functions
+/// return, and the stack has the same depth whichever way an instruction is
+/// reached. Argument counts are checked when the call runs.
+pub fn verify(functions: &[Function], obj_list: &Heap, natives: &Native) -> Result<(), VerifyError> {
+    for (func_id, func) in functions.iter().enumerate() {
+        Verifier { func_id, func, functions, obj_list, natives }.verify()?;
+    }
+    Ok(())
+}
+
+/// `(offset, instruction, stack depth)` of each instruction. The depth counts
+/// the values of the frame before the instruction runs and is `None` where
+/// the instruction cannot be reached.
+pub type StackDepths = Vec<(usize, ByteCode, Option<usize>)>;
+
+/// Verifies function `func_id` and lists the stack depth at each of its
+/// instructions.
+pub fn stack_depths(func_id: usize, functions: &[Function], obj_list: &Heap, natives: &Native)
+    -> Result<StackDepths, VerifyError> {
+    Verifier { func_id, func: &functions[func_id], functions, obj_list, natives }.verify()
+}
+
+struct Verifier<'a> {
+    func_id: usize,
+    func: &'a Function,
+    functions: &'a [Function],
+    obj_list: &'a Heap,
+    natives: &'a Native,
+}
+
+impl Verifier<'_> {
+    fn verify(&self) -> Result<StackDepths, VerifyError> {
+        let chunk = &self.func.chunk;
+        if chunk.source_map.len() != chunk.code.len() {
+            return Err(self.error(0, 0, "Source map does not match the code"));
+        }
+        let code = self.decode()?;
+        let index_of = |target: usize| code.binary_search_by_key(&target, |(offset, _)| *offset).ok();
+
+        let mut depth: Vec<Option<usize>> = vec![None; code.len()];
+        let mut worklist = vec![];
+        if !code.is_empty() {
+            depth[0] = Some(self.func.arity.max(0) as usize);
+            worklist.push(0);
+        }
+        while let Some(i) = worklist.pop() {
+            let (offset, ins) = code[i];
+            let before = depth[i].unwrap();
+            let (pops, pushes) = self.check(chunk, &ins, before)
+                .map_err(|msg| self.error(i, offset, &msg))?;
+            if before < pops {
+                return Err(self.error(i, offset, "Stack underflow"));
+            }
+            let after = before - pops + pushes;
+            let mut successors = vec![];
+            if let Some(target) = ins.jump_target() {
+                let Some(target) = index_of(target) else {
+                    return Err(self.error(i, offset, &format!("Jump target {} is not an instruction", target)));
+                };
+                successors.push(target);
+            }
+            if !matches!(ins, ByteCode::J(_) | ByteCode::Ret | ByteCode::Hlt | ByteCode::TailCall(_)) {
+                if i + 1 == code.len() {
+                    return Err(self.error(i, offset, "Execution falls off the end of the function"));
+                }
+                successors.push(i + 1);
+            }
+            for next in successors {
+                match depth[next] {
+                    None => {
+                        depth[next] = Some(after);
+                        worklist.push(next);
+                    },
+                    Some(d) if d != after => {
+                        return Err(self.error(next, code[next].0,
+                            &format!("Stack depth {} does not match depth {} of another path", after, d)));
+                    },
+                    Some(_) => (),
+                }
+            }
+        }
+        Ok(code.into_iter().zip(depth).map(|((offset, ins), depth)| (offset, ins, depth)).collect())
+    }
+
+    fn decode(&self) -> Result<Vec<(usize, ByteCode)>, VerifyError> {
+        let chunk = &self.func.chunk;
+        let mut code = vec![];
+        let mut offset = 0;
+        while offset < chunk.code.len() {
+            let (ins, next) = ByteCode::try_decode(&chunk.code, offset)
+                .map_err(|msg| self.error(code.len(), offset, &msg))?;
+            code.push((offset, ins));
+            offset = next;
+        }
+        Ok(code)
+    }
+
+    /// Checks the operands of `ins` when `depth` values are on the frame.
+    /// :returns: (values popped, values pushed)
+    fn check(&self, chunk: &Chunk, ins: &ByteCode, depth: usize) -> Result<(usize, usize), String> {
+        let local = |slot: usize, depth: usize| if slot < depth {
+            Ok(())
+        } else {
+            Err(format!("Local slot {} outside of the {} values of the frame", slot, depth))
+        };
+        Ok(match *ins {
+            ByteCode::Value(c) => { self.constant(chunk, c)?; (0, 1) },
+            ByteCode::True | ByteCode::False | ByteCode::Nil =>
+                return Err(format!("Unsupported instruction `{}`", ins.disassemble())),
+            ByteCode::Add | ByteCode::Sub | ByteCode::Mul | ByteCode::Div | ByteCode::Mod |
+            ByteCode::Shr | ByteCode::Shl | ByteCode::LAnd | ByteCode::LOr | ByteCode::LXor |
+            ByteCode::And | ByteCode::Or |
+            ByteCode::Eq | ByteCode::Ne | ByteCode::Lt | ByteCode::Le | ByteCode::Gt | ByteCode::Ge => (2, 1),
+            ByteCode::Neg | ByteCode::Not | ByteCode::LNot => (1, 1),
+            ByteCode::Out | ByteCode::JzKeep(_) | ByteCode::JnzKeep(_) => (1, 1),
+            ByteCode::Pop | ByteCode::Jz(_) | ByteCode::Jnz(_) => (1, 0),
+            ByteCode::PopN(n) => (n, 0),
+            ByteCode::J(_) | ByteCode::Nop | ByteCode::Hlt => (0, 0),
+            ByteCode::DefGlobal(c) => { self.name(chunk, c)?; (1, 0) },
+            ByteCode::Set(c) => { self.name(chunk, c)?; (1, 1) },
+            ByteCode::Load(c) => { self.name(chunk, c)?; (0, 1) },
+            ByteCode::LoadNative(c) => { self.native(chunk, c)?; (0, 1) },
+            ByteCode::LoadLocal(l) => { local(l, depth)?; (0, 1) },
+            ByteCode::SetLocal(l) => { local(l, depth)?; (1, 1) },
+            ByteCode::Call(argc) | ByteCode::CallNative(argc) => (argc + 1, 1),
+            // the script itself ends with `Hlt`, there is no caller to return to
+            ByteCode::TailCall(_) | ByteCode::Ret if self.func_id == 0 => {
+                return Err(String::from("Return outside of a function"));
+            },
+            ByteCode::TailCall(argc) => (argc + 1, 0),
+            ByteCode::Ret => (1, 0),
+            ByteCode::AddLocalConst(l, c) => { local(l, depth)?; self.constant(chunk, c)?; (0, 1) },
+            ByteCode::AddConstSetLocal(c, l) => {
+                self.constant(chunk, c)?;
+                local(l, depth.saturating_sub(1))?;
+                (1, 0)
+            },
+            ByteCode::LtLocalsJz(a, b, _) => { local(a, depth)?; local(b, depth)?; (0, 0) },
+        })
+    }
+
+    /// An entry of the function's own constant pool.
+    fn constant(&self, chunk: &Chunk, c: usize) -> Result<(), String> {
+        match chunk.constants.get(c) {
+            None => Err(format!("Constant {} out of range", c)),
+            Some(Value::Function(id)) if *id >= self.functions.len() =>
+                Err(format!("Function {} out of range", id)),
+            Some(Value::Obj(obj)) if *obj >= self.obj_list.len() =>
+                Err(format!("Object {} out of range", obj)),
+            Some(_) => Ok(()),
+        }
+    }
+
+    /// A global or native name, a string in the function's constant pool.
+    fn name(&self, chunk: &Chunk, c: usize) -> Result<&str, String> {
+        match chunk.constants.get(c) {
+            None => Err(format!("Name {} out of range", c)),
+            Some(Value::Obj(obj)) => match self.obj_list.get(*obj) {
+                Some(Object::String(name)) => Ok(name),
+                _ => Err(format!("Name {} is not a string", c)),
+            },
+            Some(_) => Err(format!("Name {} is not a string", c)),
+        }
+    }
+
+    fn native(&self, chunk: &Chunk, c: usize) -> Result<(), String> {
+        let name = self.name(chunk, c)?;
+        if !self.natives.contains_key(name) {
+            return Err(format!("Unknown native '{}'", name));
+        }
+        Ok(())
+    }
+
+    fn error(&self, index: usize, offset: usize, msg: &str) -> VerifyError {
+        VerifyError { function: self.func.name.clone(), index, offset, msg: String::from(msg) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn function(name: &str, arity: i64, code: &[ByteCode], constants: &[Value]) -> Function {
+        let mut chunk = Chunk::new();
+        for &value in constants {
+            chunk.add_constant(value);
+        }
+        for &ins in code {
+            chunk.add(ins, 1);
+        }
+        Function { arity, chunk, name: String::from(name) }
+    }
+
+    /// Objects 0 and 1 are the names `$list` and `$nope`.
+    fn verify_main(code: &[ByteCode], constants: &[Value]) -> Result<(), VerifyError> {
+        let functions = [function("$main", 0, code, constants), function("f", 0, &[ByteCode::Value(0), ByteCode::Ret], &[Value::Nil])];
+        let mut obj_list = Heap::new();
+        obj_list.insert(Object::String(String::from("$list")));
+        obj_list.insert(Object::String(String::from("$nope")));
+        verify(&functions, &obj_list, &Native::new())
+    }
+
+    #[test]
+    fn rejects_instructions_no_backend_runs() {
+        for ins in [ByteCode::Nil, ByteCode::True, ByteCode::False] {
+            let err = verify_main(&[ins, ByteCode::Hlt], &[]).unwrap_err();
+            assert_eq!(err.msg, format!("Unsupported instruction `{}`", ins.disassemble()));
+        }
+    }
+
+    #[test]
+    fn rejects_return_from_the_script() {
+        let err = verify_main(&[ByteCode::Value(0), ByteCode::Ret], &[Value::Int(1)]).unwrap_err();
+        assert_eq!((err.function.as_str(), err.msg.as_str()), ("$main", "Return outside of a function"));
+        let err = verify_main(&[ByteCode::Value(0), ByteCode::TailCall(0)], &[Value::Function(1)]).unwrap_err();
+        assert_eq!(err.msg, "Return outside of a function");
+        assert_eq!(verify_main(&[ByteCode::Value(0), ByteCode::Call(0), ByteCode::Pop, ByteCode::Hlt], &[Value::Function(1)]), Ok(()));
+    }
+
+    #[test]
+    fn rejects_unknown_natives() {
+        let names = [Value::Obj(0), Value::Obj(1)];
+        assert_eq!(verify_main(&[ByteCode::LoadNative(0), ByteCode::Pop, ByteCode::Hlt], &names), Ok(()));
+        let err = verify_main(&[ByteCode::LoadNative(1), ByteCode::Pop, ByteCode::Hlt], &names).unwrap_err();
+        assert_eq!(err.msg, "Unknown native '$nope'");
+        let err = verify_main(&[ByteCode::LoadNative(2), ByteCode::Pop, ByteCode::Hlt], &names).unwrap_err();
+        assert_eq!(err.msg, "Name 2 out of range");
+    }
+
+    #[test]
+    fn accepts_compiled_scripts() {
+        let codegen = crate::compile_source("let x\nfunc f(a):\n    let y\n    if a:\n        return y\nprint(x)\nprint(f(true))\nprint([1, 2][1])\n");
+        assert_eq!(verify(&codegen.functions, &codegen.obj_list, &codegen.native_functions), Ok(()));
+    }
+}
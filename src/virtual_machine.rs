@@ -1,4 +1,6 @@
 use std::collections::HashMap;
+use std::fmt;
+use std::time::Instant;
 use std::fs::File;
 use std::ops::{Add, Sub, Mul, Div, Neg, Rem, Shl, BitAnd, BitXor, BitOr, Shr};
 use std::path::Components;
@@ -6,9 +8,13 @@ use std::vec;
 use std::io::{self, Write};
 
 use crate::bytecode::*;
-use crate::helper::ToObject;
+use crate::heap::{Heap, DEFAULT_HEAP_LIMIT};
 use crate::native_functions::Native;
-use crate::parser::Parser;
+use crate::codegen::CodeGen;
+use crate::register::RegisterFunction;
+use crate::closure::ClosureFunction;
+use crate::verifier::{self, VerifyError};
+use crate::assembler;
 use crate::value::*;
 use crate::object::*;
 
@@ -18,18 +24,57 @@ pub struct CallFrame {
     pub func_id: usize,
     pub ip: usize,
     pub slot_index: usize,
+    /// Number of frames that tail calls have replaced by this one.
+    pub tail_calls: usize,
 }
 
 
+#[derive(Debug)]
 pub enum InterpretError {
-    RuntimeError,
-    CompileError
+    RuntimeError(String),
+    /// The bytecode was rejected before running, see `verifier`.
+    InvalidBytecode(VerifyError),
+    /// `instruction_limit` was reached.
+    BudgetExhausted,
+    /// `deadline` has passed.
+    Timeout,
 }
 
+impl fmt::Display for InterpretError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InterpretError::RuntimeError(msg) => write!(f, "{}", msg),
+            InterpretError::InvalidBytecode(err) => write!(f, "{}", err),
+            InterpretError::BudgetExhausted => write!(f, "Runtime Error: instruction budget exhausted"),
+            InterpretError::Timeout => write!(f, "Runtime Error: execution timed out"),
+        }
+    }
+}
+
+pub const DEFAULT_MAX_FRAMES: usize = 1024;
+pub const DEFAULT_MAX_STACK: usize = DEFAULT_MAX_FRAMES * 256;
+/// Number of instructions between two reads of the clock.
+const DEADLINE_CHECK_INTERVAL: usize = 1024;
+
 pub type InterpretResult = Result<(), InterpretError>;
+#[cfg(not(feature = "nan_boxing"))]
 pub type StackElem = Value;
+#[cfg(feature = "nan_boxing")]
+pub type StackElem = crate::nan_boxing::PackedValue;
 pub type Stack = Vec<StackElem>;
 
+/// Instruction set the VM executes.
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    /// The compiled bytecode.
+    #[default]
+    Stack,
+    /// Three-address code translated from the bytecode, see `register`.
+    Register,
+    /// The bytecode compiled to closures, see `closure`.
+    Closure,
+}
+
 #[derive(Default, Debug)]
 pub struct VirtualMachine {
     pub functions: Vec<Function>,
@@ -39,17 +84,35 @@ pub struct VirtualMachine {
     // pub static_table: Vec<dyn DObject>
     // pub panic_mode: bool,
     pub global: HashMap<String, Value>,
-    pub constants: Vec<Value>,
-    pub obj_list: Vec<Object>,
-    pub native_functions: Native
+    /// Objects of the script; `obj_list.limit` defaults to `DEFAULT_HEAP_LIMIT`.
+    pub obj_list: Heap,
+    pub native_functions: Native,
+    /// Maximum number of nested call frames.
+    pub max_frames: usize,
+    /// Maximum number of values on the stack when entering a function.
+    pub max_stack: usize,
+    /// Total number of instructions `interpret` may execute.
+    pub instruction_limit: Option<usize>,
+    pub instruction_count: usize,
+    pub deadline: Option<Instant>,
+    pub backend: Backend,
+    /// `functions` translated for `Backend::Register`, filled on first run.
+    pub register_code: Vec<RegisterFunction>,
+    /// `functions` compiled for `Backend::Closure`, filled on first run.
+    pub closure_code: Vec<ClosureFunction>,
+    /// Machine code of hot functions, see `jit`.
+    #[cfg(feature = "jit")]
+    pub jit: crate::jit::Jit,
+    /// Whether `functions` passed the verifier.
+    verified: bool,
 }
 
 macro_rules! apply_op_unary {
     ($this:ident, $check:ident, $func:ident) => {{
         $this.$check($this.peek(0), $this.peek(0)); 
         let a = $this.pop();
-        let value = Value::from(a.$func());
-        if let Value::Nil = value {
+        let value = StackElem::from(a.$func());
+        if value.is_nil() {
             $this.error("Wrong object type for the operator !");
         }
         $this.push(value);
@@ -61,8 +124,8 @@ macro_rules! apply_op {
         $this.$check($this.peek(0), $this.peek(1)); 
         let a = $this.pop();
         let b = $this.pop(); 
-        let value = Value::from(b.$func(a));
-        if let Value::Nil = value {
+        let value = StackElem::from(b.$func(a));
+        if value.is_nil() {
             $this.error("Wrong object type for the operator !");
         }
         $this.push(value);
@@ -74,8 +137,8 @@ macro_rules! apply_op_cmp {
         $this.check_number($this.peek(0), $this.peek(1)); 
         let a = &$this.pop();
         let b = &$this.pop();
-        let value = Value::from(b.$func(a));
-        if let Value::Nil = value {
+        let value = StackElem::from(b.$func(a));
+        if value.is_nil() {
             $this.error("Wrong object type for the operator!");
         }
         $this.push(value);
@@ -85,16 +148,34 @@ macro_rules! apply_op_cmp {
 
 impl VirtualMachine {
 
-    pub fn from_parser(parser: &Parser) -> Self {
+    pub fn from_codegen(codegen: &CodeGen) -> Self {
+        Self::from_parts(codegen.functions.clone(), codegen.obj_list.clone(), codegen.native_functions.clone())
+    }
+
+    /// A VM about to run `functions[0]`, e.g. of a module loaded from disk.
+    pub fn from_parts(functions: Vec<Function>, obj_list: Heap, native_functions: Native) -> Self {
         let frame = CallFrame {
             func_id: 0,
             ip: 0,
             slot_index: 0,
+            tail_calls: 0,
         };
+        let mut obj_list = obj_list;
+        obj_list.limit = Some(DEFAULT_HEAP_LIMIT);
         Self { stack: Vec::new(), debug: true,
-               global: HashMap::new(), constants: vec![] , 
-               functions: parser.functions.clone(), frames: vec![frame],
-               obj_list: parser.obj_list.clone(), native_functions: parser.native_functions.clone() }
+               global: HashMap::new(),
+               functions, frames: vec![frame],
+               obj_list, native_functions,
+               max_frames: DEFAULT_MAX_FRAMES, max_stack: DEFAULT_MAX_STACK,
+               instruction_limit: None, instruction_count: 0, deadline: None,
+               backend: Backend::Stack, register_code: vec![], closure_code: vec![],
+               #[cfg(feature = "jit")]
+               jit: Default::default(),
+               verified: false }
+    }
+
+    pub fn with_backend(codegen: &CodeGen, backend: Backend) -> Self {
+        Self { backend, ..Self::from_codegen(codegen) }
     }
 
     pub fn push(&mut self, s: StackElem) {
@@ -114,22 +195,15 @@ impl VirtualMachine {
         &self.stack[self.stack.len() - 1 - i]
     }
 
-    fn check_number(&self, c1: &Value, c2: &Value) {
-        match (c1, c2) {
-            (Value::Int(_), Value::Int(_))     |
-            (Value::Int(_), Value::Float(_))   |
-            (Value::Float(_), Value::Int(_))   |
-            (Value::Float(_), Value::Float(_)) => (),
-            _ => {
-                self.print_stack(); self.error("The type to be operated shoule be Number")
-            }
+    pub fn check_number(&self, c1: &StackElem, c2: &StackElem) {
+        if !(c1.is_number() && c2.is_number()) {
+            self.print_stack(); self.error("The type to be operated shoule be Number")
         }
     }
 
-    fn check_bool(&self, c1: &Value, c2: &Value) {
-        match (c1, c2) {
-            (Value::Bool(_), Value::Bool(_))=> (),
-            _ => self.error("The type to be operated shoule be Boolean")
+    pub fn check_bool(&self, c1: &StackElem, c2: &StackElem) {
+        if c1.as_bool().is_none() || c2.as_bool().is_none() {
+            self.error("The type to be operated shoule be Boolean")
         }
     }
 
@@ -160,21 +234,61 @@ impl VirtualMachine {
 
 
     pub fn interpret(&mut self) -> InterpretResult {
+        self.interpret_steps(usize::MAX).map(|_| ())
+    }
+
+    /// Executes at most `steps` instructions so that the host can time-slice
+    /// the script. Returns `Ok(true)` once the script has finished.
+    pub fn interpret_steps(&mut self, steps: usize) -> Result<bool, InterpretError> {
+        self.run(steps)
+    }
+
+    /// Counts one instruction against `instruction_limit` and `deadline`.
+    /// Limits are checked before the instruction runs, so that the VM can be
+    /// resumed after raising them.
+    pub fn check_limits(&mut self) -> Result<(), InterpretError> {
+        if self.instruction_limit.is_some_and(|limit| self.instruction_count >= limit) {
+            return Err(InterpretError::BudgetExhausted);
+        }
+        if self.instruction_count.is_multiple_of(DEADLINE_CHECK_INTERVAL)
+            && self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(InterpretError::Timeout);
+        }
+        self.instruction_count += 1;
+        Ok(())
+    }
+
+    fn run(&mut self, steps: usize) -> Result<bool, InterpretError> {
+        if !self.verified {
+            verifier::verify(&self.functions, &self.obj_list, &self.native_functions)
+                .map_err(InterpretError::InvalidBytecode)?;
+            self.verified = true;
+        }
+        match self.backend {
+            Backend::Register => return self.run_registers(steps),
+            Backend::Closure => return self.run_closures(steps),
+            Backend::Stack => (),
+        }
+        let mut step = 0;
         loop {
             if self.get_ip() >= self.current_chunk().len() {
-                return Ok(());
+                return Ok(true);
             }
-            let ins = self.current_chunk().code[self.get_ip()].clone();
-            let lineno = self.current_chunk().lines[self.get_ip()];
+            if step == steps {
+                return Ok(false);
+            }
+            step += 1;
+            self.check_limits()?;
+            let (ins, mut next_ip) = self.current_chunk().decode(self.get_ip());
             if self.debug {
+                let loc = self.current_chunk().loc(self.get_ip());
                 let mut asm = String::new();
-                asm += &format!("I{}\t", self.get_ip().to_string());
-                asm += &format!("L{}\t", lineno.to_string());
-                asm += &ins.disassemble_detail(&self.obj_list);
+                asm += &format!("I{}\t", self.get_ip());
+                asm += &format!("L{}\t", loc);
+                asm += &self.current_chunk().disassemble_detail(&ins, &self.obj_list);
                 println!("{}", asm);
             }
             // println!("RUN {}", ins.disassemble());
-            let mut next_ip = self.get_ip() + 1;
             // ins.disassemble();
             match ins {
                 ByteCode::Add  => apply_op!(self, check_number, add),
@@ -200,15 +314,22 @@ impl VirtualMachine {
                 ByteCode::LNot => apply_op_unary!(self, check_number, bitnot),
 
                 ByteCode::Out => { 
-                    if let Value::Obj(c) = self.top() {
-                        println!("[STDOUT] {}", self.obj_list[*c].to_str()); 
+                    if let Value::Obj(c) = self.top().unpack() {
+                        println!("[STDOUT] {}", self.obj_list[c].to_str()); 
                     } else {
                         println!("[STDOUT] {}", self.top().to_str()); 
                     }                    
                 },
-                ByteCode::Value(c) => self.push(c.clone()),
-                ByteCode::Hlt =>  return Ok(()),
+                ByteCode::Value(c) => {
+                    let value = self.current_chunk().constants[c];
+                    self.push(StackElem::pack(value));
+                },
+                ByteCode::Hlt =>  return Ok(true),
                 ByteCode::Pop => {self.pop(); /*self.print_stack();*/},
+                ByteCode::PopN(n) => {
+                    let len = self.stack.len();
+                    self.stack.truncate(len - n);
+                },
                 ByteCode::J(n) => {
                     next_ip = n;
                     // println!("GLobal {:?}", self.global);
@@ -216,30 +337,44 @@ impl VirtualMachine {
                     // io::stdin().read_line(&mut s);
                 },
                 ByteCode::Nop => (),
-                ByteCode::JZ(n) => { 
-                    if let Value::Bool(b) = self.peek(0) {
-                        if !*b { next_ip = n; }
+                ByteCode::Jz(n) => { 
+                    if let Some(b) = self.peek(0).as_bool() {
+                        if !b { next_ip = n; }
                         self.pop();
                     } else {
                         self.error("Expect bool on stack top!");
                     }
                 },
-                ByteCode::JNZ(n) => { 
-                    if let Value::Bool(b) = self.peek(0) {
-                        if *b { next_ip = n; }
+                ByteCode::JzKeep(n) => { 
+                    if let Some(b) = self.peek(0).as_bool() {
+                        if !b { next_ip = n; }
+                    } else {
+                        self.error("Expect bool on stack top!");
+                    }
+                },
+                ByteCode::JnzKeep(n) => { 
+                    if let Some(b) = self.peek(0).as_bool() {
+                        if b { next_ip = n; }
+                    } else {
+                        self.error("Expect bool on stack top!");
+                    }
+                },
+                ByteCode::Jnz(n) => { 
+                    if let Some(b) = self.peek(0).as_bool() {
+                        if b { next_ip = n; }
                         self.pop();
                     } else {
                         self.error("Expect bool on stack top!");
                     }
                 },
                 ByteCode::DefGlobal(c) => { 
-                    if let Value::Obj(s) = &self.constants[c] {
-                        let Object::String(s) = &self.obj_list[*s] else {
+                    if let Value::Obj(s) = self.current_chunk().constants[c] {
+                        let Object::String(s) = &self.obj_list[s] else {
                             self.error("Expect String")
                         };                        
-                        let value = self.peek(0);
+                        let value = self.peek(0).unpack();
                         if !self.global.contains_key(s) {
-                            self.global.insert(s.clone(), value.clone());
+                            self.global.insert(s.clone(), value);
                         } else {
                             self.error(&format!("Variable name '{}' is defined!", s)[..]);
                         }
@@ -249,13 +384,13 @@ impl VirtualMachine {
                     self.pop();
                 },
                 ByteCode::Load(c) => { 
-                    if let Value::Obj(s) = &self.constants[c] {
-                        let Object::String(s) = &self.obj_list[*s] else {
+                    if let Value::Obj(s) = self.current_chunk().constants[c] {
+                        let Object::String(s) = &self.obj_list[s] else {
                             self.error("Expect String")
                         };
                         if self.global.contains_key(s) {
                             let value = self.global.get(s).unwrap();
-                            self.push(value.clone());
+                            self.push(StackElem::pack(*value));
                         }
                         else {
                             self.error(&format!("Variable name '{}' is not defined!", s)[..]);
@@ -265,13 +400,13 @@ impl VirtualMachine {
                     }
                 },
                 ByteCode::LoadNative(c) => { 
-                    if let Value::Obj(s) = &self.constants[c] {
-                        let Object::String(str) = &self.obj_list[*s] else {
+                    if let Value::Obj(s) = self.current_chunk().constants[c] {
+                        let Object::String(str) = &self.obj_list[s] else {
                             self.error("Expect String")
                         };
                         if self.native_functions.contains_key(str) {
                             // let value = self.native_functions.get(s).unwrap();
-                            self.push(Value::NativeFunction(*s));
+                            self.push(StackElem::pack(Value::NativeFunction(s)));
                         }
                         else {
                             self.error(&format!("Variable name '{}' is not defined!", s)[..]);
@@ -281,13 +416,13 @@ impl VirtualMachine {
                     }
                 },
                 ByteCode::Set(c) => {
-                    if let Value::Obj(s) = &self.constants[c] {
-                        let Object::String(s) = &self.obj_list[*s] else {
+                    if let Value::Obj(s) = self.current_chunk().constants[c] {
+                        let Object::String(s) = &self.obj_list[s] else {
                             self.error("Expect String")
                         };
                         if self.global.contains_key(s) {
-                            let value = self.peek(0);
-                            self.global.insert(s.clone(), value.clone());
+                            let value = self.peek(0).unpack();
+                            self.global.insert(s.clone(), value);
                         }
                         else {
                             self.error(&format!("Variable name '{}' is not defined!", s)[..]);
@@ -300,17 +435,17 @@ impl VirtualMachine {
                 ByteCode::LoadLocal(c) => { 
                     let local_index = c + self.get_frame().slot_index;
                     if local_index < self.stack.len() {
-                        let value = self.stack[local_index].clone();
-                        self.push(value.clone());
+                        let value = self.stack[local_index];
+                        self.push(value);
                     } else {
                         self.error("there's no such local variable !");
                     }
                 },
                 ByteCode::SetLocal(c) => {
                     let local_index = c + self.get_frame().slot_index;
-                    let value = self.peek(0);
+                    let value = *self.peek(0);
                     if local_index < self.stack.len() {
-                        self.stack[local_index] = value.clone();
+                        self.stack[local_index] = value;
                     } else {
                         self.error("there's no such local variable !");
                     }
@@ -318,42 +453,99 @@ impl VirtualMachine {
                 },
                 ByteCode::Call(arg_num) => {
                     let slot = self.stack.len() - arg_num;
-                    let Value::Function(func_id) = self.peek(arg_num) else {
+                    let Value::Function(func_id) = self.peek(arg_num).unpack() else {
                         self.error("Expect Function in stack");
                     };
+                    self.check_arity(func_id, arg_num)?;
+                    if self.frames.len() >= self.max_frames || self.stack.len() > self.max_stack {
+                        let name = &self.functions[func_id].name;
+                        return Err(self.runtime_error(&format!("Stack overflow in function '{}'", name)));
+                    }
+                    // the caller resumes right after this instruction
+                    self.set_ip(next_ip);
+                    next_ip = usize::MAX;
+                    #[cfg(feature = "jit")]
+                    if self.call_compiled(func_id, slot) {
+                        continue;
+                    }
                     self.frames.push(CallFrame { 
-                        func_id: *func_id, 
+                        func_id, 
                         ip: 0, 
-                        slot_index: slot
+                        slot_index: slot,
+                        tail_calls: 0,
                     });
+                },
+                ByteCode::TailCall(arg_num) => {
+                    let Value::Function(func_id) = self.peek(arg_num).unpack() else {
+                        self.error("Expect Function in stack");
+                    };
+                    self.check_arity(func_id, arg_num)?;
+                    // the callee and its arguments take the place of the
+                    // current function and its locals
+                    let callee = self.stack.len() - arg_num - 1;
+                    let base = self.get_frame().slot_index - 1;
+                    self.stack.drain(base..callee);
+                    let frame = self.current_frame();
+                    frame.func_id = func_id;
+                    frame.ip = 0;
+                    frame.slot_index = base + 1;
+                    frame.tail_calls += 1;
                     next_ip = usize::MAX;
                 },
                 ByteCode::CallNative(arg_num) => {
-                    self.print_stack();
-                    let slot = self.stack.len() - arg_num;
-                    println!("{}", slot);
-                    let Value::NativeFunction(obj_id) = self.peek(0) else {
+                    let Value::NativeFunction(obj_id) = self.peek(0).unpack() else {
                         self.error("Expect Function in stack");
                     };
-                    let Object::String(func_name) = self.obj_list[*obj_id].clone() else {
+                    let Object::String(func_name) = self.obj_list[obj_id].clone() else {
                         self.error("Expect String in stack");
                     };
                     self.pop();
                     let native_fn = self.native_functions[&func_name];
                     let args = self.get_args(arg_num);
-                    let val = native_fn(&mut self.obj_list, arg_num, args);
-                    self.stack_back_to(slot - 1);
-                    self.push(val);
+                    let val = match native_fn(&mut self.obj_list, arg_num, args) {
+                        Ok(val) => val,
+                        Err(msg) => return Err(self.runtime_error(&msg)),
+                    };
+                    self.push(StackElem::pack(val));
+                },
+                ByteCode::AddLocalConst(l, c) => {
+                    let a = self.stack[self.get_frame().slot_index + l];
+                    let b = StackElem::pack(self.current_chunk().constants[c]);
+                    self.check_number(&a, &b);
+                    let value = a.add(b);
+                    if value.is_nil() {
+                        self.error("Wrong object type for the operator !");
+                    }
+                    self.push(value);
+                },
+                ByteCode::AddConstSetLocal(c, l) => {
+                    let b = StackElem::pack(self.current_chunk().constants[c]);
+                    self.check_number(self.peek(0), &b);
+                    let value = self.pop().add(b);
+                    if value.is_nil() {
+                        self.error("Wrong object type for the operator !");
+                    }
+                    let local_index = l + self.get_frame().slot_index;
+                    self.stack[local_index] = value;
+                },
+                ByteCode::LtLocalsJz(a, b, n) => {
+                    let slot = self.get_frame().slot_index;
+                    let (a, b) = (self.stack[slot + a], self.stack[slot + b]);
+                    self.check_number(&a, &b);
+                    let less = a < b;
+                    if !less {
+                        next_ip = n;
+                    }
                 },
                 ByteCode::Ret => {
                     let ret_val = self.pop();
                     let slot: usize = self.get_frame().slot_index;
-                    self.stack_back_to(slot - 1);
+                    self.stack.truncate(slot - 1);
                     self.frames.pop();
                     self.push(ret_val);
-                    next_ip = self.get_ip() + 1;
+                    next_ip = self.get_ip();
                 },
-                _ => return Ok(()),
+                _ => return Ok(true),
             }
             if next_ip < usize::MAX {
                 self.set_ip(next_ip);
@@ -362,25 +554,20 @@ impl VirtualMachine {
         }
     }
 
-    fn get_args(&mut self, num: usize) -> Vec<Value> {
-        let mut args = vec![];
-        for i in 0..num {
-            args.push(self.pop());
+    /// Calls pass any number of arguments, the callee's frame needs exactly its arity.
+    pub fn check_arity(&self, func_id: usize, argc: usize) -> InterpretResult {
+        let func = &self.functions[func_id];
+        if func.arity != argc as i64 {
+            return Err(self.runtime_error(&format!("Function '{}' expects {} argument(s) but got {}",
+                                                   func.name, func.arity, argc)));
         }
-        args
+        Ok(())
     }
 
-    fn stack_back_to(&mut self, index: usize) {
-        if self.stack.len() == index {
-            return;
-        }
-        let end = self.stack.len() - 1;
-        if end < index {
-            return;
-        }
-        for _ in index..=end {
-            _ = self.pop();
-        }
+    /// Removes the top `num` values, last one first.
+    fn get_args(&mut self, num: usize) -> Vec<Value> {
+        let start = self.stack.len() - num;
+        self.stack.drain(start..).rev().map(|elem| elem.unpack()).collect()
     }
 
     pub fn print_stack(&self) {
@@ -391,8 +578,61 @@ impl VirtualMachine {
         print!(" ]\n");
     }
 
+    /// Lists the active calls, innermost first.
+    pub fn stack_trace(&self) -> String {
+        let mut trace = String::from("Stack trace (most recent call first):");
+        // frames identical to the one above them, e.g. of a deep recursion
+        let (mut last, mut last_name) = (String::new(), "");
+        let mut repeated = 0;
+        for (i, frame) in self.frames.iter().enumerate().rev() {
+            // callers have already moved past their `Call`
+            let ip = if i + 1 == self.frames.len() { frame.ip } else { frame.ip - 1 };
+            let at = self.location(frame.func_id, ip);
+            let name = &self.functions[frame.func_id].name;
+            let mut entry = format!("\n  {} at {}", name, at);
+            if frame.tail_calls > 0 {
+                entry += &format!("\n  ... {} tail call(s) elided", frame.tail_calls);
+            }
+            if entry == last {
+                repeated += 1;
+                continue;
+            }
+            if repeated > 0 {
+                trace += &format!("\n  ... {} more frame(s) of '{}'", repeated, last_name);
+                repeated = 0;
+            }
+            trace += &entry;
+            last = entry;
+            last_name = name;
+        }
+        if repeated > 0 {
+            trace += &format!("\n  ... {} more frame(s) of '{}'", repeated, last_name);
+        }
+        trace
+    }
+
+    /// Source location of instruction `ip` of a function, for the backend in use.
+    fn location(&self, func_id: usize, ip: usize) -> String {
+        let source_map = &self.functions[func_id].chunk.source_map;
+        let locs = match self.backend {
+            Backend::Stack => return source_map.describe(source_map.get_or_last(ip)),
+            Backend::Register => &self.register_code[func_id].locs,
+            Backend::Closure => &self.closure_code[func_id].locs,
+        };
+        source_map.describe(locs.get(ip).or(locs.last()).copied().unwrap_or_default())
+    }
+
+    fn current_location(&self) -> String {
+        self.frames.last().map_or(String::from("line 0"), |frame| self.location(frame.func_id, frame.ip))
+    }
+
+    pub fn runtime_error(&self, msg: &str) -> InterpretError {
+        InterpretError::RuntimeError(
+            format!("Runtime Error: {} at {}\n{}", msg, self.current_location(), self.stack_trace()))
+    }
+
     pub fn error(&self, msg: &str) -> ! {
-        panic!("Runtime Error: {} at line {}", msg, self.current_chunk().lines[self.get_ip()])
+        panic!("Runtime Error: {} at {}\n{}", msg, self.current_location(), self.stack_trace())
     }
 
     pub fn write_file(&self, filename: &str) {
@@ -403,10 +643,136 @@ impl VirtualMachine {
     }
     pub fn write_file_detail(&self, filename: &str) {
         let mut f = File::create(filename).unwrap();
-        for func in &self.functions {
-            f.write(format!("<fn> {}:\n{}\n", func.name, func.chunk.to_string_detail(&self.obj_list))
-                    .as_bytes()).unwrap();
+        let asm = assembler::disassemble(&self.functions, &self.obj_list);
+        f.write_all(asm.as_bytes()).unwrap();
+    }
+
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BACKENDS: [Backend; 3] = [Backend::Stack, Backend::Register, Backend::Closure];
+
+    fn vm(source: &str, backend: Backend) -> VirtualMachine {
+        let codegen = crate::compile_source(source);
+        let mut vm = VirtualMachine::with_backend(&codegen, backend);
+        vm.debug = false;
+        vm
+    }
+
+    #[test]
+    fn interpret_steps_time_slices_an_endless_loop() {
+        for backend in BACKENDS {
+            let mut vm = vm("let i = 0\nwhile true:\n    i = i + 1\n", backend);
+            let mut last = 0;
+            for _ in 0..10 {
+                assert!(!vm.interpret_steps(100).unwrap());
+                let Some(&Value::Int(i)) = vm.global.get("i") else { panic!("i is not defined") };
+                assert!(i > last, "{:?} made no progress", backend);
+                last = i;
+            }
+            assert_eq!(vm.instruction_count, 1000);
         }
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn interpret_steps_finishes_like_interpret() {
+        let source = "let s = 0\nlet i = 0\nwhile i < 100:\n    s = s + i\n    i = i + 1\n";
+        for backend in BACKENDS {
+            let mut vm = vm(source, backend);
+            while !vm.interpret_steps(7).unwrap() {}
+            assert_eq!(vm.global.get("s"), Some(&Value::Int(4950)));
+            assert!(vm.interpret_steps(7).unwrap());
+        }
+    }
+
+    #[test]
+    fn stack_trace_collapses_repeated_frames() {
+        for backend in BACKENDS {
+            let mut vm = vm("func rec(n):\n    return rec(n + 1) + 1\n\nrec(0)\n", backend);
+            let Err(InterpretError::RuntimeError(msg)) = vm.interpret() else { panic!("expected a stack overflow") };
+            let trace: Vec<&str> = msg.lines().skip(2).collect();
+            assert_eq!(trace.len(), 3, "{}", msg);
+            assert!(trace[0].starts_with("  rec at line 2"));
+            assert_eq!(trace[1], "  ... 1022 more frame(s) of 'rec'");
+            assert!(trace[2].starts_with("  $main at line 4"));
+        }
+    }
+
+    #[test]
+    fn and_or_skip_the_right_operand() {
+        let source = "let calls = 0\nfunc f(x):\n    calls = calls + 1\n    return x\n\
+                      let a = false and f(true)\nlet b = true or f(false)\nlet c = true and f(true)\n";
+        for backend in BACKENDS {
+            let mut vm = vm(source, backend);
+            vm.interpret().unwrap();
+            assert_eq!(vm.global.get("calls"), Some(&Value::Int(1)));
+            assert_eq!(vm.global.get("a"), Some(&Value::Bool(false)));
+            assert_eq!(vm.global.get("b"), Some(&Value::Bool(true)));
+            assert_eq!(vm.global.get("c"), Some(&Value::Bool(true)));
+        }
+    }
+
+    #[test]
+    fn calls_check_the_argument_count() {
+        let sources = ["func f(a):\n    return a\nprint(f(1, 2))\n",
+                       "func f(a):\n    return a\nfunc g():\n    return f()\nprint(g())\n"];
+        for backend in BACKENDS {
+            for source in sources {
+                let mut vm = vm(source, backend);
+                assert!(matches!(vm.interpret(), Err(InterpretError::RuntimeError(msg))
+                                 if msg.contains("Function 'f' expects 1 argument(s) but got")));
+            }
+        }
+    }
+
+    #[test]
+    fn resumes_after_the_instruction_limit() {
+        let source = "let s = 0\nlet i = 0\nwhile i < 100:\n    s = s + i\n    i = i + 1\n";
+        for backend in BACKENDS {
+            let mut vm = vm(source, backend);
+            vm.instruction_limit = Some(50);
+            assert!(matches!(vm.interpret(), Err(InterpretError::BudgetExhausted)));
+            assert_eq!(vm.instruction_count, 50);
+            vm.instruction_limit = Some(100);
+            assert!(matches!(vm.interpret_steps(usize::MAX), Err(InterpretError::BudgetExhausted)));
+            assert_eq!(vm.instruction_count, 100);
+            vm.instruction_limit = None;
+            assert!(vm.interpret_steps(usize::MAX).unwrap());
+            assert_eq!(vm.global.get("s"), Some(&Value::Int(4950)));
+        }
+    }
+
+    #[test]
+    fn running_out_of_heap_is_a_runtime_error() {
+        for backend in BACKENDS {
+            let mut huge = vm("let xs = list(100000000000)\n", backend);
+            assert!(matches!(huge.interpret(), Err(InterpretError::RuntimeError(msg)) if msg.contains("Out of memory")));
+            let mut limited = vm("let xs = list(10)\nlet ys = list(1000)\n", backend);
+            limited.obj_list.limit = Some(limited.obj_list.bytes_allocated + 1000);
+            assert!(matches!(limited.interpret(), Err(InterpretError::RuntimeError(msg)) if msg.contains("Out of memory")));
+            assert!(matches!(limited.global.get("xs"), Some(Value::Obj(_))));
+        }
+    }
+
+    #[test]
+    fn negative_list_size_is_a_runtime_error() {
+        for backend in BACKENDS {
+            let mut vm = vm("let xs = list(0 - 1)\n", backend);
+            assert!(matches!(vm.interpret(), Err(InterpretError::RuntimeError(msg)) if msg.contains("cannot be negative")));
+        }
+    }
+
+    #[test]
+    fn stops_once_the_deadline_has_passed() {
+        for backend in BACKENDS {
+            let mut vm = vm("let i = 0\nwhile true:\n    i = i + 1\n", backend);
+            vm.deadline = Some(Instant::now());
+            assert!(matches!(vm.interpret(), Err(InterpretError::Timeout)));
+            assert_eq!(vm.instruction_count, 0);
+            vm.deadline = None;
+            assert!(!vm.interpret_steps(10).unwrap());
+        }
+    }
+}
@@ -0,0 +1,343 @@
+use std::collections::{BTreeSet, HashMap};
+
+use crate::bytecode::ByteCode;
+use crate::c_backend::Globals;
+use crate::heap::Heap;
+use crate::native_functions::Native;
+use crate::object::{Function, Object};
+use crate::value::Value;
+use crate::verifier;
+
+
+/// Helpers spliced into every translated module.
+const RUNTIME: &str = include_str!("dpp_runtime.wat");
+
+/// Messages of the runtime, they become globals holding their address.
+const MESSAGES: [(&str, &str); 6] = [
+    ("msg_number", "The type to be operated shoule be Number"),
+    ("msg_bool", "The type to be operated shoule be Boolean"),
+    ("msg_wrong_type", "Wrong object type for the operator !"),
+    ("msg_mod_zero", "attempt to calculate the remainder with a divisor of zero"),
+    ("msg_truth", "Expect bool on stack top!"),
+    ("msg_not_function", "Expect Function in stack"),
+];
+
+const NIL: u8 = 0;
+const BOOL: u8 = 1;
+const INT: u8 = 2;
+const FLOAT: u8 = 3;
+const FUNCTION: u8 = 4;
+/// Tag of a global that is not defined yet.
+const UNDEFINED: u8 = 5;
+
+/// Translates a compiled program to WebAssembly text. Only numbers, bools,
+/// globals and functions are supported: strings, lists, objects and natives
+/// are reported as errors.
+///
+/// The module imports `dpp.print(tag, bits)`, `dpp.error(msg, len, at, len)`
+/// and `dpp.halt()`, the last two do not return. It exports `memory` and
+/// `run`. Like in `c_backend`, every stack slot is fixed, here a pair of
+/// locals holding the tag and the bits of the value.
+pub fn to_wat(functions: &[Function], obj_list: &Heap) -> Result<String, String> {
+    let globals = Globals::new(functions, obj_list);
+    let mut data = Data::new();
+    let mut code = String::new();
+    let natives = Native::new();
+    for (id, func) in functions.iter().enumerate() {
+        code += &function(id, func, functions, obj_list, &natives, &globals, &mut data)?;
+    }
+    let messages: Vec<usize> = MESSAGES.iter().map(|(_, msg)| data.string(msg)).collect();
+    let overflow: Vec<usize> = functions.iter()
+        .map(|func| data.string(&format!("Stack overflow in function '{}'", func.name)))
+        .collect();
+    let overflow = data.table(&overflow);
+
+    let mut wat = String::from(";; Translated by wat, run with the imports of module \"dpp\".\n(module\n");
+    wat += "  (import \"dpp\" \"print\" (func $print (param i32 i64)))\n";
+    wat += "  (import \"dpp\" \"error\" (func $error (param i32 i32 i32 i32)))\n";
+    wat += "  (import \"dpp\" \"halt\" (func $halt))\n";
+    wat += &format!("  (memory (export \"memory\") {})\n", data.bytes.len().div_ceil(65536).max(1));
+    let arities: BTreeSet<usize> = functions.iter()
+        .map(|func| func.arity as usize)
+        .chain(data.mismatch.keys().copied())
+        .collect();
+    for arity in arities {
+        wat += &format!("  (type $fn{} (func{} (result i32 i64)))\n", arity, " (param i32 i64)".repeat(arity));
+    }
+    wat += &format!("  (table {} funcref)\n  (elem (i32.const 0) func", functions.len());
+    for id in 0..functions.len() {
+        wat += &format!(" $f{}", id);
+    }
+    wat += ")\n";
+    for ((name, _), addr) in MESSAGES.iter().zip(messages) {
+        wat += &format!("  (global ${} i32 (i32.const {}))\n", name, addr);
+    }
+    wat += &format!("  (global $overflow i32 (i32.const {}))\n", overflow);
+    for slot in 0..globals.names.len() {
+        wat += &format!("  (global $gt{} (mut i32) (i32.const {})) (global $gb{} (mut i64) (i64.const 0))\n",
+                        slot, UNDEFINED, slot);
+    }
+    wat += "\n";
+    wat += RUNTIME;
+    wat += &code;
+    wat += "\n  (func (export \"run\")\n    call $f0\n    drop\n    drop)\n\n";
+    wat += &format!("  (data (i32.const 0) {})\n)\n", wat_string(&data.bytes));
+    Ok(wat)
+}
+
+/// The linear memory: strings and tables of string addresses.
+struct Data {
+    bytes: Vec<u8>,
+    strings: HashMap<String, usize>,
+    /// Arity errors of each function by the number of arguments passed.
+    mismatch: HashMap<usize, usize>,
+}
+
+impl Data {
+    fn new() -> Self {
+        // address 0 stands for no message
+        Data { bytes: vec![0; 4], strings: HashMap::new(), mismatch: HashMap::new() }
+    }
+
+    /// The address of the length of `s`, its bytes follow.
+    fn string(&mut self, s: &str) -> usize {
+        if let Some(&addr) = self.strings.get(s) {
+            return addr;
+        }
+        let addr = self.table(&[s.len()]);
+        self.bytes.extend(s.as_bytes());
+        self.bytes.resize(self.bytes.len().next_multiple_of(4), 0);
+        self.strings.insert(String::from(s), addr);
+        addr
+    }
+
+    fn table(&mut self, words: &[usize]) -> usize {
+        let addr = self.bytes.len();
+        for word in words {
+            self.bytes.extend((*word as u32).to_le_bytes());
+        }
+        addr
+    }
+
+    /// The table `$enter` checks calls with `argc` arguments against.
+    fn mismatch(&mut self, argc: usize, functions: &[Function]) -> usize {
+        if let Some(&addr) = self.mismatch.get(&argc) {
+            return addr;
+        }
+        let errors: Vec<usize> = functions.iter().map(|func| match func.arity as usize {
+            arity if arity == argc => 0,
+            arity => self.string(&format!("Function '{}' expects {} argument(s) but got {}", func.name, arity, argc)),
+        }).collect();
+        let addr = self.table(&errors);
+        self.mismatch.insert(argc, addr);
+        addr
+    }
+}
+
+fn function(id: usize, func: &Function, functions: &[Function], obj_list: &Heap, natives: &Native,
+            globals: &Globals, data: &mut Data) -> Result<String, String> {
+    let chunk = &func.chunk;
+    let code = verifier::stack_depths(id, functions, obj_list, natives).map_err(|err| err.to_string())?;
+    let arity = func.arity as usize;
+    // blocks start at the jump targets, `$pc` picks the block to run
+    let mut blocks: Vec<usize> = code.iter()
+        .filter(|(_, _, depth)| depth.is_some())
+        .filter_map(|(_, ins, _)| ins.jump_target())
+        .collect();
+    let dispatch = !blocks.is_empty();
+    blocks.push(0);
+    blocks.sort();
+    blocks.dedup();
+    let size = code.iter().filter_map(|(_, _, depth)| *depth).max().unwrap_or(0) + 2;
+
+    let mut w = format!("\n  ;; <fn> {}/{}\n", func.name.replace('\n', " "), func.arity);
+    w += &format!("  (func $f{} (type $fn{})", id, arity);
+    for k in 0..arity {
+        w += &format!(" (param $t{} i32) (param $b{} i64)", k, k);
+    }
+    w += " (result i32 i64)\n";
+    for k in arity..size {
+        w += &format!("    (local $t{} i32) (local $b{} i64)\n", k, k);
+    }
+    if dispatch {
+        w += "    (local $pc i32)\n    loop $dispatch\n";
+        for block in (0..blocks.len()).rev() {
+            w += &format!("    block $B{}\n", block);
+        }
+        w += "    local.get $pc\n    br_table";
+        for block in 0..blocks.len() {
+            w += &format!(" $B{}", block);
+        }
+        w += &format!(" $B{}\n    end\n", blocks.len() - 1);
+    }
+    let get = |k: usize| format!("local.get $t{} local.get $b{}", k, k);
+    let set = |k: usize| format!("local.set $b{} local.set $t{}", k, k);
+    let jump = |t: usize| format!("i32.const {} local.set $pc br $dispatch",
+                                  blocks.binary_search(&t).unwrap());
+    for &(offset, ins, depth) in &code {
+        let Some(d) = depth else {
+            continue
+        };
+        if offset > 0 && blocks.binary_search(&offset).is_ok() {
+            w += "    end\n";
+        }
+        let at_text = chunk.source_map.describe(chunk.loc(offset));
+        let at = data.string(&at_text);
+        let unsupported = |what: &str| Err(format!("{} at {} cannot be translated to wat", what, at_text));
+        let constant = |c: usize| match chunk.constants[c] {
+            Value::Obj(obj) => unsupported(match obj_list.get(obj) {
+                Some(Object::String(_)) => "A string",
+                Some(Object::List(_)) => "A list",
+                _ => "An object",
+            }),
+            other => value(&other).map_err(|what| format!("{} at {} cannot be translated to wat", what, at_text)),
+        };
+        let arith = |op: u8, a: String, b: String| format!("i32.const {} {} {} i32.const {} call $arith", op, a, b, at);
+        let binary = |op: u8| format!("{} {}", arith(op, get(d - 2), get(d - 1)), set(d - 2));
+        let compare = |op: u8| format!("i32.const {} {} {} i32.const {} call $compare i64.extend_i32_u local.set $b{} i32.const {} local.set $t{}",
+                                       op, get(d - 2), get(d - 1), at, d - 2, BOOL, d - 2);
+        let unary = |f: &str| format!("{} i32.const {} call ${} {}", get(d - 1), at, f, set(d - 1));
+        let logic = |f: &str| format!("{} {} i32.const {} call ${} {}", get(d - 2), get(d - 1), at, f, set(d - 2));
+        let branch = |test: &str, t: usize| format!("{} i32.const {} call $truth {}if {} end", get(d - 1), at, test, jump(t));
+        let global = |c: usize, defined: bool, data: &mut Data| {
+            let (slot, name) = globals.get(chunk, c, obj_list);
+            let msg = format!("Variable name '{}' is {}!", name, if defined { "defined" } else { "not defined" });
+            (slot, format!("global.get $gt{} i32.const {} {} if i32.const {} i32.const {} call $fail end ",
+                           slot, UNDEFINED, if defined { "i32.ne" } else { "i32.eq" }, data.string(&msg), at))
+        };
+        let call = |argc: usize, data: &mut Data| {
+            let mut args: Vec<String> = (d - argc..d).map(get).collect();
+            args.push(get(d - argc - 1));
+            format!("{} i32.const {} i32.const {} call $enter", args.join(" "), data.mismatch(argc, functions), at)
+        };
+        let statement = match ins {
+            ByteCode::Add => binary(0),
+            ByteCode::Sub => binary(1),
+            ByteCode::Mul => binary(2),
+            ByteCode::Div => binary(3),
+            ByteCode::Mod => binary(4),
+            ByteCode::Shl => binary(5),
+            ByteCode::Shr => binary(6),
+            ByteCode::LAnd => binary(7),
+            ByteCode::LOr => binary(8),
+            ByteCode::LXor => binary(9),
+            ByteCode::And => logic("and"),
+            ByteCode::Or => logic("or"),
+            ByteCode::Eq => compare(0),
+            ByteCode::Ne => compare(1),
+            ByteCode::Lt => compare(2),
+            ByteCode::Le => compare(3),
+            ByteCode::Gt => compare(4),
+            ByteCode::Ge => compare(5),
+            ByteCode::Neg => unary("neg"),
+            ByteCode::Not => unary("not"),
+            ByteCode::LNot => unary("lnot"),
+            ByteCode::Out => format!("{} call $print", get(d - 1)),
+            ByteCode::Value(k) => format!("{} {}", constant(k)?, set(d)),
+            ByteCode::Pop | ByteCode::PopN(_) | ByteCode::Nop => continue,
+            // The VM stops at these like it does at `hlt`.
+            ByteCode::Hlt | ByteCode::Nil | ByteCode::True | ByteCode::False => String::from("call $halt unreachable"),
+            ByteCode::J(t) => jump(t),
+            ByteCode::Jz(t) | ByteCode::JzKeep(t) => branch("i32.eqz ", t),
+            ByteCode::Jnz(t) | ByteCode::JnzKeep(t) => branch("", t),
+            ByteCode::DefGlobal(k) => {
+                let (slot, check) = global(k, true, data);
+                format!("{}{} global.set $gb{} global.set $gt{}", check, get(d - 1), slot, slot)
+            },
+            ByteCode::Load(k) => {
+                let (slot, check) = global(k, false, data);
+                format!("{}global.get $gt{} global.get $gb{} {}", check, slot, slot, set(d))
+            },
+            ByteCode::Set(k) => {
+                let (slot, check) = global(k, false, data);
+                format!("{}{} global.set $gb{} global.set $gt{}", check, get(d - 1), slot, slot)
+            },
+            // the natives are the list operations
+            ByteCode::LoadNative(_) | ByteCode::CallNative(_) => return unsupported("A list"),
+            ByteCode::LoadLocal(l) => format!("{} {}", get(l), set(d)),
+            ByteCode::SetLocal(l) => format!("{} {}", get(d - 1), set(l)),
+            ByteCode::Call(argc) => format!("{} i32.const {} call $push call_indirect (type $fn{}) {} \
+                                             global.get $depth i32.const 1 i32.sub global.set $depth",
+                                            call(argc, data), at, argc, set(d - argc - 1)),
+            // the callee takes the frame of the caller, as in the VM
+            ByteCode::TailCall(argc) => format!("{} return_call_indirect (type $fn{})", call(argc, data), argc),
+            ByteCode::Ret => format!("{} return", get(d - 1)),
+            ByteCode::AddLocalConst(l, k) => format!("{} {}", arith(0, get(l), constant(k)?), set(d)),
+            ByteCode::AddConstSetLocal(k, l) => format!("{} {}", arith(0, get(d - 1), constant(k)?), set(l)),
+            ByteCode::LtLocalsJz(a, b, t) => format!("i32.const 2 {} {} i32.const {} call $compare i32.eqz if {} end",
+                                                     get(a), get(b), at, jump(t)),
+        };
+        w += &format!("    {}\n", statement);
+    }
+    // jumps may land right after the last instruction, which ends the program
+    if dispatch {
+        if blocks.binary_search(&chunk.code.len()).is_ok() {
+            w += "    end\n";
+        }
+        w += "    end\n";
+    }
+    w += "    call $halt\n    unreachable)\n";
+    Ok(w)
+}
+
+/// A constant as the instructions pushing its tag and bits.
+fn value(value: &Value) -> Result<String, String> {
+    let (tag, bits) = match *value {
+        Value::Nil => (NIL, 0),
+        Value::Bool(b) => (BOOL, b as i64),
+        Value::Int(i) => (INT, i),
+        Value::Float(f) => (FLOAT, f.to_bits() as i64),
+        Value::Function(id) => (FUNCTION, id as i64),
+        Value::NativeFunction(_) => return Err(String::from("A native function")),
+        _ => return Err(value.to_str()),
+    };
+    Ok(format!("i32.const {} i64.const {}", tag, bits))
+}
+
+/// A wat string literal, bytes outside printable ASCII are hex escapes.
+fn wat_string(bytes: &[u8]) -> String {
+    let mut s = String::from("\"");
+    for &byte in bytes {
+        match byte {
+            0x20..=0x7e if byte != b'"' && byte != b'\\' => s.push(byte as char),
+            _ => s += &format!("\\{:02x}", byte),
+        }
+    }
+    s.push('"');
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses and validates the text with a wat toolchain.
+    fn validate(wat: &str) -> Result<(), String> {
+        let bytes = ::wat::parse_str(wat).map_err(|err| err.to_string())?;
+        wasmparser::Validator::new().validate_all(&bytes).map_err(|err| err.to_string())?;
+        Ok(())
+    }
+
+    #[test]
+    fn numeric_scripts_translate_to_valid_wat() {
+        for name in ["arithmetic", "globals", "let_without_initializer", "logic", "loops", "stack_overflow"] {
+            let codegen = crate::compile(&format!("tests/scripts/{}.dpp", name), 2);
+            let wat = to_wat(&codegen.functions, &codegen.obj_list).unwrap();
+            if let Err(msg) = validate(&wat) {
+                panic!("{}.dpp: {}", name, msg);
+            }
+        }
+    }
+
+    #[test]
+    fn strings_and_lists_cannot_be_translated() {
+        for (source, msg) in [
+            ("print(\"s\")\n", "A string at line 1, column 7-9 cannot be translated to wat"),
+            ("let l = [1, 2]\n", "A list at line 1, column 9-14 cannot be translated to wat"),
+            ("let l = list(3)\n", "A list at line 1, column 14 cannot be translated to wat"),
+        ] {
+            let codegen = crate::compile_source(source);
+            assert_eq!(to_wat(&codegen.functions, &codegen.obj_list), Err(String::from(msg)), "{}", source);
+        }
+    }
+}
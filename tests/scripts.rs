@@ -0,0 +1,81 @@
+//! Runs every script in `tests/scripts` and compares what it prints with the
+//! `.out` file next to it. All backends and optimization levels, and the
+//! scripts translated to C, must print the same.
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+fn scripts() -> Vec<PathBuf> {
+    let mut scripts: Vec<PathBuf> = fs::read_dir("tests/scripts").unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "dpp"))
+        .collect();
+    scripts.sort();
+    scripts
+}
+
+fn interpreter() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_crafting_interpreters_rs"))
+}
+
+fn check(flags: &[&str]) {
+    for script in scripts() {
+        let output = interpreter()
+            .arg("run")
+            .arg(&script)
+            .args(flags)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{} {:?} failed:\n{}",
+                script.display(), flags, String::from_utf8_lossy(&output.stderr));
+        let expected = fs::read_to_string(script.with_extension("out")).unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), expected, "{} {:?}", script.display(), flags);
+    }
+}
+
+#[test]
+fn stack_backend() {
+    check(&[]);
+    check(&["-O0"]);
+}
+
+#[test]
+fn register_backend() {
+    check(&["--register"]);
+    check(&["--register", "-O0"]);
+}
+
+#[test]
+fn closure_backend() {
+    check(&["--closure"]);
+    check(&["--closure", "-O0"]);
+}
+
+/// Skipped when there is no C compiler.
+#[test]
+fn c_backend() {
+    let compiler = std::env::var("CC").unwrap_or_else(|_| String::from("cc"));
+    if Command::new(&compiler).arg("--version").output().is_err() {
+        eprintln!("'{}' not found, skipping", compiler);
+        return;
+    }
+    for script in scripts() {
+        for opt_level in ["-O0", "-O2"] {
+            let executable = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(script.file_stem().unwrap());
+            let output = interpreter()
+                .arg("build")
+                .arg(&script)
+                .args(["--emit", "c", opt_level, "-o"])
+                .arg(&executable)
+                .output()
+                .unwrap();
+            assert_eq!(String::from_utf8_lossy(&output.stdout), "", "{} {}", script.display(), opt_level);
+            // runtime errors go to stderr, after everything printed before them
+            let output = Command::new(&executable).output().unwrap();
+            let printed = String::from_utf8_lossy(&output.stdout) + String::from_utf8_lossy(&output.stderr);
+            let expected = fs::read_to_string(script.with_extension("out")).unwrap();
+            assert_eq!(printed, expected, "{} {}", script.display(), opt_level);
+        }
+    }
+}